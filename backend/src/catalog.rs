@@ -0,0 +1,377 @@
+//! Queries against Materialize's system catalog.
+//!
+//! These are abstracted behind the [`Catalog`] trait so that code which
+//! only needs catalog metadata (such as the resource service) can be
+//! tested without a running Materialize instance.
+
+use std::{collections::HashMap, fmt, str::FromStr};
+
+use grafana_plugin_sdk::backend;
+use serde::Serialize;
+use serde_with::{DeserializeFromStr, SerializeDisplay};
+use tokio_postgres::{types::Type, Client, Row};
+
+use crate::{
+    convert,
+    graph::{Dependency, GraphNode, ObjectGraph},
+    queries::SourceName,
+    sql, Error, Result,
+};
+
+/// The kind of a relation in the Materialize catalog.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, DeserializeFromStr, SerializeDisplay)]
+pub enum RelationKind {
+    Table,
+    View,
+    MaterializedView,
+    Source,
+    Sink,
+    Index,
+}
+
+impl RelationKind {
+    /// The name of this kind in the `type` column of `mz_catalog.mz_objects`.
+    pub fn catalog_name(self) -> &'static str {
+        match self {
+            Self::Table => "table",
+            Self::View => "view",
+            Self::MaterializedView => "materialized-view",
+            Self::Source => "source",
+            Self::Sink => "sink",
+            Self::Index => "index",
+        }
+    }
+}
+
+impl fmt::Display for RelationKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::MaterializedView => "materializedView",
+            other => other.catalog_name(),
+        })
+    }
+}
+
+impl FromStr for RelationKind {
+    type Err = String;
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "table" => Ok(Self::Table),
+            "view" => Ok(Self::View),
+            "materializedView" | "materialized-view" => Ok(Self::MaterializedView),
+            "source" => Ok(Self::Source),
+            "sink" => Ok(Self::Sink),
+            "index" => Ok(Self::Index),
+            other => Err(format!("unknown relation kind {other}")),
+        }
+    }
+}
+
+/// A relation in the catalog.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Relation {
+    pub database: String,
+    pub schema: String,
+    pub name: String,
+    /// The quoted, fully qualified name, suitable for use as a `TAIL` target.
+    pub fully_qualified_name: String,
+    pub kind: RelationKind,
+}
+
+impl Relation {
+    pub fn new(database: String, schema: String, name: String, kind: RelationKind) -> Self {
+        let fully_qualified_name =
+            SourceName::new(Some(database.clone()), Some(schema.clone()), name.clone()).to_string();
+        Self {
+            database,
+            schema,
+            name,
+            fully_qualified_name,
+            kind,
+        }
+    }
+}
+
+impl TryFrom<&Row> for Relation {
+    type Error = Error;
+    fn try_from(row: &Row) -> Result<Self> {
+        Ok(Self::new(
+            row.get("database"),
+            row.get("schema"),
+            row.get("name"),
+            row.get::<_, &str>("kind")
+                .parse()
+                .map_err(Error::InvalidCatalog)?,
+        ))
+    }
+}
+
+/// A database in the catalog.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct Database {
+    pub name: String,
+}
+
+/// A schema in the catalog.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct Schema {
+    pub database: String,
+    pub name: String,
+}
+
+/// A cluster in the catalog.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct Cluster {
+    pub name: String,
+}
+
+/// A column of a relation in the catalog.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Column {
+    pub name: String,
+    /// The SQL type of the column, as reported by the catalog.
+    pub sql_type: String,
+    pub nullable: bool,
+    /// The type of the Grafana field this column is converted to.
+    pub field_type: &'static str,
+}
+
+/// Access to the metadata in Materialize's system catalog.
+#[backend::async_trait]
+pub trait Catalog {
+    /// List relations, optionally filtered by schema name and kind.
+    async fn relations(
+        &self,
+        schema: Option<&str>,
+        kind: Option<RelationKind>,
+    ) -> Result<Vec<Relation>>;
+
+    /// List databases.
+    async fn databases(&self) -> Result<Vec<Database>>;
+
+    /// List schemas, optionally filtered by database name.
+    async fn schemas(&self, database: Option<&str>) -> Result<Vec<Schema>>;
+
+    /// List clusters.
+    async fn clusters(&self) -> Result<Vec<Cluster>>;
+
+    /// List the columns of a relation, in order.
+    ///
+    /// Unqualified names are resolved in the session's current database and schema.
+    async fn columns(&self, relation: &SourceName) -> Result<Vec<Column>>;
+
+    /// List up to `limit` distinct values of a column of a relation, as text.
+    ///
+    /// The column is assumed to exist; callers should check it against
+    /// [`Catalog::columns`] first.
+    async fn column_values(
+        &self,
+        relation: &SourceName,
+        column: &str,
+        limit: i64,
+    ) -> Result<Vec<String>>;
+
+    /// Get every relation and the dependencies between them.
+    ///
+    /// If `stats` is true, the size of each object's arrangements is included.
+    async fn object_graph(&self, stats: bool) -> Result<ObjectGraph>;
+}
+
+#[backend::async_trait]
+impl Catalog for Client {
+    async fn relations(
+        &self,
+        schema: Option<&str>,
+        kind: Option<RelationKind>,
+    ) -> Result<Vec<Relation>> {
+        self.query(
+            r#"
+            SELECT mzd.name AS database, mzs.name AS schema, mzo.name AS name, mzo.type AS kind
+            FROM mz_catalog.mz_objects mzo
+            JOIN mz_catalog.mz_schemas mzs ON mzo.schema_id = mzs.id
+            JOIN mz_catalog.mz_databases mzd ON mzs.database_id = mzd.id
+            WHERE mzo.type IN ('table', 'view', 'materialized-view', 'source', 'sink', 'index')
+            AND ($1::text IS NULL OR mzs.name = $1)
+            AND ($2::text IS NULL OR mzo.type = $2)
+            ORDER BY mzd.name, mzs.name, mzo.name
+        "#,
+            &[&schema, &kind.map(RelationKind::catalog_name)],
+        )
+        .await?
+        .iter()
+        .map(Relation::try_from)
+        .collect()
+    }
+
+    async fn databases(&self) -> Result<Vec<Database>> {
+        Ok(self
+            .query(
+                "SELECT name FROM mz_catalog.mz_databases ORDER BY name",
+                &[],
+            )
+            .await?
+            .iter()
+            .map(|row| Database {
+                name: row.get("name"),
+            })
+            .collect())
+    }
+
+    async fn schemas(&self, database: Option<&str>) -> Result<Vec<Schema>> {
+        Ok(self
+            .query(
+                r#"
+            SELECT mzd.name AS database, mzs.name AS name
+            FROM mz_catalog.mz_schemas mzs
+            JOIN mz_catalog.mz_databases mzd ON mzs.database_id = mzd.id
+            WHERE ($1::text IS NULL OR mzd.name = $1)
+            ORDER BY mzd.name, mzs.name
+        "#,
+                &[&database],
+            )
+            .await?
+            .iter()
+            .map(|row| Schema {
+                database: row.get("database"),
+                name: row.get("name"),
+            })
+            .collect())
+    }
+
+    async fn clusters(&self) -> Result<Vec<Cluster>> {
+        Ok(self
+            .query("SELECT name FROM mz_catalog.mz_clusters ORDER BY name", &[])
+            .await?
+            .iter()
+            .map(|row| Cluster {
+                name: row.get("name"),
+            })
+            .collect())
+    }
+
+    async fn columns(&self, relation: &SourceName) -> Result<Vec<Column>> {
+        let rows = self
+            .query(
+                r#"
+            SELECT mzc.name AS name, mzc.type AS sql_type, mzc.type_oid AS type_oid, mzc.nullable AS nullable
+            FROM mz_catalog.mz_columns mzc
+            JOIN mz_catalog.mz_objects mzo ON mzc.id = mzo.id
+            JOIN mz_catalog.mz_schemas mzs ON mzo.schema_id = mzs.id
+            JOIN mz_catalog.mz_databases mzd ON mzs.database_id = mzd.id
+            WHERE mzo.name = $1
+            AND mzs.name = COALESCE($2, current_schema())
+            AND mzd.name = COALESCE($3, current_database())
+            ORDER BY mzc.position
+        "#,
+                &[&relation.name(), &relation.schema(), &relation.database()],
+            )
+            .await?;
+        if rows.is_empty() {
+            return Err(Error::TailTargetNotFound(relation.to_string()));
+        }
+        Ok(rows
+            .iter()
+            .map(|row| {
+                let oid: u32 = row.get("type_oid");
+                Column {
+                    name: row.get("name"),
+                    sql_type: row.get("sql_type"),
+                    nullable: row.get("nullable"),
+                    field_type: Type::from_oid(oid)
+                        .as_ref()
+                        .map_or("string", convert::field_type_name),
+                }
+            })
+            .collect())
+    }
+
+    async fn column_values(
+        &self,
+        relation: &SourceName,
+        column: &str,
+        limit: i64,
+    ) -> Result<Vec<String>> {
+        let column = sql::quote_ident(column);
+        Ok(self
+            .query(
+                &format!(
+                    "SELECT DISTINCT {column}::text AS value FROM {relation} WHERE {column} IS NOT NULL ORDER BY 1 LIMIT $1"
+                ),
+                &[&limit],
+            )
+            .await?
+            .iter()
+            .map(|row| row.get("value"))
+            .collect())
+    }
+
+    async fn object_graph(&self, stats: bool) -> Result<ObjectGraph> {
+        let mut nodes = self
+            .query(
+                r#"
+            SELECT mzo.id AS id, mzd.name AS database, mzs.name AS schema, mzo.name AS name, mzo.type AS kind
+            FROM mz_catalog.mz_objects mzo
+            JOIN mz_catalog.mz_schemas mzs ON mzo.schema_id = mzs.id
+            JOIN mz_catalog.mz_databases mzd ON mzs.database_id = mzd.id
+            WHERE mzo.type IN ('table', 'view', 'materialized-view', 'source', 'sink', 'index')
+            ORDER BY mzo.id
+        "#,
+                &[],
+            )
+            .await?
+            .iter()
+            .map(|row| {
+                Ok(GraphNode {
+                    id: row.get("id"),
+                    relation: Relation::try_from(row)?,
+                    records: None,
+                    size: None,
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let dependencies = self
+            .query(
+                r#"
+            SELECT referenced_object_id AS referenced, object_id AS dependent
+            FROM mz_internal.mz_object_dependencies
+            ORDER BY referenced_object_id, object_id
+        "#,
+                &[],
+            )
+            .await?
+            .iter()
+            .map(|row| Dependency {
+                referenced: row.get("referenced"),
+                dependent: row.get("dependent"),
+            })
+            .collect();
+        if stats {
+            let sizes: HashMap<String, (i64, i64)> = self
+                .query(
+                    r#"
+            SELECT mce.export_id AS id, SUM(mdas.records)::int8 AS records, SUM(mdas.size)::int8 AS size
+            FROM mz_internal.mz_compute_exports mce
+            JOIN mz_internal.mz_dataflow_arrangement_sizes mdas ON mdas.id = mce.dataflow_id
+            GROUP BY mce.export_id
+        "#,
+                    &[],
+                )
+                .await?
+                .iter()
+                .map(|row| (row.get("id"), (row.get("records"), row.get("size"))))
+                .collect();
+            for node in &mut nodes {
+                if let Some((records, size)) = sizes.get(&node.id) {
+                    node.records = Some(*records);
+                    node.size = Some(*size);
+                }
+            }
+        }
+        Ok(ObjectGraph {
+            nodes,
+            dependencies,
+        })
+    }
+}
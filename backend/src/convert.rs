@@ -1,4 +1,4 @@
-use std::iter;
+use std::{collections::BTreeMap, iter};
 
 use chrono::prelude::*;
 use grafana_plugin_sdk::{arrow2::array::Array, data, prelude::*};
@@ -8,10 +8,20 @@ use tokio_postgres::{
     Row,
 };
 
-const MZ_TIMESTAMP: &str = "mz_timestamp";
-const MZ_DIFF: &str = "mz_diff";
+use crate::{Error, Result};
+
+pub(crate) const MZ_TIMESTAMP: &str = "mz_timestamp";
+pub(crate) const MZ_DIFF: &str = "mz_diff";
+
+/// Get the value in column `index` of a row, or `None` if it is `NULL` or can't
+/// be converted to `T`, such as a `NaN` numeric.
+fn try_value<'a, T: FromSql<'a>>(row: &'a Row, index: usize) -> Option<T> {
+    row.try_get::<_, Option<T>>(index).ok().flatten()
+}
 
 /// Load the column with the provided `index` from a slice of `Row`s into a named `Field`.
+///
+/// Values which are `NULL` or can't be converted are loaded as nulls.
 fn load_field<'a, T>(rows: &'a [Row], index: usize, name: &str) -> data::Field
 where
     T: FromSql<'a> + data::IntoFieldType,
@@ -20,8 +30,8 @@ where
         Array + FromIterator<Option<<T as data::IntoFieldType>::ElementType>> + 'static,
 {
     rows.iter()
-        .map(|row| row.get::<_, T>(index))
-        .into_field(name)
+        .map(|row| try_value::<T>(row, index))
+        .into_opt_field(name)
 }
 
 fn unsupported_type_field(n: usize, type_: &Type, name: &str) -> data::Field {
@@ -30,46 +40,143 @@ fn unsupported_type_field(n: usize, type_: &Type, name: &str) -> data::Field {
         .into_field(name)
 }
 
+fn get_string<'a, T>(row: &'a Row, index: usize) -> Option<String>
+where
+    T: FromSql<'a> + ToString,
+{
+    try_value::<T>(row, index).map(|v| v.to_string())
+}
+
+/// Convert the value in column `index` of a row to a string.
+///
+/// This is used where we need a textual representation of a value, such as
+/// the text and value of a template variable option. Returns `None` if the
+/// value is `NULL`.
+pub fn value_to_string(row: &Row, index: usize) -> Option<String> {
+    match row.columns().get(index)?.type_() {
+        &Type::BOOL => get_string::<bool>(row, index),
+        &Type::CHAR => get_string::<i8>(row, index),
+        &Type::INT2 => get_string::<i16>(row, index),
+        &Type::INT4 => get_string::<i32>(row, index),
+        &Type::INT8 => get_string::<i64>(row, index),
+        &Type::FLOAT4 => get_string::<f32>(row, index),
+        &Type::FLOAT8 => get_string::<f64>(row, index),
+        &Type::OID => get_string::<u32>(row, index),
+        &Type::TEXT | &Type::VARCHAR => try_value::<String>(row, index),
+        &Type::JSON | &Type::JSONB => get_string::<serde_json::Value>(row, index),
+        &Type::NUMERIC => get_string::<Decimal>(row, index),
+        &Type::DATE => get_string::<NaiveDate>(row, index),
+        &Type::TIMESTAMP => get_string::<NaiveDateTime>(row, index),
+        &Type::TIMESTAMPTZ => try_value::<DateTime<Utc>>(row, index).map(|v| v.to_rfc3339()),
+        other => Some(format!("unsupported column type {other}")),
+    }
+}
+
+/// Convert the numeric value in column `index` of a row to an `f64`.
+///
+/// Returns `None` if the value is `NULL` or the column isn't numeric.
+pub(crate) fn value_to_f64(row: &Row, index: usize) -> Option<f64> {
+    match *row.columns().get(index)?.type_() {
+        Type::CHAR => try_value::<i8>(row, index).map(f64::from),
+        Type::INT2 => try_value::<i16>(row, index).map(f64::from),
+        Type::INT4 => try_value::<i32>(row, index).map(f64::from),
+        Type::INT8 => try_value::<i64>(row, index).map(|v| v as f64),
+        Type::FLOAT4 => try_value::<f32>(row, index).map(f64::from),
+        Type::FLOAT8 => try_value::<f64>(row, index),
+        Type::OID => try_value::<u32>(row, index).map(f64::from),
+        Type::NUMERIC => try_value::<Decimal>(row, index).and_then(|v| v.to_f64()),
+        _ => None,
+    }
+}
+
+/// Convert the time value in column `index` of a row to a UTC timestamp.
+///
+/// `mz_timestamp` columns are interpreted as milliseconds since the epoch.
+/// Returns `None` if the value is `NULL` or the column isn't a time.
+pub(crate) fn value_to_time(row: &Row, index: usize) -> Option<DateTime<Utc>> {
+    let column = row.columns().get(index)?;
+    if column.name() == MZ_TIMESTAMP {
+        return try_value::<Decimal>(row, index)
+            .and_then(|v| v.to_i64())
+            .and_then(|ms| Utc.timestamp_millis_opt(ms).single());
+    }
+    match *column.type_() {
+        Type::DATE => try_value::<NaiveDate>(row, index)
+            .and_then(|v| v.and_hms_opt(0, 0, 0))
+            .map(|v| Utc.from_utc_datetime(&v)),
+        Type::TIMESTAMP => {
+            try_value::<NaiveDateTime>(row, index).map(|v| Utc.from_utc_datetime(&v))
+        }
+        Type::TIMESTAMPTZ => try_value::<DateTime<Utc>>(row, index),
+        _ => None,
+    }
+}
+
+/// The name of the Grafana field type that columns of type `type_` are converted
+/// to by [`rows_to_frame`].
+///
+/// This must be kept in sync with the conversions in `rows_to_frame`.
+pub fn field_type_name(type_: &Type) -> &'static str {
+    match type_ {
+        &Type::CHAR
+        | &Type::INT2
+        | &Type::INT4
+        | &Type::INT8
+        | &Type::FLOAT4
+        | &Type::FLOAT8
+        | &Type::OID
+        | &Type::NUMERIC => "number",
+        &Type::DATE | &Type::TIMESTAMP | &Type::TIMESTAMPTZ => "time",
+        // Text, JSON and unsupported types are all converted to strings.
+        _ => "string",
+    }
+}
+
 /// Convert some rows returned from Materialize to a Grafana Plugin SDK Frame.
 ///
-/// Note that all of the rows must have the same columns; this function will
-/// likely panic if that is not the case.
+/// All of the rows must have the same columns, otherwise [`Error::InvalidRows`]
+/// is returned. Values which are `NULL` or can't be converted become nulls.
 ///
 /// If the rows do not return the `MZ_TIMESTAMP` or `MZ_DIFF` columns
 /// they will be added automatically using the current timestamp and `None`
 /// as values respectively.
-pub fn rows_to_frame(rows: &[Row]) -> data::Frame {
+pub fn rows_to_frame(rows: &[Row]) -> Result<data::Frame> {
     let mut frame = data::Frame::new("tail");
-    if rows.is_empty() {
-        return frame;
-    }
+    let Some(first) = rows.first() else {
+        return Ok(frame);
+    };
 
-    let columns = rows[0].columns();
+    let columns = first.columns();
+    let same_columns = |row: &Row| {
+        row.columns().len() == columns.len()
+            && row
+                .columns()
+                .iter()
+                .zip(columns)
+                .all(|(a, b)| a.name() == b.name() && a.type_() == b.type_())
+    };
+    if !rows.iter().all(same_columns) {
+        return Err(Error::InvalidRows(
+            "rows have different columns".to_string(),
+        ));
+    }
     let (has_mz_timestamp, has_mz_diff) = (
         columns.iter().any(|col| col.name() == MZ_TIMESTAMP),
         columns.iter().any(|col| col.name() == MZ_DIFF),
     );
     if !has_mz_timestamp {
         let now = Utc::now();
-        frame.add_field(iter::repeat(now).take(rows.len()).into_field(MZ_TIMESTAMP));
+        frame.add_field(iter::repeat_n(now, rows.len()).into_field(MZ_TIMESTAMP));
     }
     if !has_mz_diff {
-        frame.add_field(
-            iter::repeat::<Option<i64>>(None)
-                .take(rows.len())
-                .into_opt_field(MZ_DIFF),
-        );
+        frame.add_field(iter::repeat_n::<Option<i64>>(None, rows.len()).into_opt_field(MZ_DIFF));
     }
 
-    for (i, column) in rows[0].columns().iter().enumerate() {
+    for (i, column) in columns.iter().enumerate() {
         let name = column.name();
         let field = if name == MZ_TIMESTAMP {
             rows.iter()
-                .map(|row| {
-                    row.get::<_, Decimal>(i)
-                        .to_i64()
-                        .map(|i| Utc.timestamp_millis(i))
-                })
+                .map(|row| value_to_time(row, i))
                 .into_opt_field(name)
         } else {
             match column.type_() {
@@ -83,11 +190,11 @@ pub fn rows_to_frame(rows: &[Row]) -> data::Frame {
                 &Type::TEXT | &Type::VARCHAR => load_field::<String>(rows, i, name),
                 &Type::JSON | &Type::JSONB => rows
                     .iter()
-                    .map(|row| row.get::<_, serde_json::Value>(i).to_string())
-                    .into_field(name),
+                    .map(|row| try_value::<serde_json::Value>(row, i).map(|v| v.to_string()))
+                    .into_opt_field(name),
                 &Type::NUMERIC => rows
                     .iter()
-                    .map(|row| row.get::<_, Decimal>(i).to_i64())
+                    .map(|row| try_value::<Decimal>(row, i).and_then(|v| v.to_i64()))
                     .into_opt_field(name),
                 &Type::DATE => load_field::<NaiveDate>(rows, i, name),
                 &Type::TIMESTAMP => load_field::<NaiveDateTime>(rows, i, name),
@@ -97,5 +204,88 @@ pub fn rows_to_frame(rows: &[Row]) -> data::Frame {
         };
         frame.add_field(field);
     }
-    frame
+    Ok(frame)
+}
+
+/// A single row of a time series query: the values of each numeric column at a
+/// point in time, identified by the values of the non-numeric columns.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct Sample {
+    pub time: DateTime<Utc>,
+    pub labels: BTreeMap<String, String>,
+    pub values: Vec<(String, Option<f64>)>,
+}
+
+/// Convert some rows returned from Materialize to time series frames, in the
+/// form expected by Grafana's alerting engine and server-side expressions.
+///
+/// The time of each row is taken from the first time column other than
+/// `mz_timestamp`, falling back to `mz_timestamp` and then the current time.
+/// Numeric columns become values and text columns become labels; `mz_diff`
+/// and any other columns are ignored. See [`samples_to_time_series`] for the
+/// shape of the returned frames.
+pub fn rows_to_time_series(rows: &[Row]) -> Vec<data::Frame> {
+    let Some(first) = rows.first() else {
+        return Vec::new();
+    };
+    let columns = first.columns();
+    let is_mz_column = |name: &str| name == MZ_TIMESTAMP || name == MZ_DIFF;
+    let time_index = columns
+        .iter()
+        .position(|c| c.name() != MZ_TIMESTAMP && field_type_name(c.type_()) == "time")
+        .or_else(|| columns.iter().position(|c| c.name() == MZ_TIMESTAMP));
+    let columns_of_type = |field_type: &'static str| {
+        columns.iter().enumerate().filter(move |(_, c)| {
+            !is_mz_column(c.name()) && field_type_name(c.type_()) == field_type
+        })
+    };
+    let now = Utc::now();
+    samples_to_time_series(rows.iter().map(|row| {
+        Sample {
+            time: time_index
+                .and_then(|i| value_to_time(row, i))
+                .unwrap_or(now),
+            labels: columns_of_type("string")
+                .map(|(i, c)| {
+                    (
+                        c.name().to_string(),
+                        value_to_string(row, i).unwrap_or_default(),
+                    )
+                })
+                .collect(),
+            values: columns_of_type("number")
+                .map(|(i, c)| (c.name().to_string(), value_to_f64(row, i)))
+                .collect(),
+        }
+    }))
+}
+
+/// Group samples into one frame per value column and distinct set of labels.
+///
+/// Each frame has a `time` field, sorted in ascending order, followed by a
+/// nullable `f64` value field named after its column and carrying the labels.
+/// Frames are returned in a stable order, sorted by column name then labels.
+pub(crate) fn samples_to_time_series(
+    samples: impl IntoIterator<Item = Sample>,
+) -> Vec<data::Frame> {
+    let mut series: BTreeMap<_, Vec<_>> = BTreeMap::new();
+    for sample in samples {
+        for (name, value) in sample.values {
+            series
+                .entry((name, sample.labels.clone()))
+                .or_default()
+                .push((sample.time, value));
+        }
+    }
+    series
+        .into_iter()
+        .map(|((name, labels), mut points)| {
+            points.sort_by_key(|(time, _)| *time);
+            let mut value = points.iter().map(|(_, v)| *v).into_opt_field(name.as_str());
+            value.labels = labels;
+            data::Frame::new(name)
+                .with_field(points.iter().map(|(t, _)| *t).into_field("time"))
+                .with_field(value)
+        })
+        .collect()
 }
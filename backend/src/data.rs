@@ -1,20 +1,72 @@
-use std::{collections::HashMap, sync::Arc};
+use std::{
+    collections::HashMap,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
+use chrono::Utc;
 use futures_util::{
-    stream::{FuturesOrdered, FuturesUnordered},
+    stream::{self, FuturesOrdered, FuturesUnordered},
     StreamExt,
 };
 
-use grafana_plugin_sdk::backend;
-use tokio::sync::RwLock;
+use grafana_plugin_sdk::{backend, data, live::MAX_CHANNEL_LENGTH};
 use tokio_postgres::Client;
+use tracing::{debug, field::Empty, info_span, warn, Instrument, Span};
 
 use crate::{
-    path::{self, PathDisplay, QueryId},
-    queries::{Query, SelectStatement, TailTarget},
-    rows_to_frame, Error, MaterializePlugin,
+    catalog::Catalog,
+    convert::rows_to_time_series,
+    explain::{explain_sql, plan_to_frame, Explainer},
+    filters,
+    introspection::{self, server_version},
+    macros::MacroContext,
+    metrics::{EmitKind, Metrics},
+    notices::{self, Notices},
+    panics::catch_panics,
+    queries::{Query, TailTarget, TemplatedQuery},
+    registry::{QueryRegistry, QueryScope},
+    stats::{self, QueryStats},
+    trace::{request_span, TraceContext},
+    Error, MaterializePlugin,
 };
 
+/// Request headers set by Grafana when a query is run headlessly.
+///
+/// `FromAlert` is set by the alerting engine and `X-Grafana-From-Expr` by
+/// server-side expressions, which are also used by recorded queries.
+const HEADLESS_HEADERS: [&str; 2] = ["FromAlert", "X-Grafana-From-Expr"];
+
+/// How the response to a data request will be consumed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum ResponseMode {
+    /// A dashboard panel or Explore, which can follow Live channels.
+    Interactive,
+    /// A caller such as the alerting engine, which can't follow Live channels
+    /// and needs plain time series frames.
+    Headless,
+}
+
+impl ResponseMode {
+    /// Determine the response mode from the headers of a data request.
+    ///
+    /// Grafana may forward headers with an `http_` prefix, so that is ignored.
+    fn from_headers(headers: &HashMap<String, String>) -> Self {
+        let headless = headers.iter().any(|(name, value)| {
+            let name = name.strip_prefix("http_").unwrap_or(name);
+            HEADLESS_HEADERS
+                .iter()
+                .any(|h| h.eq_ignore_ascii_case(name))
+                && value.eq_ignore_ascii_case("true")
+        });
+        if headless {
+            Self::Headless
+        } else {
+            Self::Interactive
+        }
+    }
+}
+
 /// An error returned when querying for data.
 #[derive(Debug, thiserror::Error)]
 #[error("Error querying backend for {}: {}", .ref_id, .source)]
@@ -31,58 +83,176 @@ impl backend::DataQueryError for QueryError {
 
 /// Query for data for a single `DataQuery` in a request.
 ///
+/// Any notices the server sent while running the query, along with the SQL
+/// which was run and statistics about it, are attached to the first frame of
+/// the response.
+///
 // Unfortunately this has to take all of its arguments by value until we have
 // GATs, since the `DataService::Stream` associated type can't contain references.
-// Ideally we'd just borrow the query/uid etc but it's really not a big deal.
+// Ideally we'd just borrow the query/scope etc but it's really not a big deal.
 async fn query_data_single(
-    client: Client,
-    uid: String,
-    query: backend::DataQuery<Query>,
-    queries: Arc<RwLock<HashMap<path::QueryId, SelectStatement>>>,
+    ((client, mut notices), connect): ((Client, Notices), Duration),
+    scope: QueryScope,
+    query: backend::DataQuery<TemplatedQuery>,
+    queries: QueryRegistry,
+    mode: ResponseMode,
+    metrics: Arc<Metrics>,
 ) -> Result<backend::DataResponse, Error> {
-    let q = query.query;
-    let target = q.as_tail()?;
-    let rows = target.select_all(&client).await?;
-    let mut frame = rows_to_frame(&rows);
-
-    if let TailTarget::Select { statement } = target {
-        let query_id = QueryId::from_statement(statement);
-        queries.write().await.insert(query_id, statement.clone());
+    let stats = QueryStats {
+        connect: Some(connect),
+        ..QueryStats::default()
+    };
+    let mut frames = query_frames(&client, scope, &query, queries, mode, &metrics, stats).await?;
+    if let Some(first) = frames.first_mut() {
+        notices::attach(first, notices.drain());
+    }
+    let checked = frames
+        .iter()
+        .map(|frame| frame.check().map_err(Error::from))
+        .collect::<Result<_, _>>()?;
+    Ok(backend::DataResponse::new(query.ref_id, checked))
+}
+
+/// Get the frames answering a single `DataQuery`.
+///
+/// Headless requests get time series frames without a channel, regardless of the
+/// query's operation, since the caller can't subscribe to updates.
+async fn query_frames(
+    client: &Client,
+    scope: QueryScope,
+    query: &backend::DataQuery<TemplatedQuery>,
+    queries: QueryRegistry,
+    mode: ResponseMode,
+    metrics: &Metrics,
+    mut stats: QueryStats,
+) -> Result<Vec<data::Frame>, Error> {
+    let templated = &query.query;
+    let (target, streaming) = match &templated.query {
+        Query::Graph { name, stats } => {
+            let graph = client.object_graph(*stats).await?.around(name)?;
+            return Ok(graph.to_frames().into());
+        }
+        // Introspection queries are resolved to a statement suited to the server's
+        // version, then run like any other statement.
+        Query::Introspection {
+            query: introspection,
+            stream,
+        } => {
+            let version = server_version(client).await?;
+            let statement = introspection.statement(version)?;
+            (TailTarget::Select { statement }, *stream)
+        }
+        Query::Tail(target) => (target.clone(), true),
+        Query::Select(target) | Query::Explain { target, .. } => (target.clone(), false),
+    };
+    let target = target
+        .expand_macros(&MacroContext::from_query(query))?
+        .bind(&templated.variables)?;
+    let target = if templated.adhoc_filters.is_empty() {
+        target
+    } else {
+        let columns = filters::target_columns(client, &target).await?;
+        filters::apply(&target, &templated.adhoc_filters, &columns)?
+    };
+    if let Query::Explain { plan, .. } = &templated.query {
+        let started = Instant::now();
+        let mut frame = plan_to_frame(&client.explain(&target, *plan).await?);
+        stats.duration = Some(started.elapsed());
+        stats::annotate(&mut frame, Some(explain_sql(&target, *plan)), stats);
+        return Ok(vec![frame]);
+    }
+    let started = Instant::now();
+    let rows = target.select_all(client).await?;
+    let duration = started.elapsed();
+    metrics.record_snapshot_duration(duration.as_secs_f64());
+    metrics.record_frame(EmitKind::Snapshot, rows.len());
+    stats.duration = Some(duration);
+    stats.record(&rows);
+    let (mut sql, _) = target.select_sql();
+    if mode == ResponseMode::Headless {
+        let mut frames = rows_to_time_series(&rows);
+        let now = Utc::now();
+        for frame in &mut frames {
+            introspection::add_lag(frame, now);
+        }
+        if let Some(first) = frames.first_mut() {
+            stats::annotate(first, Some(sql), stats);
+        }
+        return Ok(frames);
     }
+    let mut frame = templated.format.rows_to_frame(&rows)?;
 
-    let path = q.to_path();
-    // Set the channel of the frame, indicating to Grafana that it should switch to
-    // streaming.
-    let channel = format!("ds/{uid}/{path}")
-        .parse()
-        .map_err(Error::CreatingChannel)?;
-    frame.set_channel(channel);
-    let frame = frame.check()?;
+    if streaming {
+        // Show both the snapshot and the statement used to stream updates.
+        sql.push_str(";\n");
+        sql.push_str(&target.tail_sql().0);
 
-    Ok(backend::DataResponse::new(query.ref_id, vec![frame]))
+        let prefix = format!("ds/{}/", scope.datasource_uid);
+        let (path, query_id) = target.stream_path(
+            &templated.format,
+            MAX_CHANNEL_LENGTH - prefix.len(),
+            |statement| queries.query_id(&scope, statement),
+        );
+        if let (TailTarget::Select { statement }, Some(query_id)) = (&target, query_id) {
+            Span::current().record("query_id", &query_id.as_str());
+            queries.insert(scope, statement.clone()).await;
+        }
+
+        // Set the channel of the frame, indicating to Grafana that it should switch to
+        // streaming.
+        let channel = format!("{prefix}{path}")
+            .parse()
+            .map_err(Error::CreatingChannel)?;
+        Span::current().record("path", &path.as_str());
+        frame.set_channel(channel);
+    }
+    stats::annotate(&mut frame, Some(sql), stats);
+    Ok(vec![frame])
 }
 
 #[backend::async_trait]
 impl backend::DataService for MaterializePlugin {
-    type Query = Query;
+    type Query = TemplatedQuery;
     type QueryError = QueryError;
     type Stream = backend::BoxDataResponseStream<Self::QueryError>;
 
     async fn query_data(&self, request: backend::QueryDataRequest<Self::Query>) -> Self::Stream {
-        let datasource_settings = request
-            .plugin_context
-            .datasource_instance_settings
-            .clone()
-            .ok_or(Error::MissingDatasource)
-            .unwrap();
+        let span = request_span(
+            "query_data",
+            Some(&request.plugin_context),
+            TraceContext::from_header_map(&request.headers).as_ref(),
+        );
+        let datasource_settings = match request.plugin_context.datasource_instance_settings.clone()
+        {
+            Some(settings) => settings,
+            None => {
+                span.in_scope(|| warn!("data request without datasource settings"));
+                return Box::pin(stream::iter(request.queries.into_iter().map(|x| {
+                    Err(QueryError {
+                        ref_id: x.ref_id,
+                        source: Error::MissingDatasource,
+                    })
+                })));
+            }
+        };
+        let mode = ResponseMode::from_headers(&request.headers);
         let clients: Vec<_> = request
             .queries
             .iter()
-            .map(|_| self.get_client(&datasource_settings))
+            .map(|_| {
+                catch_panics(async {
+                    let started = Instant::now();
+                    let connected = self.connect(&datasource_settings).await;
+                    connected.map(|c| (c, started.elapsed()))
+                })
+            })
             .collect::<FuturesUnordered<_>>()
             .collect()
+            .instrument(span.clone())
             .await;
         let queries = self.sql_queries.clone();
+        let metrics = self.metrics.clone();
+        let org_id = request.plugin_context.org_id;
         Box::pin(
             request
                 .queries
@@ -90,19 +260,341 @@ impl backend::DataService for MaterializePlugin {
                 .zip(clients)
                 .map(move |(x, client)| {
                     let queries = queries.clone();
+                    let metrics = metrics.clone();
                     let ref_id = x.ref_id.clone();
-                    let uid = datasource_settings.uid.clone();
-                    async {
+                    let scope = QueryScope {
+                        datasource_uid: datasource_settings.uid.clone(),
+                        org_id,
+                    };
+                    let query_span = info_span!(
+                        parent: &span,
+                        "query",
+                        ref_id = %x.ref_id,
+                        query_id = Empty,
+                        path = Empty,
+                    );
+                    async move {
                         let client = client.map_err(|source| QueryError {
                             ref_id: ref_id.clone(),
                             source,
                         })?;
-                        query_data_single(client, uid, x, queries)
-                            .await
-                            .map_err(|source| QueryError { ref_id, source })
+                        let response = catch_panics(query_data_single(
+                            client, scope, x, queries, mode, metrics,
+                        ))
+                        .await
+                        .map_err(|source| QueryError { ref_id, source });
+                        match &response {
+                            Ok(_) => debug!("query succeeded"),
+                            Err(e) => warn!(error = %e.source, "query failed"),
+                        }
+                        response
                     }
+                    .instrument(query_span)
                 })
                 .collect::<FuturesOrdered<_>>(),
         )
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::{BTreeMap, HashSet};
+
+    use chrono::prelude::*;
+    use grafana_plugin_sdk::{
+        arrow2::{
+            array::{Array, PrimitiveArray},
+            chunk::Chunk,
+            datatypes::{DataType, Schema},
+            io::ipc::read::{read_file_metadata, FileReader},
+        },
+        backend::DataService,
+        pluginv2,
+    };
+    use tokio_postgres::types::Type;
+
+    use crate::{
+        convert::{samples_to_time_series, Sample},
+        testing::{FakeServer, FakeValue},
+    };
+
+    use super::*;
+
+    /// Build a data request for a single query with ref ID `A`.
+    fn request(
+        settings: Option<pluginv2::DataSourceInstanceSettings>,
+        headers: &[(&str, &str)],
+        json: &[u8],
+    ) -> pluginv2::QueryDataRequest {
+        pluginv2::QueryDataRequest {
+            plugin_context: Some(pluginv2::PluginContext {
+                data_source_instance_settings: settings,
+                ..Default::default()
+            }),
+            headers: headers
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect(),
+            queries: vec![pluginv2::DataQuery {
+                ref_id: "A".to_string(),
+                time_range: Some(Default::default()),
+                json: json.to_vec(),
+                ..Default::default()
+            }],
+        }
+    }
+
+    #[tokio::test]
+    async fn malformed_requests() {
+        let query = br#"{"operation": "tail", "target": "relation", "name": "orders"}"#;
+        let settings = |json: &[u8]| pluginv2::DataSourceInstanceSettings {
+            json_data: json.to_vec(),
+            ..Default::default()
+        };
+        let plugin = MaterializePlugin::default();
+
+        let responses: Vec<_> = plugin
+            .query_data(request(None, &[], query).try_into().unwrap())
+            .await
+            .collect()
+            .await;
+        assert!(matches!(
+            &responses[..],
+            [Err(QueryError { ref_id, source: Error::MissingDatasource })] if ref_id == "A"
+        ));
+
+        let responses: Vec<_> = plugin
+            .query_data(
+                request(Some(settings(b"{}")), &[], query)
+                    .try_into()
+                    .unwrap(),
+            )
+            .await
+            .collect()
+            .await;
+        assert!(matches!(
+            &responses[..],
+            [Err(QueryError {
+                source: Error::InvalidDatasourceSettings(_),
+                ..
+            })]
+        ));
+    }
+
+    #[test]
+    fn response_mode() {
+        let headers = |pairs: &[(&str, &str)]| {
+            pairs
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect::<HashMap<_, _>>()
+        };
+        assert_eq!(
+            ResponseMode::from_headers(&headers(&[])),
+            ResponseMode::Interactive
+        );
+        assert_eq!(
+            ResponseMode::from_headers(&headers(&[("FromAlert", "false")])),
+            ResponseMode::Interactive
+        );
+        assert_eq!(
+            ResponseMode::from_headers(&headers(&[("FromAlert", "true")])),
+            ResponseMode::Headless
+        );
+        assert_eq!(
+            ResponseMode::from_headers(&headers(&[("http_X-Grafana-From-Expr", "true")])),
+            ResponseMode::Headless
+        );
+    }
+
+    /// Simulate the rows returned for an alerting request and check that they
+    /// are converted to time series frames usable by thresholds and reductions.
+    #[test]
+    fn alerting_frames() {
+        let mode = ResponseMode::from_headers(&HashMap::from([(
+            "FromAlert".to_string(),
+            "true".to_string(),
+        )]));
+        assert_eq!(mode, ResponseMode::Headless);
+
+        let sample = |secs, region: &str, orders| Sample {
+            time: Utc.timestamp_opt(secs, 0).unwrap(),
+            labels: BTreeMap::from([("region".to_string(), region.to_string())]),
+            values: vec![("orders".to_string(), orders)],
+        };
+        let frames = samples_to_time_series([
+            sample(20, "eu", Some(2.0)),
+            sample(10, "eu", Some(1.0)),
+            sample(10, "us", None),
+        ]);
+
+        assert_eq!(frames.len(), 2);
+        for (frame, region) in frames.iter().zip(["eu", "us"]) {
+            assert!(frame
+                .meta
+                .as_ref()
+                .and_then(|m| m.channel.as_ref())
+                .is_none());
+            let fields = frame.fields();
+            assert_eq!(fields.len(), 2);
+            assert_eq!(fields[0].name, "time");
+            assert!(fields[0].labels.is_empty());
+            assert_eq!(fields[1].name, "orders");
+            assert_eq!(
+                fields[1].labels,
+                BTreeMap::from([("region".to_string(), region.to_string())])
+            );
+            frame.check().unwrap();
+        }
+        let values = frames[0].fields()[1]
+            .values()
+            .as_any()
+            .downcast_ref::<grafana_plugin_sdk::arrow2::array::PrimitiveArray<f64>>()
+            .unwrap();
+        assert_eq!(values.values().as_slice(), &[1.0, 2.0]);
+    }
+
+    /// Run `request` through the plugin's gRPC data service, as Grafana does,
+    /// and decode the Arrow frames of its only response.
+    async fn query_frames_via_grpc(
+        plugin: &MaterializePlugin,
+        request: pluginv2::QueryDataRequest,
+    ) -> Vec<(Schema, Chunk<Box<dyn Array>>)> {
+        let mut response =
+            pluginv2::data_server::Data::query_data(plugin, tonic::Request::new(request))
+                .await
+                .unwrap()
+                .into_inner();
+        let response = response.responses.remove("A").unwrap();
+        assert_eq!(response.error, "");
+        response
+            .frames
+            .into_iter()
+            .map(|bytes| {
+                let mut cursor = std::io::Cursor::new(bytes);
+                let metadata = read_file_metadata(&mut cursor).unwrap();
+                let schema = metadata.schema.clone();
+                let chunk = FileReader::new(cursor, metadata, None, None)
+                    .next()
+                    .unwrap()
+                    .unwrap();
+                (schema, chunk)
+            })
+            .collect()
+    }
+
+    /// The channel set on a decoded frame, if any.
+    fn channel(schema: &Schema) -> Option<String> {
+        let meta: serde_json::Value = serde_json::from_str(schema.metadata.get("meta")?).unwrap();
+        meta.get("channel")?.as_str().map(str::to_string)
+    }
+
+    /// Send alerting requests for streaming and one-shot operations through
+    /// `query_data`, and check that each returns time series frames without a
+    /// channel, and that nothing is tailed.
+    #[tokio::test]
+    async fn alerting_requests() {
+        let time = |secs| FakeValue::Time(Utc.timestamp_opt(secs, 0).unwrap());
+        let server = FakeServer::start(
+            &[
+                ("time", Type::TIMESTAMPTZ),
+                ("region", Type::TEXT),
+                ("orders", Type::FLOAT8),
+            ],
+            vec![
+                vec![time(20), FakeValue::Text("eu"), FakeValue::Float(2.0)],
+                vec![time(10), FakeValue::Text("eu"), FakeValue::Float(1.0)],
+                vec![time(10), FakeValue::Text("us"), FakeValue::Float(5.0)],
+            ],
+        )
+        .await;
+        let plugin = MaterializePlugin::default();
+
+        for query in [
+            r#"{"operation": "tail", "target": "relation", "name": "orders"}"#,
+            r#"{"operation": "tail", "target": "select", "statement": "SELECT * FROM orders"}"#,
+            r#"{"operation": "select", "target": "relation", "name": "orders"}"#,
+        ] {
+            let frames = query_frames_via_grpc(
+                &plugin,
+                request(
+                    Some(server.settings()),
+                    &[("FromAlert", "true")],
+                    query.as_bytes(),
+                ),
+            )
+            .await;
+            assert_eq!(frames.len(), 2, "unexpected frames for {query}");
+            for ((schema, chunk), (region, expected)) in frames
+                .iter()
+                .zip([("eu", [1.0, 2.0].as_slice()), ("us", &[5.0])])
+            {
+                assert_eq!(channel(schema), None, "{query} returned a channel");
+                let fields: Vec<_> = schema.fields.iter().map(|f| f.name.as_str()).collect();
+                assert_eq!(fields, ["time", "orders"]);
+                assert!(matches!(
+                    schema.fields[0].data_type,
+                    DataType::Timestamp(..)
+                ));
+                assert_eq!(
+                    schema.fields[1].metadata.get("labels").map(String::as_str),
+                    Some(format!(r#"{{"region":"{region}"}}"#).as_str())
+                );
+                let values = chunk.arrays()[1]
+                    .as_any()
+                    .downcast_ref::<PrimitiveArray<f64>>()
+                    .unwrap();
+                assert_eq!(values.values().as_slice(), expected);
+            }
+        }
+        let statements = server.statements();
+        assert!(
+            statements.iter().all(|s| !s.starts_with("TAIL")),
+            "unexpected TAIL in {statements:?}"
+        );
+
+        // The same streaming query from a dashboard gets a channel.
+        let frames = query_frames_via_grpc(
+            &plugin,
+            request(
+                Some(server.settings()),
+                &[],
+                br#"{"operation": "tail", "target": "relation", "name": "orders"}"#,
+            ),
+        )
+        .await;
+        assert_eq!(frames.len(), 1);
+        assert!(channel(&frames[0].0).is_some_and(|c| c.starts_with("ds/materialize/tail/")));
+    }
+
+    /// Statements are run in read-only transactions, since Materialize can't
+    /// make a whole session read-only, and the fallback is noted once.
+    #[tokio::test]
+    async fn read_only_transactions() {
+        let server = FakeServer::start(
+            &[("orders", Type::FLOAT8)],
+            vec![vec![FakeValue::Float(1.0)]],
+        )
+        .await;
+        let plugin = MaterializePlugin::default();
+        let query = br#"{"operation": "select", "target": "relation", "name": "orders"}"#;
+        for _ in 0..2 {
+            query_frames_via_grpc(&plugin, request(Some(server.settings()), &[], query)).await;
+        }
+        let statements = server.statements();
+        assert_eq!(
+            statements[..4],
+            [
+                "SET default_transaction_read_only = on",
+                "BEGIN READ ONLY",
+                "SELECT * FROM \"orders\"",
+                "COMMIT",
+            ]
+        );
+        assert_eq!(statements[4..], statements[..4]);
+        assert_eq!(
+            *plugin.read_only_fallbacks.lock().unwrap(),
+            HashSet::from(["materialize".to_string()])
+        );
+    }
+}
@@ -1,11 +1,266 @@
+use std::time::Instant;
+
 use grafana_plugin_sdk::backend;
+use serde::Serialize;
+use tokio_postgres::{error::SqlState, Client};
+
+use tracing::{info, Instrument};
+
+use crate::{
+    introspection::server_version,
+    panics::catch_panics,
+    trace::{request_span, TraceContext},
+    Error, MaterializePlugin, Result,
+};
+
+/// Schemas the datasource's role needs `USAGE` on, along with the status
+/// reported if it is missing.
+///
+/// `mz_catalog` backs the query editor's catalog browsing, while `mz_internal`
+/// is only needed for the graph and introspection operations.
+const REQUIRED_SCHEMAS: [(&str, CheckStatus); 2] = [
+    ("mz_catalog", CheckStatus::Error),
+    ("mz_internal", CheckStatus::Warning),
+];
+
+/// The outcome of a single health check.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+enum CheckStatus {
+    Ok,
+    /// Some functionality won't work, but queries can still be run.
+    Warning,
+    Error,
+}
+
+/// A single health check, with a hint for fixing it if it didn't pass.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Check {
+    name: &'static str,
+    status: CheckStatus,
+    message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    remediation: Option<String>,
+}
+
+impl Check {
+    fn ok(name: &'static str, message: impl Into<String>) -> Self {
+        Self {
+            name,
+            status: CheckStatus::Ok,
+            message: message.into(),
+            remediation: None,
+        }
+    }
+
+    fn failed(
+        name: &'static str,
+        status: CheckStatus,
+        message: impl Into<String>,
+        remediation: impl Into<String>,
+    ) -> Self {
+        Self {
+            name,
+            status,
+            message: message.into(),
+            remediation: Some(remediation.into()),
+        }
+    }
+}
 
-use crate::{Error, MaterializePlugin, Result};
+/// Which streaming statements the server accepts.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct StreamingSupport {
+    tail: bool,
+    subscribe: bool,
+}
+
+/// The results of a health check, returned to Grafana as `json_details`.
+#[derive(Clone, Debug, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct HealthReport {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    version: Option<String>,
+    /// The round-trip time of a trivial query, in milliseconds.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    latency_ms: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    streaming: Option<StreamingSupport>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    database: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    cluster: Option<String>,
+    checks: Vec<Check>,
+}
+
+impl HealthReport {
+    /// The overall status, which is an error if any check failed outright.
+    fn status(&self) -> backend::HealthStatus {
+        if self.checks.iter().any(|c| c.status == CheckStatus::Error) {
+            backend::HealthStatus::Error
+        } else {
+            backend::HealthStatus::Ok
+        }
+    }
+
+    /// A summary of the report, listing any checks which didn't pass along
+    /// with their remediation hints.
+    fn message(&self) -> String {
+        let problems: Vec<_> = self
+            .checks
+            .iter()
+            .filter(|c| c.status != CheckStatus::Ok)
+            .map(|c| match &c.remediation {
+                Some(hint) => format!("{}: {} ({hint})", c.name, c.message),
+                None => format!("{}: {}", c.name, c.message),
+            })
+            .collect();
+        if self.status() == backend::HealthStatus::Error {
+            problems.join("; ")
+        } else if problems.is_empty() {
+            "Connection successful".to_string()
+        } else {
+            format!(
+                "Connection successful, with warnings: {}",
+                problems.join("; ")
+            )
+        }
+    }
+
+    fn into_response(self) -> backend::CheckHealthResponse {
+        let json_details = serde_json::to_value(&self).unwrap_or_default();
+        let message = self.message();
+        let response = match self.status() {
+            backend::HealthStatus::Ok => backend::CheckHealthResponse::ok(message),
+            _ => backend::CheckHealthResponse::error(message),
+        };
+        response.with_json_details(json_details)
+    }
+}
+
+/// A hint for fixing an error returned when connecting to the server.
+fn connection_remediation(error: &Error) -> String {
+    let text = error.to_string().to_lowercase();
+    if text.contains("tls") || text.contains("ssl") {
+        return "TLS required by server; this datasource only supports unencrypted connections"
+            .to_string();
+    }
+    match error {
+        Error::AuthenticationFailed(_) => {
+            "Authentication failed; check the username and that the role is allowed to log in"
+                .to_string()
+        }
+        Error::ConnectionRefused(_) => {
+            "Connection refused; check the host and port, and that Materialize is reachable from Grafana"
+                .to_string()
+        }
+        Error::Connection(e) if e.code() == Some(&SqlState::INVALID_CATALOG_NAME) => {
+            "The configured database does not exist; create it or change the database setting"
+                .to_string()
+        }
+        Error::Connection(_) => {
+            "Check the datasource's connection settings and the server's logs".to_string()
+        }
+        _ => "Check the datasource's host, port and username settings".to_string(),
+    }
+}
+
+/// Check that the server accepts the `TAIL` statements used for streaming.
+fn streaming_check(support: StreamingSupport) -> Check {
+    match support {
+        StreamingSupport { tail: true, .. } => Check::ok("streaming", "TAIL is supported"),
+        StreamingSupport {
+            tail: false,
+            subscribe: true,
+        } => Check::failed(
+            "streaming",
+            CheckStatus::Warning,
+            "server supports SUBSCRIBE but not TAIL",
+            "Streaming queries won't update; use the select operation instead",
+        ),
+        StreamingSupport {
+            tail: false,
+            subscribe: false,
+        } => Check::failed(
+            "streaming",
+            CheckStatus::Warning,
+            "server supports neither TAIL nor SUBSCRIBE",
+            "Streaming queries won't update; use the select operation instead",
+        ),
+    }
+}
+
+/// Check the result of asking whether the current role has `USAGE` on `schema`.
+fn privilege_check(schema: &str, missing: CheckStatus, usage: Result<bool>) -> Check {
+    match usage {
+        Ok(true) => Check::ok("privileges", format!("role has USAGE on schema {schema}")),
+        Ok(false) => Check::failed(
+            "privileges",
+            missing,
+            format!("role lacks USAGE on schema {schema}"),
+            format!("Grant it with GRANT USAGE ON SCHEMA {schema} TO <role>"),
+        ),
+        // Servers without RBAC don't have the privilege functions, but don't
+        // restrict access to the catalog either.
+        Err(e) => Check::failed(
+            "privileges",
+            CheckStatus::Warning,
+            format!("could not check USAGE on schema {schema}: {e}"),
+            "Privileges can only be checked on servers with role-based access control",
+        ),
+    }
+}
+
+/// Check the result of looking up the session's database or cluster in the catalog.
+fn existence_check(kind: &'static str, name: &str, exists: Result<bool>) -> Check {
+    match exists {
+        Ok(true) => Check::ok(kind, format!("{kind} {name} exists")),
+        Ok(false) => Check::failed(
+            kind,
+            CheckStatus::Error,
+            format!("{kind} {name} does not exist"),
+            format!("Create the {kind} or change the datasource's {kind} setting"),
+        ),
+        Err(e) => Check::failed(
+            kind,
+            CheckStatus::Error,
+            format!("could not look up {kind} {name}: {e}"),
+            format!("Check that the role can read mz_catalog.mz_{kind}s"),
+        ),
+    }
+}
+
+/// Look up the session's current `kind` (database or cluster) using `current`,
+/// and whether it exists in `catalog`.
+async fn session_object(
+    client: &Client,
+    kind: &'static str,
+    current: &str,
+    catalog: &str,
+) -> Result<(String, Check)> {
+    let name: String = client.query_one(current, &[]).await?.get(0);
+    let exists = client
+        .query_one(
+            &format!("SELECT EXISTS (SELECT 1 FROM {catalog} WHERE name = $1)"),
+            &[&name],
+        )
+        .await
+        .map(|row| row.get(0))
+        .map_err(Error::from);
+    let check = existence_check(kind, &name, exists);
+    Ok((name, check))
+}
 
 impl MaterializePlugin {
-    /// Connect to the database and run a `SELECT 1` query, returning `Ok(())` on
-    /// success or `Err(Error)` if anything goes wrong.
-    async fn check_health(&self, request: &backend::CheckHealthRequest) -> Result<()> {
+    /// Connect to the database and check that it's usable by the datasource,
+    /// recording the outcome of each check in a report.
+    ///
+    /// Checks after connecting are independent, so a failure in one doesn't
+    /// stop the others from running.
+    async fn check_health(&self, request: &backend::CheckHealthRequest) -> HealthReport {
+        let mut report = HealthReport::default();
         let datasource_settings = request
             .plugin_context
             .as_ref()
@@ -14,9 +269,98 @@ impl MaterializePlugin {
                 pc.datasource_instance_settings
                     .as_ref()
                     .ok_or(Error::MissingDatasource)
-            })?;
-        let client = self.get_client(datasource_settings).await?;
-        Ok(client.query("SELECT 1", &[]).await.map(|_| ())?)
+            });
+        let connected = match datasource_settings {
+            Ok(settings) => self.get_client(settings).await,
+            Err(e) => Err(e),
+        };
+        let client = match connected {
+            Ok(client) => client,
+            Err(e) => {
+                report.checks.push(Check::failed(
+                    "connection",
+                    CheckStatus::Error,
+                    e.to_string(),
+                    connection_remediation(&e),
+                ));
+                return report;
+            }
+        };
+        report
+            .checks
+            .push(Check::ok("connection", "Connection successful"));
+
+        let started = Instant::now();
+        if let Err(e) = client.query("SELECT 1", &[]).await {
+            report.checks.push(Check::failed(
+                "query",
+                CheckStatus::Error,
+                e.to_string(),
+                "The server accepted the connection but couldn't run a query; check its logs",
+            ));
+            return report;
+        }
+        report.latency_ms = Some(started.elapsed().as_secs_f64() * 1000.0);
+
+        match server_version(&client).await {
+            Ok(version) => {
+                report.version = Some(version.to_string());
+                report
+                    .checks
+                    .push(Check::ok("version", format!("Materialize {version}")));
+            }
+            Err(e) => report.checks.push(Check::failed(
+                "version",
+                CheckStatus::Error,
+                e.to_string(),
+                "Check that the datasource points at a Materialize server",
+            )),
+        }
+
+        // Preparing a statement is enough to tell whether it's supported,
+        // without starting a dataflow.
+        let support = StreamingSupport {
+            tail: client.prepare("TAIL (SELECT 1)").await.is_ok(),
+            subscribe: client.prepare("SUBSCRIBE (SELECT 1)").await.is_ok(),
+        };
+        report.streaming = Some(support);
+        report.checks.push(streaming_check(support));
+
+        for (schema, missing) in REQUIRED_SCHEMAS {
+            let usage = client
+                .query_one("SELECT has_schema_privilege($1, 'USAGE')", &[&schema])
+                .await
+                .map(|row| row.get(0))
+                .map_err(Error::from);
+            report.checks.push(privilege_check(schema, missing, usage));
+        }
+
+        for (kind, current, catalog) in [
+            (
+                "database",
+                "SELECT current_database()",
+                "mz_catalog.mz_databases",
+            ),
+            ("cluster", "SHOW cluster", "mz_catalog.mz_clusters"),
+        ] {
+            match session_object(&client, kind, current, catalog).await {
+                Ok((name, check)) => {
+                    if kind == "database" {
+                        report.database = Some(name);
+                    } else {
+                        report.cluster = Some(name);
+                    }
+                    report.checks.push(check);
+                }
+                Err(e) => report.checks.push(Check::failed(
+                    kind,
+                    CheckStatus::Error,
+                    format!("could not determine the session's {kind}: {e}"),
+                    format!("Check the datasource's {kind} setting"),
+                )),
+            }
+        }
+        report
     }
 }
 
@@ -28,12 +372,20 @@ impl backend::DiagnosticsService for MaterializePlugin {
         &self,
         request: backend::CheckHealthRequest,
     ) -> Result<backend::CheckHealthResponse> {
-        match self.check_health(&request).await {
-            Ok(_) => Ok(backend::CheckHealthResponse::ok(
-                "Connection successful".to_string(),
-            )),
-            Err(e) => Ok(backend::CheckHealthResponse::error(e.to_string())),
-        }
+        let span = request_span(
+            "check_health",
+            request.plugin_context.as_ref(),
+            TraceContext::from_header_map(&request.headers).as_ref(),
+        );
+        let report = catch_panics(async { Ok::<_, Error>(self.check_health(&request).await) })
+            .instrument(span.clone())
+            .await;
+        let response = match report {
+            Ok(report) => report.into_response(),
+            Err(e) => backend::CheckHealthResponse::error(e.to_string()),
+        };
+        span.in_scope(|| info!(status = ?response.status, "health check finished"));
+        Ok(response)
     }
 
     type CollectMetricsError = Error;
@@ -42,6 +394,157 @@ impl backend::DiagnosticsService for MaterializePlugin {
         &self,
         _request: backend::CollectMetricsRequest,
     ) -> Result<backend::CollectMetricsResponse> {
-        todo!()
+        catch_panics(async {
+            let sql_queries = self.sql_queries.len();
+            let payload = self.metrics.encode(sql_queries)?;
+            Ok(backend::CollectMetricsResponse::new(Some(
+                backend::MetricsPayload::prometheus(payload),
+            )))
+        })
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use grafana_plugin_sdk::{backend::DiagnosticsService, pluginv2};
+    use serde_json::json;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn malformed_requests() {
+        let plugin = MaterializePlugin::default();
+        let response = DiagnosticsService::check_health(
+            &plugin,
+            pluginv2::CheckHealthRequest::default().try_into().unwrap(),
+        )
+        .await
+        .unwrap();
+        assert!(matches!(response.status, backend::HealthStatus::Error));
+
+        let request = pluginv2::CheckHealthRequest {
+            plugin_context: Some(pluginv2::PluginContext {
+                data_source_instance_settings: Some(pluginv2::DataSourceInstanceSettings {
+                    json_data: br#"{"port": 6875}"#.to_vec(),
+                    ..Default::default()
+                }),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let response = DiagnosticsService::check_health(&plugin, request.try_into().unwrap())
+            .await
+            .unwrap();
+        assert!(matches!(response.status, backend::HealthStatus::Error));
+
+        let response = plugin
+            .collect_metrics(
+                pluginv2::CollectMetricsRequest::default()
+                    .try_into()
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert!(response.metrics.is_some());
+    }
+
+    #[test]
+    fn streaming() {
+        let check = |tail, subscribe| streaming_check(StreamingSupport { tail, subscribe });
+        assert_eq!(check(true, true).status, CheckStatus::Ok);
+        assert_eq!(check(true, false).status, CheckStatus::Ok);
+        assert_eq!(check(false, true).status, CheckStatus::Warning);
+        assert_eq!(check(false, false).status, CheckStatus::Warning);
+    }
+
+    #[test]
+    fn privileges() {
+        assert_eq!(
+            privilege_check("mz_catalog", CheckStatus::Error, Ok(true)).status,
+            CheckStatus::Ok
+        );
+        let missing = privilege_check("mz_internal", CheckStatus::Warning, Ok(false));
+        assert_eq!(missing.status, CheckStatus::Warning);
+        assert_eq!(missing.message, "role lacks USAGE on schema mz_internal");
+        assert!(missing.remediation.unwrap().contains("GRANT USAGE"));
+        assert_eq!(
+            privilege_check(
+                "mz_catalog",
+                CheckStatus::Error,
+                Err(Error::InvalidCatalog("no such function".to_string()))
+            )
+            .status,
+            CheckStatus::Warning
+        );
+    }
+
+    #[test]
+    fn existence() {
+        assert_eq!(
+            existence_check("cluster", "default", Ok(true)).status,
+            CheckStatus::Ok
+        );
+        let missing = existence_check("cluster", "analytics", Ok(false));
+        assert_eq!(missing.status, CheckStatus::Error);
+        assert_eq!(missing.message, "cluster analytics does not exist");
+    }
+
+    #[test]
+    fn remediation() {
+        assert!(connection_remediation(&Error::MissingDatasource).contains("settings"));
+        assert!(
+            connection_remediation(&Error::ConnectionRefused("refused".to_string()))
+                .contains("host and port")
+        );
+        let tls = Error::AuthenticationFailed(crate::error::ServerMessage {
+            message: "TLS encryption is required".to_string(),
+            detail: None,
+            hint: None,
+        });
+        assert!(connection_remediation(&tls).starts_with("TLS required by server"));
+    }
+
+    #[test]
+    fn report() {
+        let mut report = HealthReport {
+            version: Some("v0.26.4".to_string()),
+            latency_ms: Some(1.5),
+            streaming: Some(StreamingSupport {
+                tail: true,
+                subscribe: false,
+            }),
+            database: Some("materialize".to_string()),
+            cluster: Some("default".to_string()),
+            checks: vec![
+                Check::ok("connection", "Connection successful"),
+                privilege_check("mz_internal", CheckStatus::Warning, Ok(false)),
+            ],
+        };
+        assert_eq!(report.status(), backend::HealthStatus::Ok);
+        assert_eq!(
+            report.message(),
+            "Connection successful, with warnings: privileges: role lacks USAGE on schema \
+             mz_internal (Grant it with GRANT USAGE ON SCHEMA mz_internal TO <role>)"
+        );
+
+        report
+            .checks
+            .push(existence_check("cluster", "default", Ok(false)));
+        assert_eq!(report.status(), backend::HealthStatus::Error);
+        assert!(report.message().starts_with("privileges: "));
+
+        let details = serde_json::to_value(&report).unwrap();
+        assert_eq!(details["version"], json!("v0.26.4"));
+        assert_eq!(details["latencyMs"], json!(1.5));
+        assert_eq!(
+            details["streaming"],
+            json!({"tail": true, "subscribe": false})
+        );
+        assert_eq!(
+            details["checks"][0],
+            json!({"name": "connection", "status": "ok", "message": "Connection successful"})
+        );
+        assert_eq!(details["checks"][2]["status"], json!("error"));
     }
 }
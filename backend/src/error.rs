@@ -1,4 +1,41 @@
+use std::{fmt, io};
+
 use grafana_plugin_sdk::{backend, data, live};
+use http::StatusCode;
+use tokio_postgres::error::{DbError, ErrorPosition, SqlState};
+use tracing::warn;
+
+/// The message reported by the server for an error, along with any detail and
+/// hint it gave.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ServerMessage {
+    pub message: String,
+    pub detail: Option<String>,
+    pub hint: Option<String>,
+}
+
+impl From<&DbError> for ServerMessage {
+    fn from(other: &DbError) -> Self {
+        Self {
+            message: other.message().to_string(),
+            detail: other.detail().map(str::to_string),
+            hint: other.hint().map(str::to_string),
+        }
+    }
+}
+
+impl fmt::Display for ServerMessage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)?;
+        if let Some(detail) = &self.detail {
+            write!(f, ". {detail}")?;
+        }
+        if let Some(hint) = &self.hint {
+            write!(f, " (hint: {hint})")?;
+        }
+        Ok(())
+    }
+}
 
 #[derive(Debug, thiserror::Error)]
 pub enum Error {
@@ -8,6 +45,9 @@ pub enum Error {
     #[error("invalid query")]
     InvalidQuery(serde_json::Error),
 
+    #[error("query does not stream results")]
+    NotStreaming,
+
     #[error("missing tail target")]
     MissingTailTarget,
     #[error("invalid tail target: {0}")]
@@ -15,6 +55,18 @@ pub enum Error {
     #[error("target with name {} not found", .0)]
     TailTargetNotFound(String),
 
+    #[error("invalid macro: {0}")]
+    InvalidMacro(String),
+
+    #[error("no value for template variable {0}")]
+    MissingVariable(String),
+
+    #[error("invalid output format: {0}")]
+    InvalidFormat(String),
+
+    #[error("invalid ad-hoc filter: {0}")]
+    InvalidFilter(String),
+
     #[error("unknown path: {0}. must be one of: tail/object/<name>, tail/select/<query>")]
     UnknownPath(String),
 
@@ -24,8 +76,43 @@ pub enum Error {
     #[error("Datasource not present on request")]
     MissingDatasource,
 
+    #[error("Unsupported Materialize version: {0}")]
+    UnsupportedVersion(String),
+
+    #[error("Unexpected catalog contents: {0}")]
+    InvalidCatalog(String),
+
+    #[error("Unexpected rows: {0}")]
+    InvalidRows(String),
+
+    #[error("Internal error: {0}")]
+    Panic(String),
+
     #[error("Connection error: {0}")]
-    Connection(#[from] tokio_postgres::Error),
+    Connection(tokio_postgres::Error),
+    #[error("Connection refused: {0}")]
+    ConnectionRefused(String),
+    #[error("Authentication failed: {0}")]
+    AuthenticationFailed(ServerMessage),
+
+    #[error(
+        "Syntax error{}: {message}",
+        .position.map(|p| format!(" at position {p}")).unwrap_or_default()
+    )]
+    Syntax {
+        message: ServerMessage,
+        /// The 1-based character position of the error in the statement, if known.
+        position: Option<u32>,
+    },
+    #[error("Relation not found: {0}")]
+    UndefinedRelation(ServerMessage),
+    #[error("Permission denied: {0}")]
+    PermissionDenied(ServerMessage),
+    #[error("Query canceled: {0}")]
+    QueryCanceled(ServerMessage),
+
+    #[error("Read-only violation: statement attempted to modify data: {0}")]
+    ReadOnlyViolation(ServerMessage),
 
     #[error("Error creating channel: {0}")]
     CreatingChannel(live::ChannelError),
@@ -34,8 +121,214 @@ pub enum Error {
     ConvertTo(#[from] backend::ConvertToError),
     #[error("Error converting request: {0}")]
     ConvertFrom(#[from] backend::ConvertFromError),
+    #[error("Error encoding metrics: {0}")]
+    Metrics(#[from] prometheus::Error),
     #[error("Error creating frame : {0}")]
-    Data(#[from] data::Error),
+    Data(Box<data::Error>),
+}
+
+impl Error {
+    /// Classify an error reported by the server by its SQLSTATE code and severity,
+    /// returning `None` if it doesn't have a more specific variant.
+    fn classify(
+        code: &SqlState,
+        severity: &str,
+        message: ServerMessage,
+        position: Option<u32>,
+    ) -> Option<Self> {
+        Some(match *code {
+            SqlState::READ_ONLY_SQL_TRANSACTION => {
+                // Validation should stop these before they reach the server, so
+                // log them loudly for anyone auditing attempted writes.
+                warn!(error = %message, "read-only violation");
+                Self::ReadOnlyViolation(message)
+            }
+            SqlState::SYNTAX_ERROR => Self::Syntax { message, position },
+            SqlState::UNDEFINED_TABLE => Self::UndefinedRelation(message),
+            SqlState::INSUFFICIENT_PRIVILEGE => Self::PermissionDenied(message),
+            SqlState::QUERY_CANCELED => Self::QueryCanceled(message),
+            SqlState::INVALID_PASSWORD | SqlState::INVALID_AUTHORIZATION_SPECIFICATION => {
+                Self::AuthenticationFailed(message)
+            }
+            // Fatal connection exceptions mean the server turned the session away.
+            ref c if c.code().starts_with("08") && severity == "FATAL" => {
+                Self::ConnectionRefused(message.to_string())
+            }
+            _ => return None,
+        })
+    }
+
+    /// The HTTP status best describing this error, for use in resource responses.
+    ///
+    /// Failures to connect or authenticate are reported as a bad gateway rather
+    /// than `401`, since it's the datasource's credentials which were rejected,
+    /// not the Grafana user's.
+    pub fn status(&self) -> StatusCode {
+        match self {
+            Self::ReadOnlyViolation(_) | Self::PermissionDenied(_) => StatusCode::FORBIDDEN,
+            Self::TailTargetNotFound(_) | Self::UndefinedRelation(_) => StatusCode::NOT_FOUND,
+            Self::InvalidFilter(_) | Self::InvalidTailTarget(_) | Self::Syntax { .. } => {
+                StatusCode::BAD_REQUEST
+            }
+            Self::QueryCanceled(_) => StatusCode::GATEWAY_TIMEOUT,
+            Self::ConnectionRefused(_) | Self::AuthenticationFailed(_) => StatusCode::BAD_GATEWAY,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+/// Whether `code` means the server doesn't support a session setting, either
+/// because it doesn't know the parameter or because it can't be changed.
+pub(crate) fn is_unsupported_setting(code: &SqlState) -> bool {
+    [
+        SqlState::UNDEFINED_OBJECT,
+        SqlState::FEATURE_NOT_SUPPORTED,
+        SqlState::CANT_CHANGE_RUNTIME_PARAM,
+        SqlState::INVALID_PARAMETER_VALUE,
+    ]
+    .contains(code)
+}
+
+impl From<tokio_postgres::Error> for Error {
+    fn from(other: tokio_postgres::Error) -> Self {
+        if let Some(db) = other.as_db_error() {
+            let position = match db.position() {
+                Some(ErrorPosition::Original(p)) => Some(*p),
+                _ => None,
+            };
+            // The localized severity depends on the server's `lc_messages`.
+            let severity = db
+                .parsed_severity()
+                .map_or_else(|| db.severity().to_string(), |s| s.to_string());
+            if let Some(classified) = Self::classify(db.code(), &severity, db.into(), position) {
+                return classified;
+            }
+        }
+        let refused = std::error::Error::source(&other)
+            .and_then(|s| s.downcast_ref::<io::Error>())
+            .is_some_and(|e| e.kind() == io::ErrorKind::ConnectionRefused);
+        if refused {
+            Self::ConnectionRefused(other.to_string())
+        } else {
+            Self::Connection(other)
+        }
+    }
+}
+
+impl From<data::Error> for Error {
+    fn from(other: data::Error) -> Self {
+        Self::Data(Box::new(other))
+    }
 }
 
 pub type Result<T> = std::result::Result<T, Error>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(hint: Option<&str>) -> ServerMessage {
+        ServerMessage {
+            message: "unknown catalog item 'ordrs'".to_string(),
+            detail: None,
+            hint: hint.map(str::to_string),
+        }
+    }
+
+    #[test]
+    fn classify() {
+        let classify = |code: &str, severity| {
+            Error::classify(
+                &SqlState::from_code(code),
+                severity,
+                message(None),
+                Some(15),
+            )
+        };
+        assert!(matches!(
+            classify("42601", "ERROR"),
+            Some(Error::Syntax {
+                position: Some(15),
+                ..
+            })
+        ));
+        assert!(matches!(
+            classify("25006", "ERROR"),
+            Some(Error::ReadOnlyViolation(_))
+        ));
+        assert!(matches!(
+            classify("42P01", "ERROR"),
+            Some(Error::UndefinedRelation(_))
+        ));
+        assert!(matches!(
+            classify("42501", "ERROR"),
+            Some(Error::PermissionDenied(_))
+        ));
+        assert!(matches!(
+            classify("57014", "ERROR"),
+            Some(Error::QueryCanceled(_))
+        ));
+        assert!(matches!(
+            classify("28P01", "FATAL"),
+            Some(Error::AuthenticationFailed(_))
+        ));
+        assert!(matches!(
+            classify("08004", "FATAL"),
+            Some(Error::ConnectionRefused(_))
+        ));
+        assert!(classify("08004", "ERROR").is_none());
+        assert!(classify("XX000", "ERROR").is_none());
+    }
+
+    #[test]
+    fn unsupported_settings() {
+        assert!(is_unsupported_setting(&SqlState::UNDEFINED_OBJECT));
+        assert!(is_unsupported_setting(&SqlState::FEATURE_NOT_SUPPORTED));
+        assert!(!is_unsupported_setting(&SqlState::INSUFFICIENT_PRIVILEGE));
+        assert!(!is_unsupported_setting(
+            &SqlState::READ_ONLY_SQL_TRANSACTION
+        ));
+    }
+
+    #[test]
+    fn display() {
+        let error = Error::UndefinedRelation(ServerMessage {
+            detail: Some("The catalog has no such item.".to_string()),
+            ..message(Some("Check the spelling"))
+        });
+        assert_eq!(
+            error.to_string(),
+            "Relation not found: unknown catalog item 'ordrs'. The catalog has no such item. \
+             (hint: Check the spelling)"
+        );
+        let error = Error::Syntax {
+            message: message(None),
+            position: Some(8),
+        };
+        assert_eq!(
+            error.to_string(),
+            "Syntax error at position 8: unknown catalog item 'ordrs'"
+        );
+        assert_eq!(error.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[test]
+    fn status() {
+        assert_eq!(
+            Error::ReadOnlyViolation(message(None)).status(),
+            StatusCode::FORBIDDEN
+        );
+        assert_eq!(
+            Error::PermissionDenied(message(None)).status(),
+            StatusCode::FORBIDDEN
+        );
+        assert_eq!(
+            Error::AuthenticationFailed(message(None)).status(),
+            StatusCode::BAD_GATEWAY
+        );
+        assert_eq!(
+            Error::QueryCanceled(message(None)).status(),
+            StatusCode::GATEWAY_TIMEOUT
+        );
+    }
+}
@@ -0,0 +1,107 @@
+//! `EXPLAIN` queries, used to show the plan Materialize uses to compute a target.
+
+use futures_util::TryStreamExt;
+use grafana_plugin_sdk::{data, prelude::*};
+use serde::Deserialize;
+use tokio_postgres::{Client, Row};
+
+use crate::{queries::TailTarget, Result};
+
+/// The stage of planning to explain.
+#[derive(Clone, Copy, Debug, Default, Hash, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum ExplainPlan {
+    /// The plan after optimization.
+    #[default]
+    Optimized,
+    /// The physical plan which is actually rendered into dataflows.
+    Physical,
+}
+
+impl ExplainPlan {
+    fn as_sql(self) -> &'static str {
+        match self {
+            Self::Optimized => "OPTIMIZED",
+            Self::Physical => "PHYSICAL",
+        }
+    }
+}
+
+/// The `EXPLAIN` statement used to explain `target`.
+pub(crate) fn explain_sql(target: &TailTarget, plan: ExplainPlan) -> String {
+    format!(
+        "EXPLAIN {} PLAN FOR {}",
+        plan.as_sql(),
+        target.select_sql().0
+    )
+}
+
+/// Convert the lines of a plan to a frame with a single `plan` field.
+pub fn plan_to_frame(lines: &[String]) -> data::Frame {
+    data::Frame::new("plan").with_field(lines.iter().cloned().into_field("plan"))
+}
+
+/// Something that can explain how a target is computed.
+#[grafana_plugin_sdk::backend::async_trait]
+pub trait Explainer {
+    /// Explain the plan used to compute `target`, returning one item per line.
+    async fn explain(&self, target: &TailTarget, plan: ExplainPlan) -> Result<Vec<String>>;
+}
+
+#[grafana_plugin_sdk::backend::async_trait]
+impl Explainer for Client {
+    async fn explain(&self, target: &TailTarget, plan: ExplainPlan) -> Result<Vec<String>> {
+        let (_, params) = target.select_sql();
+        let rows: Vec<Row> = self
+            .query_raw(&explain_sql(target, plan), params)
+            .await?
+            .try_collect()
+            .await?;
+        // The plan is usually returned as a single row of text, but split
+        // every row so each line can be shown separately.
+        Ok(rows
+            .iter()
+            .flat_map(|row| {
+                row.get::<_, String>(0)
+                    .lines()
+                    .map(str::to_string)
+                    .collect::<Vec<_>>()
+            })
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sql() {
+        assert_eq!(
+            explain_sql(
+                &TailTarget::Relation {
+                    name: "public.orders".parse().unwrap()
+                },
+                ExplainPlan::Optimized
+            ),
+            r#"EXPLAIN OPTIMIZED PLAN FOR SELECT * FROM "public"."orders""#
+        );
+        assert_eq!(
+            explain_sql(
+                &TailTarget::Select {
+                    statement: "SELECT region FROM orders".parse().unwrap()
+                },
+                ExplainPlan::Physical
+            ),
+            "EXPLAIN PHYSICAL PLAN FOR SELECT region FROM orders"
+        );
+    }
+
+    #[test]
+    fn frame() {
+        let frame = plan_to_frame(&["%0 =".to_string(), "| Get orders".to_string()]);
+        assert_eq!(frame.fields().len(), 1);
+        assert_eq!(frame.fields()[0].name, "plan");
+        assert_eq!(frame.fields()[0].values().len(), 2);
+    }
+}
@@ -0,0 +1,229 @@
+//! Grafana ad-hoc filters, applied to queries as `WHERE` clauses.
+//!
+//! Ad-hoc filters are `key <operator> value` conditions added by users across
+//! a whole dashboard. Keys must be columns of the filtered target; values are
+//! always sent as query parameters.
+
+use serde::Deserialize;
+use tokio_postgres::Client;
+
+use crate::{
+    catalog::Catalog,
+    params::ParamValue,
+    queries::{SelectStatement, TailTarget},
+    sql, Error, Result,
+};
+
+/// A comparison operator supported by ad-hoc filters.
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq, Deserialize)]
+pub enum FilterOperator {
+    #[serde(rename = "=")]
+    Equal,
+    #[serde(rename = "!=")]
+    NotEqual,
+    #[serde(rename = "<")]
+    LessThan,
+    #[serde(rename = ">")]
+    GreaterThan,
+    #[serde(rename = "=~")]
+    RegexMatch,
+    #[serde(rename = "!~")]
+    RegexNotMatch,
+}
+
+/// A single ad-hoc filter, as sent by Grafana.
+#[derive(Clone, Debug, Hash, PartialEq, Eq, Deserialize)]
+pub struct AdHocFilter {
+    /// The column to filter on.
+    pub key: String,
+    pub operator: FilterOperator,
+    pub value: String,
+}
+
+impl AdHocFilter {
+    /// Render this filter as a SQL condition comparing the column to parameter `$n`.
+    fn condition(&self, n: usize) -> String {
+        let column = sql::quote_ident(&self.key);
+        match self.operator {
+            FilterOperator::Equal => format!("{column} = ${n}"),
+            FilterOperator::NotEqual => format!("{column} <> ${n}"),
+            FilterOperator::LessThan => format!("{column} < ${n}"),
+            FilterOperator::GreaterThan => format!("{column} > ${n}"),
+            // Regular expressions only apply to text, so cast the column
+            // to allow filtering on other types too.
+            FilterOperator::RegexMatch => format!("{column}::text ~ ${n}"),
+            FilterOperator::RegexNotMatch => format!("{column}::text !~ ${n}"),
+        }
+    }
+}
+
+/// Get the names of the columns returned by `target`.
+///
+/// Relations are looked up in the catalog, while statements are
+/// described by the server without being run.
+pub async fn target_columns(client: &Client, target: &TailTarget) -> Result<Vec<String>> {
+    Ok(match target {
+        TailTarget::Relation { name } => client
+            .columns(name)
+            .await?
+            .into_iter()
+            .map(|column| column.name)
+            .collect(),
+        TailTarget::Select { statement } => client
+            .prepare(statement.as_str())
+            .await?
+            .columns()
+            .iter()
+            .map(|column| column.name().to_string())
+            .collect(),
+    })
+}
+
+/// Wrap `target` in a subquery applying `filters`.
+///
+/// `columns` should contain the columns of `target`, as returned by
+/// [`target_columns`]; filters on any other keys are rejected.
+///
+/// If `filters` is empty the target is returned unchanged. Otherwise the
+/// result is always a [`TailTarget::Select`].
+pub fn apply(
+    target: &TailTarget,
+    filters: &[AdHocFilter],
+    columns: &[String],
+) -> Result<TailTarget> {
+    if filters.is_empty() {
+        return Ok(target.clone());
+    }
+    if let Some(filter) = filters.iter().find(|f| !columns.contains(&f.key)) {
+        return Err(Error::InvalidFilter(format!(
+            "{} is not a column of the filtered target",
+            filter.key
+        )));
+    }
+
+    let (inner, mut params) = match target {
+        TailTarget::Relation { name } => (name.to_string(), Vec::new()),
+        TailTarget::Select { statement } => (format!("({statement})"), statement.params().to_vec()),
+    };
+    let conditions: Vec<_> = filters
+        .iter()
+        .map(|filter| {
+            params.push(ParamValue::Single(filter.value.clone()));
+            filter.condition(params.len())
+        })
+        .collect();
+    let sql = format!(
+        "SELECT * FROM {inner} AS filtered WHERE {}",
+        conditions.join(" AND ")
+    );
+    Ok(TailTarget::Select {
+        statement: SelectStatement::from_parts(sql, params),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn filter(key: &str, operator: FilterOperator, value: &str) -> AdHocFilter {
+        AdHocFilter {
+            key: key.to_string(),
+            operator,
+            value: value.to_string(),
+        }
+    }
+
+    fn columns() -> Vec<String> {
+        vec!["region".to_string(), "Host Name".to_string()]
+    }
+
+    #[test]
+    fn deserialize() {
+        assert_eq!(
+            serde_json::from_str::<Vec<AdHocFilter>>(
+                r#"[{"key": "region", "operator": "=~", "value": "eu-.*", "condition": ""}]"#
+            )
+            .unwrap(),
+            vec![filter("region", FilterOperator::RegexMatch, "eu-.*")]
+        );
+    }
+
+    #[test]
+    fn apply_to_relation() {
+        let target = TailTarget::Relation {
+            name: "orders".parse().unwrap(),
+        };
+        assert_eq!(apply(&target, &[], &columns()).unwrap(), target);
+
+        let filtered = apply(
+            &target,
+            &[
+                filter("region", FilterOperator::Equal, "eu"),
+                filter(
+                    "Host Name",
+                    FilterOperator::RegexNotMatch,
+                    "'; DROP VIEW x; --",
+                ),
+            ],
+            &columns(),
+        )
+        .unwrap();
+        match filtered {
+            TailTarget::Select { statement } => {
+                assert_eq!(
+                    statement.as_str(),
+                    r#"SELECT * FROM "orders" AS filtered WHERE "region" = $1 AND "Host Name"::text !~ $2"#
+                );
+                assert_eq!(
+                    statement.params(),
+                    &[
+                        ParamValue::Single("eu".to_string()),
+                        ParamValue::Single("'; DROP VIEW x; --".to_string()),
+                    ]
+                );
+            }
+            other => panic!("unexpected target {other:?}"),
+        }
+    }
+
+    #[test]
+    fn apply_to_select() {
+        let statement: SelectStatement = "SELECT * FROM orders WHERE id > $min".parse().unwrap();
+        let target = TailTarget::Select {
+            statement: statement
+                .bind(&[("min".to_string(), ParamValue::Single("10".to_string()))].into())
+                .unwrap(),
+        };
+        let filtered = apply(
+            &target,
+            &[filter("region", FilterOperator::NotEqual, "us")],
+            &columns(),
+        )
+        .unwrap();
+        match filtered {
+            TailTarget::Select { statement } => {
+                assert_eq!(
+                    statement.as_str(),
+                    r#"SELECT * FROM (SELECT * FROM orders WHERE id > $1) AS filtered WHERE "region" <> $2"#
+                );
+                assert_eq!(statement.params().len(), 2);
+            }
+            other => panic!("unexpected target {other:?}"),
+        }
+    }
+
+    #[test]
+    fn unknown_key() {
+        let target = TailTarget::Relation {
+            name: "orders".parse().unwrap(),
+        };
+        assert!(matches!(
+            apply(
+                &target,
+                &[filter("region; DROP VIEW x", FilterOperator::Equal, "eu")],
+                &columns()
+            ),
+            Err(Error::InvalidFilter(_))
+        ));
+    }
+}
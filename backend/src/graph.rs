@@ -0,0 +1,309 @@
+//! Dependency graphs between catalog objects, shown using Grafana's Node Graph panel.
+
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+
+use grafana_plugin_sdk::{data, prelude::*};
+
+use crate::{
+    catalog::{Relation, RelationKind},
+    queries::SourceName,
+    Error, Result,
+};
+
+/// An object in the dependency graph, along with optional dataflow statistics.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct GraphNode {
+    /// The catalog ID of the object, e.g. `u1`.
+    pub id: String,
+    pub relation: Relation,
+    /// The number of records in the object's arrangements, if it has any.
+    pub records: Option<i64>,
+    /// The size of the object's arrangements in bytes, if it has any.
+    pub size: Option<i64>,
+}
+
+/// A dependency between two objects, by ID.
+///
+/// `dependent` can't exist without `referenced`, e.g. a view which
+/// selects from a source or an index on a view.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Dependency {
+    pub referenced: String,
+    pub dependent: String,
+}
+
+/// A set of catalog objects and the dependencies between them.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ObjectGraph {
+    pub nodes: Vec<GraphNode>,
+    pub dependencies: Vec<Dependency>,
+}
+
+/// Whether `name` refers to `relation`.
+///
+/// Unqualified parts of `name` match any database or schema.
+fn matches(name: &SourceName, relation: &Relation) -> bool {
+    name.name() == relation.name
+        && name.schema().is_none_or(|s| s == relation.schema)
+        && name.database().is_none_or(|d| d == relation.database)
+}
+
+/// The color used for nodes of each kind of object.
+///
+/// The Node Graph panel expects CSS colors rather than the names of Grafana's
+/// palette, so these are the hex values of the palette's colors.
+fn kind_color(kind: RelationKind) -> &'static str {
+    match kind {
+        // blue
+        RelationKind::Table => "#3274D9",
+        // green
+        RelationKind::View => "#56A64B",
+        // dark-green
+        RelationKind::MaterializedView => "#37872D",
+        // purple
+        RelationKind::Source => "#A352CC",
+        // orange
+        RelationKind::Sink => "#FF780A",
+        // yellow
+        RelationKind::Index => "#F2CC0C",
+    }
+}
+
+impl ObjectGraph {
+    /// Restrict the graph to the objects connected to `root`, i.e. everything
+    /// it depends on and everything which depends on it, transitively.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`Error::TailTargetNotFound`] if no object matches `root`.
+    pub fn around(self, root: &SourceName) -> Result<Self> {
+        let roots: Vec<_> = self
+            .nodes
+            .iter()
+            .filter(|node| matches(root, &node.relation))
+            .map(|node| node.id.as_str())
+            .collect();
+        if roots.is_empty() {
+            return Err(Error::TailTargetNotFound(root.to_string()));
+        }
+
+        // Walk upstream and downstream separately, so that siblings (other
+        // dependents of the root's dependencies) aren't included.
+        let mut upstream: BTreeMap<&str, Vec<&str>> = BTreeMap::new();
+        let mut downstream: BTreeMap<&str, Vec<&str>> = BTreeMap::new();
+        for dep in &self.dependencies {
+            upstream
+                .entry(dep.dependent.as_str())
+                .or_default()
+                .push(&dep.referenced);
+            downstream
+                .entry(dep.referenced.as_str())
+                .or_default()
+                .push(&dep.dependent);
+        }
+        fn walk<'a>(
+            roots: &[&'a str],
+            edges: &BTreeMap<&'a str, Vec<&'a str>>,
+        ) -> BTreeSet<&'a str> {
+            let mut seen: BTreeSet<&str> = roots.iter().copied().collect();
+            let mut queue: VecDeque<&str> = roots.iter().copied().collect();
+            while let Some(id) = queue.pop_front() {
+                for next in edges.get(id).into_iter().flatten() {
+                    if seen.insert(next) {
+                        queue.push_back(next);
+                    }
+                }
+            }
+            seen
+        }
+        let keep: BTreeSet<String> = walk(&roots, &upstream)
+            .union(&walk(&roots, &downstream))
+            .map(|id| id.to_string())
+            .collect();
+
+        Ok(Self {
+            nodes: self
+                .nodes
+                .into_iter()
+                .filter(|node| keep.contains(&node.id))
+                .collect(),
+            dependencies: self
+                .dependencies
+                .into_iter()
+                .filter(|dep| keep.contains(&dep.referenced) && keep.contains(&dep.dependent))
+                .collect(),
+        })
+    }
+
+    /// Convert the graph to the `nodes` and `edges` frames used by the Node Graph panel.
+    ///
+    /// Edges point from each object to the objects depending on it, so data flows
+    /// from left to right.
+    pub fn to_frames(&self) -> [data::Frame; 2] {
+        let mut metadata = data::Metadata::default();
+        metadata.preferred_visualisation = Some(data::VisType::NodeGraph);
+
+        let nodes = data::Frame::new("nodes")
+            .with_metadata(metadata.clone())
+            .with_fields([
+                self.nodes.iter().map(|n| n.id.clone()).into_field("id"),
+                self.nodes
+                    .iter()
+                    .map(|n| n.relation.name.clone())
+                    .into_field("title"),
+                self.nodes
+                    .iter()
+                    .map(|n| format!("{} ({})", n.relation.schema, n.relation.kind))
+                    .into_field("subTitle"),
+                self.nodes
+                    .iter()
+                    .map(|n| n.records)
+                    .into_opt_field("mainStat"),
+                self.nodes
+                    .iter()
+                    .map(|n| n.size)
+                    .into_opt_field("secondaryStat"),
+                self.nodes
+                    .iter()
+                    .map(|n| kind_color(n.relation.kind))
+                    .into_field("color"),
+            ]);
+        let edges = data::Frame::new("edges")
+            .with_metadata(metadata)
+            .with_fields([
+                self.dependencies
+                    .iter()
+                    .map(|d| format!("{}-{}", d.referenced, d.dependent))
+                    .into_field("id"),
+                self.dependencies
+                    .iter()
+                    .map(|d| d.referenced.clone())
+                    .into_field("source"),
+                self.dependencies
+                    .iter()
+                    .map(|d| d.dependent.clone())
+                    .into_field("target"),
+            ]);
+        [nodes, edges]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn node(id: &str, schema: &str, name: &str, kind: RelationKind) -> GraphNode {
+        GraphNode {
+            id: id.to_string(),
+            relation: Relation::new(
+                "materialize".to_string(),
+                schema.to_string(),
+                name.to_string(),
+                kind,
+            ),
+            records: None,
+            size: None,
+        }
+    }
+
+    fn dep(referenced: &str, dependent: &str) -> Dependency {
+        Dependency {
+            referenced: referenced.to_string(),
+            dependent: dependent.to_string(),
+        }
+    }
+
+    fn graph() -> ObjectGraph {
+        ObjectGraph {
+            nodes: vec![
+                node("u1", "public", "orders_src", RelationKind::Source),
+                node("u2", "public", "orders", RelationKind::MaterializedView),
+                node("u3", "public", "orders_idx", RelationKind::Index),
+                node("u4", "public", "orders_sink", RelationKind::Sink),
+                node("u5", "public", "refunds", RelationKind::View),
+                node("u6", "other", "unrelated", RelationKind::Table),
+            ],
+            dependencies: vec![
+                dep("u1", "u2"),
+                dep("u2", "u3"),
+                dep("u2", "u4"),
+                dep("u1", "u5"),
+            ],
+        }
+    }
+
+    #[test]
+    fn around() {
+        let around = graph().around(&"public.orders".parse().unwrap()).unwrap();
+        let ids: Vec<_> = around.nodes.iter().map(|n| n.id.as_str()).collect();
+        // The source and both dependents of `orders` are included, but the sibling
+        // view `refunds` and the unrelated table are not.
+        assert_eq!(ids, ["u1", "u2", "u3", "u4"]);
+        assert_eq!(
+            around.dependencies,
+            vec![dep("u1", "u2"), dep("u2", "u3"), dep("u2", "u4")]
+        );
+
+        assert!(matches!(
+            graph().around(&"staging.orders".parse().unwrap()),
+            Err(Error::TailTargetNotFound(_))
+        ));
+    }
+
+    #[test]
+    fn frames() {
+        let around = graph().around(&"orders_idx".parse().unwrap()).unwrap();
+        let [nodes, edges] = around.to_frames();
+        let names = |frame: &data::Frame| {
+            frame
+                .fields()
+                .iter()
+                .map(|f| f.name.clone())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            names(&nodes),
+            [
+                "id",
+                "title",
+                "subTitle",
+                "mainStat",
+                "secondaryStat",
+                "color"
+            ]
+        );
+        assert_eq!(names(&edges), ["id", "source", "target"]);
+        assert_eq!(nodes.fields()[0].values().len(), 3);
+        assert_eq!(edges.fields()[0].values().len(), 2);
+        nodes.check().unwrap();
+        edges.check().unwrap();
+    }
+
+    #[test]
+    fn colors() {
+        let is_css_hex = |c: &str| {
+            c.strip_prefix('#')
+                .is_some_and(|hex| hex.len() == 6 && hex.chars().all(|c| c.is_ascii_hexdigit()))
+        };
+        for kind in [
+            RelationKind::Table,
+            RelationKind::View,
+            RelationKind::MaterializedView,
+            RelationKind::Source,
+            RelationKind::Sink,
+            RelationKind::Index,
+        ] {
+            assert!(is_css_hex(kind_color(kind)), "{kind:?}");
+        }
+
+        let [nodes, _] = graph().to_frames();
+        let field = nodes.fields().iter().find(|f| f.name == "color").unwrap();
+        let colors = field
+            .values()
+            .as_any()
+            .downcast_ref::<grafana_plugin_sdk::arrow2::array::Utf8Array<i32>>()
+            .unwrap();
+        assert_eq!(colors.len(), 6);
+        assert!(colors.values_iter().all(is_css_hex));
+    }
+}
@@ -0,0 +1,410 @@
+//! Predefined queries against Materialize's introspection relations.
+//!
+//! These let operators build dashboards showing whether sources, sinks and
+//! replicas are healthy without writing catalog SQL by hand. The introspection
+//! relations have changed significantly between Materialize versions, so each
+//! query has a variant per supported version range.
+
+use std::{fmt, str::FromStr};
+
+use chrono::{DateTime, Utc};
+use grafana_plugin_sdk::{arrow2::array::PrimitiveArray, data, prelude::*};
+use serde::Deserialize;
+use tokio_postgres::Client;
+
+use crate::{queries::SelectStatement, Error, Result};
+
+/// A Materialize server version, as reported by `mz_version()`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Version {
+    pub major: u32,
+    pub minor: u32,
+    pub patch: u32,
+}
+
+impl Version {
+    pub const fn new(major: u32, minor: u32, patch: u32) -> Self {
+        Self {
+            major,
+            minor,
+            patch,
+        }
+    }
+}
+
+impl fmt::Display for Version {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "v{}.{}.{}", self.major, self.minor, self.patch)
+    }
+}
+
+/// Parses versions such as `v0.26.4 (0a1b2c3d4)`, ignoring anything after the
+/// version number itself.
+impl FromStr for Version {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self> {
+        let invalid = || Error::InvalidCatalog(format!("unrecognised version {s}"));
+        let number = s
+            .trim_start_matches('v')
+            .split([' ', '-'])
+            .next()
+            .unwrap_or_default();
+        let mut parts = number.split('.').map(|p| p.parse::<u32>());
+        match (parts.next(), parts.next(), parts.next()) {
+            (Some(Ok(major)), Some(Ok(minor)), Some(Ok(patch))) => {
+                Ok(Self::new(major, minor, patch))
+            }
+            _ => Err(invalid()),
+        }
+    }
+}
+
+/// A variant of an introspection query, used from version `since` onwards
+/// until the next variant's version.
+struct Variant {
+    since: Version,
+    sql: &'static str,
+}
+
+/// The oldest version of all, for variants using relations which have been
+/// available since before any version this plugin supports.
+const ANY_VERSION: Version = Version::new(0, 0, 0);
+
+/// The variants of [`IntrospectionQuery::SourceStatus`], newest first.
+const SOURCE_STATUS: &[Variant] = &[
+    Variant {
+        since: Version::new(0, 44, 0),
+        sql: r#"SELECT mss.name AS source, mss.type AS type, mss.status AS status, mss.error AS error, mss.last_status_change_at AS last_status_change_at, mf.write_frontier::text::numeric AS write_frontier
+            FROM mz_internal.mz_source_statuses mss
+            LEFT JOIN mz_internal.mz_frontiers mf ON mf.object_id = mss.id
+            ORDER BY mss.name"#,
+    },
+    Variant {
+        since: Version::new(0, 39, 0),
+        sql: r#"SELECT mss.name AS source, mss.type AS type, mss.status AS status, mss.error AS error, mss.last_status_change_at AS last_status_change_at, mmf.time::numeric AS write_frontier
+            FROM mz_internal.mz_source_statuses mss
+            LEFT JOIN mz_catalog.mz_materialization_frontiers mmf ON mmf.global_id = mss.id
+            ORDER BY mss.name"#,
+    },
+    // Older versions don't track status, but do report ingestion progress.
+    Variant {
+        since: ANY_VERSION,
+        sql: r#"SELECT msi.source_name AS source, msi.partition_id AS partition_id, msi."offset" AS "offset", msi.timestamp::numeric AS write_frontier
+            FROM mz_catalog.mz_source_info msi
+            ORDER BY msi.source_name, msi.partition_id"#,
+    },
+];
+
+/// The variants of [`IntrospectionQuery::SinkStatus`], newest first.
+const SINK_STATUS: &[Variant] = &[Variant {
+    since: Version::new(0, 41, 0),
+    sql: r#"SELECT mss.name AS sink, mss.type AS type, mss.status AS status, mss.error AS error, mss.last_status_change_at AS last_status_change_at
+        FROM mz_internal.mz_sink_statuses mss
+        ORDER BY mss.name"#,
+}];
+
+/// The variants of [`IntrospectionQuery::ReplicaUtilization`], newest first.
+const REPLICA_UTILIZATION: &[Variant] = &[Variant {
+    since: Version::new(0, 29, 0),
+    sql: r#"SELECT mc.name AS cluster, mcr.name AS replica, mcru.process_id AS process_id, mcru.cpu_percent AS cpu_percent, mcru.memory_percent AS memory_percent
+        FROM mz_internal.mz_cluster_replica_utilization mcru
+        JOIN mz_catalog.mz_cluster_replicas mcr ON mcru.replica_id = mcr.id
+        JOIN mz_catalog.mz_clusters mc ON mcr.cluster_id = mc.id
+        ORDER BY mc.name, mcr.name, mcru.process_id"#,
+}];
+
+/// The variants of [`IntrospectionQuery::Freshness`], newest first.
+const FRESHNESS: &[Variant] = &[
+    Variant {
+        since: Version::new(0, 44, 0),
+        sql: r#"SELECT mzo.name AS object, mzo.type AS type, mf.write_frontier::text::numeric AS write_frontier
+            FROM mz_internal.mz_frontiers mf
+            JOIN mz_catalog.mz_objects mzo ON mf.object_id = mzo.id
+            ORDER BY mzo.name"#,
+    },
+    Variant {
+        since: ANY_VERSION,
+        sql: r#"SELECT mzo.name AS object, mzo.type AS type, mmf.time::numeric AS write_frontier
+            FROM mz_catalog.mz_materialization_frontiers mmf
+            JOIN mz_catalog.mz_objects mzo ON mmf.global_id = mzo.id
+            ORDER BY mzo.name"#,
+    },
+];
+
+/// The column of introspection results holding each object's write frontier,
+/// in milliseconds since the Unix epoch.
+const WRITE_FRONTIER: &str = "write_frontier";
+
+/// The field added by [`add_lag`].
+const LAG: &str = "lag_ms";
+
+/// Add a `lag_ms` field to `frame` for each of its numeric `write_frontier`
+/// fields, giving how far each frontier is behind `now` in milliseconds.
+///
+/// The current time can't be used in a `TAIL`, so the lag reported by the
+/// introspection queries is computed here, both for snapshots and for each
+/// streamed update. This applies to any frame with such a field, so
+/// statements selecting frontiers themselves get the lag too.
+/// Any labels of the frontier field are copied to its lag field.
+pub fn add_lag(frame: &mut data::Frame, now: DateTime<Utc>) {
+    let now = now.timestamp_millis() as f64;
+    let lags: Vec<_> = frame
+        .fields()
+        .iter()
+        .filter(|field| field.name == WRITE_FRONTIER)
+        .filter_map(|field| {
+            let values = field.values().as_any();
+            let frontiers: Vec<Option<f64>> =
+                if let Some(values) = values.downcast_ref::<PrimitiveArray<i64>>() {
+                    values.iter().map(|v| v.map(|v| *v as f64)).collect()
+                } else {
+                    let values = values.downcast_ref::<PrimitiveArray<f64>>()?;
+                    values.iter().map(|v| v.copied()).collect()
+                };
+            let mut lag = frontiers
+                .into_iter()
+                .map(|frontier| frontier.map(|f| now - f))
+                .into_opt_field(LAG);
+            lag.labels = field.labels.clone();
+            Some(lag)
+        })
+        .collect();
+    for lag in lags {
+        frame.add_field(lag);
+    }
+}
+
+/// Get the version of the Materialize server `client` is connected to.
+pub async fn server_version(client: &Client) -> Result<Version> {
+    client
+        .query_one("SELECT mz_version()", &[])
+        .await?
+        .get::<_, String>(0)
+        .parse()
+}
+
+/// A predefined introspection query.
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum IntrospectionQuery {
+    /// The ingestion status of each source, along with any error.
+    SourceStatus,
+    /// The status of each sink, along with any error.
+    SinkStatus,
+    /// The CPU and memory utilisation of each cluster replica.
+    ReplicaUtilization,
+    /// How far each object's write frontier lags behind the current time, in milliseconds.
+    Freshness,
+}
+
+impl IntrospectionQuery {
+    /// The name of this query, as used in requests.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::SourceStatus => "sourceStatus",
+            Self::SinkStatus => "sinkStatus",
+            Self::ReplicaUtilization => "replicaUtilization",
+            Self::Freshness => "freshness",
+        }
+    }
+
+    /// The variants of this query, newest first.
+    ///
+    /// Each relation in `mz_internal` arrived in a different release, so each
+    /// query has its own minimum version.
+    fn variants(self) -> &'static [Variant] {
+        match self {
+            Self::SourceStatus => SOURCE_STATUS,
+            Self::SinkStatus => SINK_STATUS,
+            Self::ReplicaUtilization => REPLICA_UTILIZATION,
+            Self::Freshness => FRESHNESS,
+        }
+    }
+
+    /// Get the SQL for this query suitable for a server running `version`.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`Error::UnsupportedVersion`] if the query isn't available in `version`.
+    pub fn sql(self, version: Version) -> Result<&'static str> {
+        let variants = self.variants();
+        variants
+            .iter()
+            .find(|v| v.since <= version)
+            .map(|v| v.sql)
+            .ok_or_else(|| {
+                let oldest = variants.last().map_or(ANY_VERSION, |v| v.since);
+                Error::UnsupportedVersion(format!(
+                    "{} requires Materialize {oldest} or later, but the server is running {version}",
+                    self.as_str()
+                ))
+            })
+    }
+
+    /// Get the statement for this query suitable for a server running `version`.
+    ///
+    /// Every statement can be streamed. Those reporting lag select each
+    /// object's write frontier, from which [`add_lag`] computes it.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`Error::UnsupportedVersion`] if the query isn't available in `version`.
+    pub fn statement(self, version: Version) -> Result<SelectStatement> {
+        self.sql(version)?.parse()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+
+    use super::*;
+
+    const ALL: [IntrospectionQuery; 4] = [
+        IntrospectionQuery::SourceStatus,
+        IntrospectionQuery::SinkStatus,
+        IntrospectionQuery::ReplicaUtilization,
+        IntrospectionQuery::Freshness,
+    ];
+
+    #[test]
+    fn parse_version() {
+        assert_eq!(
+            "v0.26.4 (0a1b2c3d4)".parse::<Version>().unwrap(),
+            Version::new(0, 26, 4)
+        );
+        assert_eq!(
+            "v0.89.0-dev (abc)".parse::<Version>().unwrap(),
+            Version::new(0, 89, 0)
+        );
+        assert!("unknown".parse::<Version>().is_err());
+        assert!(Version::new(0, 26, 4) < Version::new(0, 27, 0));
+    }
+
+    #[test]
+    fn statements_are_read_only() {
+        for query in ALL {
+            query.statement(Version::new(0, 90, 0)).unwrap();
+            match query.statement(Version::new(0, 26, 0)) {
+                Ok(_) | Err(Error::UnsupportedVersion(_)) => {}
+                Err(other) => panic!("invalid legacy statement for {query:?}: {other}"),
+            }
+        }
+    }
+
+    #[test]
+    fn unsupported_versions() {
+        assert!(matches!(
+            IntrospectionQuery::SinkStatus.sql(Version::new(0, 26, 0)),
+            Err(Error::UnsupportedVersion(_))
+        ));
+        assert!(IntrospectionQuery::Freshness
+            .sql(Version::new(0, 26, 0))
+            .unwrap()
+            .contains("mz_materialization_frontiers"));
+    }
+
+    #[test]
+    fn minimum_versions() {
+        let uses = |query: IntrospectionQuery, version, relation| {
+            query.sql(version).unwrap().contains(relation)
+        };
+        // Each query switches to its `mz_internal` relation at its own version.
+        assert!(uses(
+            IntrospectionQuery::ReplicaUtilization,
+            Version::new(0, 29, 0),
+            "mz_cluster_replica_utilization"
+        ));
+        assert!(uses(
+            IntrospectionQuery::SourceStatus,
+            Version::new(0, 38, 0),
+            "mz_source_info"
+        ));
+        assert!(uses(
+            IntrospectionQuery::SourceStatus,
+            Version::new(0, 39, 0),
+            "mz_source_statuses"
+        ));
+        assert!(uses(
+            IntrospectionQuery::SourceStatus,
+            Version::new(0, 44, 0),
+            "mz_frontiers"
+        ));
+        assert!(matches!(
+            IntrospectionQuery::SinkStatus.sql(Version::new(0, 40, 0)),
+            Err(Error::UnsupportedVersion(_))
+        ));
+        assert!(uses(
+            IntrospectionQuery::Freshness,
+            Version::new(0, 43, 0),
+            "mz_materialization_frontiers"
+        ));
+        assert!(uses(
+            IntrospectionQuery::Freshness,
+            Version::new(0, 44, 0),
+            "mz_frontiers"
+        ));
+    }
+
+    #[test]
+    fn streaming() {
+        // Every variant can be used in a `TAIL`, so none use the current time.
+        let versions = [
+            Version::new(0, 26, 0),
+            Version::new(0, 39, 0),
+            Version::new(0, 44, 0),
+        ];
+        for query in ALL {
+            for version in versions {
+                let Ok(sql) = query.sql(version) else {
+                    continue;
+                };
+                assert!(
+                    !sql.contains("mz_now()") && !sql.contains("mz_logical_timestamp()"),
+                    "{query:?} uses the current time in {version}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn lag() {
+        let time = |ms| Utc.timestamp_millis_opt(ms).unwrap();
+        let mut frame = data::Frame::new("freshness")
+            .with_field(["a", "b", "c"].into_field("object"))
+            .with_field([Some(1_000i64), None, Some(9_500)].into_opt_field(WRITE_FRONTIER));
+        add_lag(&mut frame, time(10_000));
+        let field = &frame.fields()[2];
+        assert_eq!(field.name, LAG);
+        let lags = field
+            .values()
+            .as_any()
+            .downcast_ref::<PrimitiveArray<f64>>()
+            .unwrap();
+        assert_eq!(
+            lags.iter().map(|v| v.copied()).collect::<Vec<_>>(),
+            [Some(9_000.0), None, Some(500.0)]
+        );
+        frame.check().unwrap();
+
+        // Time series frames keep the labels of their values.
+        let mut field = [2_000.0f64].into_field(WRITE_FRONTIER);
+        field.labels = [("object".to_string(), "a".to_string())].into();
+        let mut frame = data::Frame::new("freshness").with_field(field);
+        add_lag(&mut frame, time(10_000));
+        assert_eq!(frame.fields()[1].labels, frame.fields()[0].labels);
+
+        // Frames without frontiers are left alone.
+        let mut frame = data::Frame::new("sinks").with_field(["a"].into_field("sink"));
+        add_lag(&mut frame, time(10_000));
+        assert_eq!(frame.fields().len(), 1);
+    }
+
+    #[test]
+    fn deserialize() {
+        assert_eq!(
+            serde_json::from_str::<IntrospectionQuery>(r#""replicaUtilization""#).unwrap(),
+            IntrospectionQuery::ReplicaUtilization
+        );
+    }
+}
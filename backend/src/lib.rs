@@ -1,55 +1,155 @@
+mod catalog;
 mod convert;
 mod data;
 mod diagnostics;
 mod error;
+mod explain;
+mod filters;
+mod graph;
+mod introspection;
+mod macros;
+mod metrics;
+mod notices;
+mod output;
+mod panics;
+mod params;
 mod path;
 mod queries;
+mod registry;
 mod resource;
+mod sql;
+mod stats;
 mod stream;
+#[cfg(test)]
+mod testing;
+mod trace;
+mod variable;
 
-use std::{collections::HashMap, sync::Arc};
+use std::{
+    collections::HashSet,
+    sync::{Arc, Mutex},
+    time::Instant,
+};
 
 use grafana_plugin_sdk::backend;
 use serde::Deserialize;
-use tokio::sync::RwLock;
 use tokio_postgres::{Client, Config, NoTls};
+use tracing::{debug, info, warn};
 
-use convert::rows_to_frame;
 use error::{Error, Result};
+use notices::Notices;
+use registry::QueryRegistry;
 
-/// An atomically reference counted, shareable async hashmap from query ID to select statement.
-pub type SqlQueries = Arc<RwLock<HashMap<path::QueryId, queries::SelectStatement>>>;
-
-#[derive(Clone, Debug, Default)]
+#[derive(Clone, Debug)]
 pub struct MaterializePlugin {
-    /// SQL queries that have previously been served by this plugin process.
-    sql_queries: SqlQueries,
+    /// SQL queries that have previously been served by this plugin.
+    sql_queries: QueryRegistry,
+    /// Metrics describing the plugin's activity, served by `collect_metrics`.
+    metrics: Arc<metrics::Metrics>,
+    /// The UIDs of datasources whose servers don't support read-only sessions,
+    /// so the fallback is only logged once for each.
+    read_only_fallbacks: Arc<Mutex<HashSet<String>>>,
+}
+
+/// The default plugin keeps its query registry in memory only.
+impl Default for MaterializePlugin {
+    fn default() -> Self {
+        let metrics = Arc::new(metrics::Metrics::default());
+        Self {
+            sql_queries: QueryRegistry::new(Default::default(), None, Arc::clone(&metrics)),
+            metrics,
+            read_only_fallbacks: Default::default(),
+        }
+    }
 }
 
 impl MaterializePlugin {
+    /// Create a plugin whose query registry is persisted to the directory
+    /// configured in the environment, if any, so streams survive restarts.
+    pub fn new() -> Self {
+        let metrics = Arc::new(metrics::Metrics::default());
+        Self {
+            sql_queries: QueryRegistry::from_env(Arc::clone(&metrics)),
+            metrics,
+            read_only_fallbacks: Default::default(),
+        }
+    }
+
     /// Get a database client using the given datasource settings.
     ///
-    /// The `tokio_postgres::Connection` is spawned into a new task;
-    /// that task will be dropped automatically when the returned `Client` is dropped.
+    /// Any notices sent by the server are discarded; use [`Self::connect`] to
+    /// receive them.
     async fn get_client(
         &self,
         datasource_settings: &backend::DataSourceInstanceSettings,
     ) -> Result<Client> {
+        Ok(self.connect(datasource_settings).await?.0)
+    }
+
+    /// Get a database client using the given datasource settings, along with
+    /// the notices sent by the server on its connection.
+    ///
+    /// The `tokio_postgres::Connection` is spawned into a new task;
+    /// that task will be dropped automatically when the returned `Client` is dropped.
+    ///
+    /// The session is made read-only before the client is returned, so any
+    /// statement which slips past validation and attempts to modify data
+    /// will be rejected by the server with [`Error::ReadOnlyViolation`].
+    /// Materialize doesn't support the setting, but statements written by
+    /// users are also run in read-only transactions, which it does support.
+    async fn connect(
+        &self,
+        datasource_settings: &backend::DataSourceInstanceSettings,
+    ) -> Result<(Client, Notices)> {
         let settings: MaterializeDatasourceSettings =
             serde_json::from_value(datasource_settings.json_data.clone())
                 .map_err(Error::InvalidDatasourceSettings)?;
-        let (client, connection) = Config::new()
+        let mut config = Config::new();
+        config
             .user(&settings.username)
             .host(&settings.host)
-            .port(settings.port)
-            .connect(NoTls)
-            .await?;
-        tokio::spawn(async move {
-            if let Err(e) = connection.await {
-                eprintln!("connection error: {e}");
+            .port(settings.port);
+        if let Some(database) = &settings.database {
+            config.dbname(database);
+        }
+        debug!(host = %settings.host, port = settings.port, "connecting");
+        let started = Instant::now();
+        let connected = config.connect(NoTls).await;
+        self.metrics.record_connection(&connected);
+        let (client, connection) = connected.map_err(|e| {
+            let e = Error::from(e);
+            warn!(error = %e, "failed to connect");
+            e
+        })?;
+        debug!(
+            elapsed_ms = started.elapsed().as_millis() as u64,
+            "connected"
+        );
+        let notices = Notices::spawn(connection);
+        if let Err(e) = client
+            .batch_execute("SET default_transaction_read_only = on")
+            .await
+        {
+            if !e.code().is_some_and(error::is_unsupported_setting) {
+                return Err(e.into());
+            }
+            let first = self
+                .read_only_fallbacks
+                .lock()
+                .is_ok_and(|mut seen| seen.insert(datasource_settings.uid.clone()));
+            if first {
+                info!(
+                    error = %e,
+                    "server doesn't support read-only sessions; using read-only transactions"
+                );
             }
-        });
-        Ok(client)
+        }
+        if let Some(cluster) = &settings.cluster {
+            client
+                .batch_execute(&format!("SET cluster = {}", sql::quote_ident(cluster)))
+                .await?;
+        }
+        Ok((client, notices))
     }
 }
 
@@ -62,4 +162,10 @@ struct MaterializeDatasourceSettings {
     host: String,
     port: u16,
     username: String,
+    /// The database to connect to, if not the server's default.
+    #[serde(default)]
+    database: Option<String>,
+    /// The cluster to run queries on, if not the server's default.
+    #[serde(default)]
+    cluster: Option<String>,
 }
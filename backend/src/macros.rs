@@ -0,0 +1,188 @@
+//! Grafana-style macros which can be used inside SQL statements.
+//!
+//! Macros are expanded before a statement is sent to Materialize. The supported
+//! macros are:
+//!
+//! - `$__timeFrom`: the start of the query's time range, as a `timestamptz` literal.
+//! - `$__timeTo`: the end of the query's time range, as a `timestamptz` literal.
+//! - `$__timeFilter(expr)`: a condition checking that `expr` falls within the time range.
+//! - `$__interval_ms`: the suggested interval between datapoints, in milliseconds.
+
+use std::time::Duration;
+
+use chrono::prelude::*;
+use grafana_plugin_sdk::backend;
+
+use crate::{sql, Error, Result};
+
+const PREFIX: &str = "$__";
+
+/// The values substituted into macros.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MacroContext {
+    pub from: DateTime<Utc>,
+    pub to: DateTime<Utc>,
+    pub interval: Duration,
+}
+
+impl MacroContext {
+    pub fn new(from: DateTime<Utc>, to: DateTime<Utc>, interval: Duration) -> Self {
+        Self { from, to, interval }
+    }
+
+    /// Create a `MacroContext` from the time range and interval of a data query.
+    pub fn from_query<Q: serde::de::DeserializeOwned>(query: &backend::DataQuery<Q>) -> Self {
+        Self::new(query.time_range.from, query.time_range.to, query.interval)
+    }
+
+    /// A context whose values don't matter, used when checking that an expanded
+    /// statement is valid before the real values are known.
+    pub(crate) fn placeholder() -> Self {
+        Self::new(
+            Utc.timestamp_opt(0, 0).unwrap(),
+            Utc.timestamp_opt(0, 0).unwrap(),
+            Duration::ZERO,
+        )
+    }
+}
+
+fn timestamp_literal(ts: &DateTime<Utc>) -> String {
+    format!(
+        "'{}'::timestamptz",
+        ts.to_rfc3339_opts(SecondsFormat::Millis, true)
+    )
+}
+
+/// Expand all macros in `sql` using the values in `ctx`.
+///
+/// Macros inside string literals, quoted identifiers and comments are left untouched.
+///
+/// # Errors
+///
+/// Returns [`Error::InvalidMacro`] if `sql` contains an unknown macro, or a
+/// `$__timeFilter` without a parenthesised argument.
+pub fn expand(sql: &str, ctx: &MacroContext) -> Result<String> {
+    let mut out = String::with_capacity(sql.len());
+    let mut rest = sql;
+    while let Some(idx) = sql::next_special(rest) {
+        out.push_str(&rest[..idx]);
+        rest = &rest[idx..];
+        if let Some(len) = sql::quoted_len(rest) {
+            out.push_str(&rest[..len]);
+            rest = &rest[len..];
+        } else if let Some(after) = rest.strip_prefix(PREFIX) {
+            let name_len = after
+                .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                .unwrap_or(after.len());
+            let (name, after) = after.split_at(name_len);
+            rest = after;
+            match name {
+                "timeFrom" => out.push_str(&timestamp_literal(&ctx.from)),
+                "timeTo" => out.push_str(&timestamp_literal(&ctx.to)),
+                "interval_ms" => out.push_str(&ctx.interval.as_millis().to_string()),
+                "timeFilter" => {
+                    let (arg, after) = parenthesised_arg(rest).ok_or_else(|| {
+                        Error::InvalidMacro(
+                            "$__timeFilter requires an argument, e.g. $__timeFilter(ts)"
+                                .to_string(),
+                        )
+                    })?;
+                    rest = after;
+                    out.push_str(&format!(
+                        "({arg} BETWEEN {} AND {})",
+                        timestamp_literal(&ctx.from),
+                        timestamp_literal(&ctx.to)
+                    ));
+                }
+                other => {
+                    return Err(Error::InvalidMacro(format!(
+                        "unknown macro {PREFIX}{other}"
+                    )))
+                }
+            }
+        } else {
+            out.push('$');
+            rest = &rest[1..];
+        }
+    }
+    out.push_str(rest);
+    Ok(out)
+}
+
+/// Split a leading parenthesised argument from `s`, returning the argument
+/// (without parentheses) and the remainder of the string.
+fn parenthesised_arg(s: &str) -> Option<(&str, &str)> {
+    let inner = s.strip_prefix('(')?;
+    let mut depth = 1;
+    for (i, c) in inner.char_indices() {
+        match c {
+            '(' => depth += 1,
+            ')' => {
+                depth -= 1;
+                if depth == 0 {
+                    return Some((inner[..i].trim(), &inner[i + 1..]));
+                }
+            }
+            _ => {}
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ctx() -> MacroContext {
+        MacroContext::new(
+            Utc.timestamp_opt(1_650_000_000, 0).unwrap(),
+            Utc.timestamp_opt(1_650_003_600, 0).unwrap(),
+            Duration::from_secs(15),
+        )
+    }
+
+    #[test]
+    fn expand_macros() {
+        assert_eq!(
+            expand(
+                "SELECT * FROM t WHERE $__timeFilter(date_trunc('minute', ts)) AND $__interval_ms > 0",
+                &ctx()
+            )
+            .unwrap(),
+            "SELECT * FROM t WHERE (date_trunc('minute', ts) BETWEEN '2022-04-15T05:20:00.000Z'::timestamptz AND '2022-04-15T06:20:00.000Z'::timestamptz) AND 15000 > 0"
+        );
+        assert_eq!(
+            expand("SELECT $__timeFrom, $__timeTo", &ctx()).unwrap(),
+            "SELECT '2022-04-15T05:20:00.000Z'::timestamptz, '2022-04-15T06:20:00.000Z'::timestamptz"
+        );
+    }
+
+    #[test]
+    fn leaves_quoted_text_and_other_dollars() {
+        for sql in [
+            "SELECT '$__timeFrom' AS \"$__timeTo\"",
+            "SELECT 'it''s $__timeFrom'",
+            "SELECT $1, $$dollar $__timeFrom quoted$$",
+            "SELECT 1 -- don't expand $__timeFrom\n",
+            r"SELECT E'it\'s $__timeFrom'",
+        ] {
+            assert_eq!(expand(sql, &ctx()).unwrap(), sql);
+        }
+    }
+
+    #[test]
+    fn invalid_macros() {
+        assert!(matches!(
+            expand("SELECT $__unknown", &ctx()),
+            Err(Error::InvalidMacro(_))
+        ));
+        assert!(matches!(
+            expand("SELECT * FROM t WHERE $__timeFilter", &ctx()),
+            Err(Error::InvalidMacro(_))
+        ));
+        assert!(matches!(
+            expand("SELECT * FROM t WHERE $__timeFilter(ts", &ctx()),
+            Err(Error::InvalidMacro(_))
+        ));
+    }
+}
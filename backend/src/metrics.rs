@@ -0,0 +1,289 @@
+//! Prometheus metrics describing the plugin's activity.
+//!
+//! These are served to Grafana by [`DiagnosticsService::collect_metrics`] in the
+//! Prometheus text exposition format.
+//!
+//! [`DiagnosticsService::collect_metrics`]: grafana_plugin_sdk::backend::DiagnosticsService::collect_metrics
+
+use std::fmt;
+
+use prometheus::{
+    Encoder, Histogram, HistogramOpts, IntCounter, IntCounterVec, IntGauge, IntGaugeVec, Opts,
+    Registry, TextEncoder,
+};
+
+use crate::Result;
+
+const NAMESPACE: &str = "materialize_datasource";
+
+/// Whether rows were emitted as part of an initial snapshot or a live stream.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EmitKind {
+    Snapshot,
+    Stream,
+}
+
+impl EmitKind {
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::Snapshot => "snapshot",
+            Self::Stream => "stream",
+        }
+    }
+}
+
+/// Why a statement was evicted from the query registry.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EvictionReason {
+    /// It hadn't been used for longer than the registry's TTL.
+    Expired,
+    /// The registry was full and it was the least recently used.
+    Capacity,
+}
+
+impl EvictionReason {
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::Expired => "expired",
+            Self::Capacity => "capacity",
+        }
+    }
+}
+
+/// The metrics collected by the plugin.
+pub struct Metrics {
+    registry: Registry,
+    active_streams: IntGaugeVec,
+    rows_emitted: IntCounterVec,
+    frames_emitted: IntCounterVec,
+    snapshot_duration: Histogram,
+    connections_opened: IntCounter,
+    connection_failures: IntCounter,
+    stream_reconnects: IntCounter,
+    sql_queries: IntGauge,
+    sql_query_evictions: IntCounterVec,
+}
+
+impl Metrics {
+    /// Create a new set of metrics, registered with a fresh registry.
+    pub fn new() -> Self {
+        let opts = |name: &str, help: &str| Opts::new(name, help).namespace(NAMESPACE);
+        let metrics = Self {
+            registry: Registry::new(),
+            active_streams: IntGaugeVec::new(
+                opts("active_streams", "Number of streams currently running."),
+                &["datasource"],
+            )
+            .expect("valid metric"),
+            rows_emitted: IntCounterVec::new(
+                opts("rows_emitted_total", "Number of rows sent to Grafana."),
+                &["kind"],
+            )
+            .expect("valid metric"),
+            frames_emitted: IntCounterVec::new(
+                opts("frames_emitted_total", "Number of frames sent to Grafana."),
+                &["kind"],
+            )
+            .expect("valid metric"),
+            snapshot_duration: Histogram::with_opts(
+                HistogramOpts::new(
+                    "snapshot_query_duration_seconds",
+                    "Time taken to select the initial snapshot of a query.",
+                )
+                .namespace(NAMESPACE),
+            )
+            .expect("valid metric"),
+            connections_opened: IntCounter::with_opts(opts(
+                "connections_opened_total",
+                "Number of connections successfully opened to Materialize.",
+            ))
+            .expect("valid metric"),
+            connection_failures: IntCounter::with_opts(opts(
+                "connection_failures_total",
+                "Number of failed attempts to connect to Materialize.",
+            ))
+            .expect("valid metric"),
+            stream_reconnects: IntCounter::with_opts(opts(
+                "stream_reconnects_total",
+                "Number of times a stream failed, after which Grafana runs it again.",
+            ))
+            .expect("valid metric"),
+            sql_queries: IntGauge::with_opts(opts(
+                "sql_queries",
+                "Number of statements held in the query registry.",
+            ))
+            .expect("valid metric"),
+            sql_query_evictions: IntCounterVec::new(
+                opts(
+                    "sql_query_evictions_total",
+                    "Number of statements evicted from the query registry.",
+                ),
+                &["reason"],
+            )
+            .expect("valid metric"),
+        };
+        for collector in [
+            Box::new(metrics.active_streams.clone()) as Box<dyn prometheus::core::Collector>,
+            Box::new(metrics.rows_emitted.clone()),
+            Box::new(metrics.frames_emitted.clone()),
+            Box::new(metrics.snapshot_duration.clone()),
+            Box::new(metrics.connections_opened.clone()),
+            Box::new(metrics.connection_failures.clone()),
+            Box::new(metrics.stream_reconnects.clone()),
+            Box::new(metrics.sql_queries.clone()),
+            Box::new(metrics.sql_query_evictions.clone()),
+        ] {
+            metrics
+                .registry
+                .register(collector)
+                .expect("metric names are unique");
+        }
+        metrics
+    }
+
+    /// Record the result of an attempt to connect to Materialize.
+    pub fn record_connection<T, E>(&self, result: &std::result::Result<T, E>) {
+        match result {
+            Ok(_) => self.connections_opened.inc(),
+            Err(_) => self.connection_failures.inc(),
+        }
+    }
+
+    /// Record that a snapshot query took `seconds` to run.
+    pub fn record_snapshot_duration(&self, seconds: f64) {
+        self.snapshot_duration.observe(seconds);
+    }
+
+    /// Record that a frame containing `rows` rows was sent to Grafana.
+    pub fn record_frame(&self, kind: EmitKind, rows: usize) {
+        self.frames_emitted
+            .with_label_values(&[kind.as_str()])
+            .inc();
+        self.rows_emitted
+            .with_label_values(&[kind.as_str()])
+            .inc_by(rows as u64);
+    }
+
+    /// Record that a statement was evicted from the query registry.
+    pub fn record_eviction(&self, reason: EvictionReason) {
+        self.sql_query_evictions
+            .with_label_values(&[reason.as_str()])
+            .inc();
+    }
+
+    /// Record that a stream has started for `datasource`.
+    ///
+    /// The stream is counted as active until the returned guard is dropped.
+    pub fn stream_started(&self, datasource: &str) -> ActiveStream {
+        let gauge = self.active_streams.with_label_values(&[datasource]);
+        gauge.inc();
+        ActiveStream(gauge)
+    }
+
+    /// Record that a stream run by `run_stream` failed to connect or to tail its
+    /// target, or failed part way through.
+    ///
+    /// Grafana runs the stream again while anyone is subscribed, so each
+    /// failure is followed by a reconnect.
+    pub fn record_stream_failure(&self) {
+        self.stream_reconnects.inc();
+    }
+
+    /// Encode all metrics in the Prometheus text format.
+    ///
+    /// `sql_queries` is the current size of the query registry.
+    pub fn encode(&self, sql_queries: usize) -> Result<Vec<u8>> {
+        self.sql_queries.set(sql_queries as i64);
+        let mut buf = Vec::new();
+        TextEncoder::new().encode(&self.registry.gather(), &mut buf)?;
+        Ok(buf)
+    }
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl fmt::Debug for Metrics {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Metrics").finish_non_exhaustive()
+    }
+}
+
+/// A guard marking a stream as active, returned by [`Metrics::stream_started`].
+#[derive(Debug)]
+pub struct ActiveStream(IntGauge);
+
+impl Drop for ActiveStream {
+    fn drop(&mut self) {
+        self.0.dec();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+
+    /// Parse the samples in Prometheus text format output, keyed by metric
+    /// name including any labels.
+    fn parse(text: &str) -> HashMap<String, f64> {
+        text.lines()
+            .filter(|line| !line.starts_with('#') && !line.is_empty())
+            .map(|line| {
+                let (name, value) = line.rsplit_once(' ').expect("sample has a value");
+                (name.to_string(), value.parse().expect("value is a number"))
+            })
+            .collect()
+    }
+
+    #[test]
+    fn encode() {
+        let metrics = Metrics::new();
+        metrics.record_connection::<(), ()>(&Ok(()));
+        metrics.record_connection::<(), ()>(&Err(()));
+        metrics.record_connection::<(), ()>(&Err(()));
+        metrics.record_snapshot_duration(0.2);
+        metrics.record_frame(EmitKind::Snapshot, 10);
+        metrics.record_frame(EmitKind::Stream, 1);
+        metrics.record_frame(EmitKind::Stream, 1);
+
+        let first = metrics.stream_started("abc");
+        let second = metrics.stream_started("abc");
+        drop(first);
+        metrics.record_stream_failure();
+        let _reconnected = metrics.stream_started("abc");
+        drop(second);
+        metrics.record_eviction(EvictionReason::Capacity);
+
+        let text = String::from_utf8(metrics.encode(3).unwrap()).unwrap();
+        let samples = parse(&text);
+        let get = |name: &str| {
+            *samples
+                .get(&format!("materialize_datasource_{name}"))
+                .unwrap_or_else(|| panic!("missing {name} in:\n{text}"))
+        };
+        assert_eq!(get("connections_opened_total"), 1.0);
+        assert_eq!(get("connection_failures_total"), 2.0);
+        assert_eq!(get(r#"active_streams{datasource="abc"}"#), 1.0);
+        assert_eq!(get("stream_reconnects_total"), 1.0);
+        assert_eq!(get(r#"rows_emitted_total{kind="snapshot"}"#), 10.0);
+        assert_eq!(get(r#"rows_emitted_total{kind="stream"}"#), 2.0);
+        assert_eq!(get(r#"frames_emitted_total{kind="stream"}"#), 2.0);
+        assert_eq!(get("snapshot_query_duration_seconds_count"), 1.0);
+        assert_eq!(
+            get(r#"snapshot_query_duration_seconds_bucket{le="0.25"}"#),
+            1.0
+        );
+        assert_eq!(
+            get(r#"snapshot_query_duration_seconds_bucket{le="0.1"}"#),
+            0.0
+        );
+        assert_eq!(get("sql_queries"), 3.0);
+        assert_eq!(get(r#"sql_query_evictions_total{reason="capacity"}"#), 1.0);
+        assert!(text.contains("# TYPE materialize_datasource_active_streams gauge"));
+    }
+}
@@ -0,0 +1,122 @@
+//! Notices sent asynchronously by the server, such as warnings about deprecated
+//! syntax or indexes which can't be used.
+//!
+//! These are forwarded from each connection so they can be shown alongside the
+//! data of the query which caused them.
+
+use futures_util::{stream, StreamExt};
+use grafana_plugin_sdk::data;
+use tokio::sync::mpsc;
+use tokio_postgres::{tls::NoTlsStream, AsyncMessage, Connection, Socket};
+use tracing::{debug, warn, Instrument};
+
+use crate::error::ServerMessage;
+
+/// The notices received on a connection.
+#[derive(Debug)]
+pub struct Notices(mpsc::UnboundedReceiver<data::Notice>);
+
+impl Notices {
+    /// Create a channel whose sender is used to forward notices.
+    fn channel() -> (mpsc::UnboundedSender<data::Notice>, Self) {
+        let (tx, rx) = mpsc::unbounded_channel();
+        (tx, Self(rx))
+    }
+
+    /// Drive `connection` in a new task, forwarding any notices it receives.
+    ///
+    /// The task ends when the connection is closed, which happens when its
+    /// `Client` is dropped.
+    pub fn spawn(mut connection: Connection<Socket, NoTlsStream>) -> Self {
+        let (tx, notices) = Self::channel();
+        tokio::spawn(
+            async move {
+                let mut messages = stream::poll_fn(move |cx| connection.poll_message(cx));
+                while let Some(message) = messages.next().await {
+                    match message {
+                        Ok(AsyncMessage::Notice(notice)) => {
+                            let mut forwarded =
+                                data::Notice::new(ServerMessage::from(&notice).to_string());
+                            // The localized severity depends on the server's
+                            // `lc_messages`, so prefer the nonlocalized one.
+                            let nonlocalized = notice.parsed_severity().map(|s| s.to_string());
+                            forwarded.severity = Some(severity(
+                                nonlocalized.as_deref().unwrap_or(notice.severity()),
+                            ));
+                            // The receiver is dropped by callers which aren't interested.
+                            let _ = tx.send(forwarded);
+                        }
+                        Ok(_) => {}
+                        Err(e) => {
+                            warn!(error = %e, "connection error");
+                            break;
+                        }
+                    }
+                }
+                debug!("connection closed");
+            }
+            .in_current_span(),
+        );
+        notices
+    }
+
+    /// Take all of the notices received so far, without waiting for more.
+    pub fn drain(&mut self) -> Vec<data::Notice> {
+        std::iter::from_fn(|| self.0.try_recv().ok()).collect()
+    }
+}
+
+/// The frame notice severity matching the severity of a server notice.
+fn severity(severity: &str) -> data::Severity {
+    match severity {
+        "WARNING" => data::Severity::Warning,
+        "ERROR" | "FATAL" | "PANIC" => data::Severity::Error,
+        _ => data::Severity::Info,
+    }
+}
+
+/// Add `notices` to the metadata of `frame`, keeping any it already has.
+pub fn attach(frame: &mut data::Frame, notices: Vec<data::Notice>) {
+    if notices.is_empty() {
+        return;
+    }
+    frame
+        .meta
+        .get_or_insert_with(Default::default)
+        .notices
+        .get_or_insert_with(Vec::new)
+        .extend(notices);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn severities() {
+        assert_eq!(severity("WARNING"), data::Severity::Warning);
+        assert_eq!(severity("NOTICE"), data::Severity::Info);
+        assert_eq!(severity("INFO"), data::Severity::Info);
+        assert_eq!(severity("ERROR"), data::Severity::Error);
+    }
+
+    #[test]
+    fn drain_and_attach() {
+        let (tx, mut notices) = Notices::channel();
+        assert!(notices.drain().is_empty());
+
+        let mut warning = data::Notice::new("index not used".to_string());
+        warning.severity = Some(data::Severity::Warning);
+        tx.send(warning.clone()).unwrap();
+        tx.send(data::Notice::new("deprecated syntax".to_string()))
+            .unwrap();
+
+        let mut frame = data::Frame::new("orders");
+        attach(&mut frame, notices.drain());
+        attach(&mut frame, notices.drain());
+        let attached = frame.meta.unwrap().notices.unwrap();
+        assert_eq!(attached.len(), 2);
+        assert_eq!(attached[0], warning);
+        assert_eq!(attached[1].text, "deprecated syntax");
+    }
+}
@@ -0,0 +1,332 @@
+//! Output formats controlling how rows are shaped into frames.
+//!
+//! By default rows are converted to a generic frame by [`rows_to_frame`].
+//! Other formats pick out specific columns so that Grafana can treat the
+//! frame as something more specialised, such as a set of annotations or log lines.
+
+use chrono::Utc;
+use grafana_plugin_sdk::{data, prelude::*};
+use serde::{Deserialize, Serialize};
+use tokio_postgres::Row;
+
+use crate::{
+    convert::{self, rows_to_frame},
+    introspection, Error, Result,
+};
+
+/// How the rows returned by a query should be converted to a frame.
+#[derive(Clone, Debug, Default, Hash, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum OutputFormat {
+    /// A generic frame with one field per column.
+    #[default]
+    Table,
+    /// Dashboard annotations, built from the chosen columns.
+    Annotations(AnnotationColumns),
+    /// Log lines, built from the chosen columns.
+    Logs(LogColumns),
+}
+
+impl OutputFormat {
+    /// Convert some rows to a frame in this format.
+    ///
+    /// Table frames with a `write_frontier` field also get the lag of each
+    /// frontier, as described by [`introspection::add_lag`].
+    pub fn rows_to_frame(&self, rows: &[Row]) -> Result<data::Frame> {
+        match self {
+            Self::Table => {
+                let mut frame = rows_to_frame(rows)?;
+                introspection::add_lag(&mut frame, Utc::now());
+                Ok(frame)
+            }
+            Self::Annotations(columns) => columns.rows_to_frame(rows),
+            Self::Logs(columns) => columns.rows_to_frame(rows),
+        }
+    }
+}
+
+/// Find the position of the column named `column` among `names`.
+fn position(names: &[&str], column: &str) -> Result<usize> {
+    names
+        .iter()
+        .position(|name| *name == column)
+        .ok_or_else(|| Error::InvalidFormat(format!("column {column} not found")))
+}
+
+/// Filter out rows retracted by a `TAIL`, i.e. those with a negative `mz_diff`.
+fn inserted_rows<'a>(rows: &'a [Row], names: &[&str]) -> Vec<&'a Row> {
+    let diff = names.iter().position(|name| *name == convert::MZ_DIFF);
+    rows.iter()
+        .filter(|row| {
+            diff.and_then(|i| convert::value_to_f64(row, i))
+                .is_none_or(|d| d >= 0.0)
+        })
+        .collect()
+}
+
+/// The columns used to build annotations.
+///
+/// Each column is given by name. Only `time` is required; annotations
+/// without a `time_end` are shown as a single point in time.
+#[derive(Clone, Debug, Hash, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AnnotationColumns {
+    pub time: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub time_end: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub title: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub text: Option<String>,
+    /// Columns whose values are added to each annotation as tags.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<String>,
+}
+
+/// The positions of the [`AnnotationColumns`] in a row.
+#[derive(Debug, PartialEq, Eq)]
+struct AnnotationIndices {
+    time: usize,
+    time_end: Option<usize>,
+    title: Option<usize>,
+    text: Option<usize>,
+    tags: Vec<usize>,
+}
+
+impl AnnotationColumns {
+    /// Find the position of each chosen column in a row with the given column names.
+    fn resolve(&self, names: &[&str]) -> Result<AnnotationIndices> {
+        let position = |column: &str| position(names, column);
+        let optional = |column: &Option<String>| column.as_deref().map(position).transpose();
+        Ok(AnnotationIndices {
+            time: position(&self.time)?,
+            time_end: optional(&self.time_end)?,
+            title: optional(&self.title)?,
+            text: optional(&self.text)?,
+            tags: self
+                .tags
+                .iter()
+                .map(|column| position(column))
+                .collect::<Result<_>>()?,
+        })
+    }
+
+    /// Convert rows to a frame with the `time`, `timeEnd`, `title`, `text` and
+    /// `tags` fields used by Grafana's annotation support.
+    ///
+    /// Tags are joined with commas, which Grafana splits into separate tags.
+    /// Rows retracted by a `TAIL` (those with a negative `mz_diff`) are skipped.
+    fn rows_to_frame(&self, rows: &[Row]) -> Result<data::Frame> {
+        let mut frame = data::Frame::new("annotations");
+        let Some(first) = rows.first() else {
+            return Ok(frame);
+        };
+        let names: Vec<_> = first.columns().iter().map(|c| c.name()).collect();
+        let indices = self.resolve(&names)?;
+        let rows = inserted_rows(rows, &names);
+
+        let times = |index: usize| {
+            rows.iter()
+                .map(move |row| convert::value_to_time(row, index))
+        };
+        let strings = |index: usize| {
+            rows.iter()
+                .map(move |row| convert::value_to_string(row, index))
+        };
+        frame.add_field(times(indices.time).into_opt_field("time"));
+        if let Some(index) = indices.time_end {
+            frame.add_field(times(index).into_opt_field("timeEnd"));
+        }
+        if let Some(index) = indices.title {
+            frame.add_field(strings(index).into_opt_field("title"));
+        }
+        if let Some(index) = indices.text {
+            frame.add_field(strings(index).into_opt_field("text"));
+        }
+        if !indices.tags.is_empty() {
+            frame.add_field(
+                rows.iter()
+                    .map(|row| {
+                        indices
+                            .tags
+                            .iter()
+                            .filter_map(|i| convert::value_to_string(row, *i))
+                            .collect::<Vec<_>>()
+                            .join(",")
+                    })
+                    .into_field("tags"),
+            );
+        }
+        Ok(frame)
+    }
+}
+
+/// The columns used to build log lines.
+///
+/// Each column is given by name. Any `labels` columns are included as extra
+/// fields, which Grafana shows alongside each line.
+#[derive(Clone, Debug, Hash, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct LogColumns {
+    pub time: String,
+    pub body: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub level: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub labels: Vec<String>,
+}
+
+/// The positions of the [`LogColumns`] in a row.
+#[derive(Debug, PartialEq, Eq)]
+struct LogIndices {
+    time: usize,
+    body: usize,
+    level: Option<usize>,
+    labels: Vec<(usize, String)>,
+}
+
+impl LogColumns {
+    /// Find the position of each chosen column in a row with the given column names.
+    fn resolve(&self, names: &[&str]) -> Result<LogIndices> {
+        Ok(LogIndices {
+            time: position(names, &self.time)?,
+            body: position(names, &self.body)?,
+            level: self
+                .level
+                .as_deref()
+                .map(|column| position(names, column))
+                .transpose()?,
+            labels: self
+                .labels
+                .iter()
+                .map(|column| Ok((position(names, column)?, column.clone())))
+                .collect::<Result<_>>()?,
+        })
+    }
+
+    /// Convert rows to a frame with `time`, `body` and `level` fields, followed by
+    /// one field per label column, marked to be shown in the Logs panel.
+    ///
+    /// Rows are sorted by time so that the newest lines come last, matching the
+    /// order in which lines streamed by a `TAIL` are appended. Retracted rows are
+    /// skipped.
+    fn rows_to_frame(&self, rows: &[Row]) -> Result<data::Frame> {
+        let mut metadata = data::Metadata::default();
+        metadata.preferred_visualisation = Some(data::VisType::Logs);
+        let mut frame = data::Frame::new("logs").with_metadata(metadata);
+        let Some(first) = rows.first() else {
+            return Ok(frame);
+        };
+        let names: Vec<_> = first.columns().iter().map(|c| c.name()).collect();
+        let indices = self.resolve(&names)?;
+        let mut rows = inserted_rows(rows, &names);
+        rows.sort_by_cached_key(|row| convert::value_to_time(row, indices.time));
+
+        let strings = |index: usize| {
+            rows.iter()
+                .map(move |row| convert::value_to_string(row, index))
+        };
+        frame.add_field(
+            rows.iter()
+                .map(|row| convert::value_to_time(row, indices.time))
+                .into_opt_field("time"),
+        );
+        frame.add_field(strings(indices.body).into_opt_field("body"));
+        if let Some(index) = indices.level {
+            frame.add_field(strings(index).into_opt_field("level"));
+        }
+        for (index, name) in &indices.labels {
+            frame.add_field(strings(*index).into_opt_field(name.as_str()));
+        }
+        Ok(frame)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn annotations() -> AnnotationColumns {
+        AnnotationColumns {
+            time: "deployed_at".to_string(),
+            time_end: None,
+            title: Some("service".to_string()),
+            text: None,
+            tags: vec!["env".to_string(), "region".to_string()],
+        }
+    }
+
+    #[test]
+    fn deserialize() {
+        assert_eq!(
+            serde_json::from_str::<OutputFormat>(r#"{"type": "table"}"#).unwrap(),
+            OutputFormat::Table
+        );
+        assert_eq!(
+            serde_json::from_str::<OutputFormat>(
+                r#"{"type": "annotations", "time": "deployed_at", "title": "service", "tags": ["env", "region"]}"#
+            )
+            .unwrap(),
+            OutputFormat::Annotations(annotations())
+        );
+        assert!(serde_json::from_str::<OutputFormat>(r#"{"type": "annotations"}"#).is_err());
+        assert_eq!(
+            serde_json::from_str::<OutputFormat>(
+                r#"{"type": "logs", "time": "ts", "body": "message", "labels": ["service"]}"#
+            )
+            .unwrap(),
+            OutputFormat::Logs(LogColumns {
+                time: "ts".to_string(),
+                body: "message".to_string(),
+                level: None,
+                labels: vec!["service".to_string()],
+            })
+        );
+        assert!(serde_json::from_str::<OutputFormat>(r#"{"type": "logs", "time": "ts"}"#).is_err());
+    }
+
+    #[test]
+    fn resolve_log_columns() {
+        let columns = LogColumns {
+            time: "ts".to_string(),
+            body: "message".to_string(),
+            level: Some("severity".to_string()),
+            labels: vec!["service".to_string()],
+        };
+        assert_eq!(
+            columns
+                .resolve(&["service", "message", "mz_diff", "ts", "severity"])
+                .unwrap(),
+            LogIndices {
+                time: 3,
+                body: 1,
+                level: Some(4),
+                labels: vec![(0, "service".to_string())],
+            }
+        );
+        assert!(matches!(
+            columns.resolve(&["ts", "message"]),
+            Err(Error::InvalidFormat(_))
+        ));
+    }
+
+    #[test]
+    fn resolve_annotation_columns() {
+        assert_eq!(
+            annotations()
+                .resolve(&["mz_timestamp", "service", "deployed_at", "region", "env"])
+                .unwrap(),
+            AnnotationIndices {
+                time: 2,
+                time_end: None,
+                title: Some(1),
+                text: None,
+                tags: vec![4, 3],
+            }
+        );
+        assert!(matches!(
+            annotations().resolve(&["deployed_at", "service"]),
+            Err(Error::InvalidFormat(_))
+        ));
+    }
+}
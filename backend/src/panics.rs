@@ -0,0 +1,87 @@
+//! Catching panics at the boundary of each service method.
+//!
+//! A panic while handling one request would otherwise bring down the whole
+//! plugin process, breaking every dashboard using it. Instead, panics are
+//! converted into an [`Error::Panic`] for the request which caused them.
+
+use std::{any::Any, future::Future, panic::AssertUnwindSafe};
+
+use futures_util::{FutureExt, Stream, StreamExt};
+use tracing::error;
+
+use crate::Error;
+
+/// Extract the message from a panic's payload, if it has one.
+fn panic_message(payload: Box<dyn Any + Send>) -> String {
+    payload
+        .downcast_ref::<&str>()
+        .map(|s| s.to_string())
+        .or_else(|| payload.downcast_ref::<String>().cloned())
+        .unwrap_or_else(|| "unknown panic".to_string())
+}
+
+/// Log a panic and convert it to an error.
+fn panic_error(payload: Box<dyn Any + Send>) -> Error {
+    let message = panic_message(payload);
+    error!(panic = %message, "panic while handling request");
+    Error::Panic(message)
+}
+
+/// Run `fut`, returning an [`Error::Panic`] if it panics.
+///
+/// Any state shared with other requests must not be left inconsistent by a
+/// panic; the plugin only shares the query registry and metrics, which are
+/// updated atomically.
+pub async fn catch_panics<T, E: From<Error>>(
+    fut: impl Future<Output = Result<T, E>>,
+) -> Result<T, E> {
+    AssertUnwindSafe(fut)
+        .catch_unwind()
+        .await
+        .unwrap_or_else(|payload| Err(panic_error(payload).into()))
+}
+
+/// Wrap `stream` so that a panic while polling it yields an [`Error::Panic`]
+/// and ends the stream.
+pub fn catch_stream_panics<T, E: From<Error>>(
+    stream: impl Stream<Item = Result<T, E>>,
+) -> impl Stream<Item = Result<T, E>> {
+    AssertUnwindSafe(stream)
+        .catch_unwind()
+        .map(|item| item.unwrap_or_else(|payload| Err(panic_error(payload).into())))
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_util::stream;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn future() {
+        let ok: Result<_, Error> = catch_panics(async { Ok(1) }).await;
+        assert_eq!(ok.unwrap(), 1);
+
+        let panicked: Result<(), Error> = catch_panics(async { panic!("boom") }).await;
+        assert!(matches!(panicked, Err(Error::Panic(m)) if m == "boom"));
+
+        let index = 3;
+        let panicked: Result<(), Error> =
+            catch_panics(async move { panic!("index {index} out of range") }).await;
+        assert!(matches!(panicked, Err(Error::Panic(m)) if m == "index 3 out of range"));
+    }
+
+    #[tokio::test]
+    async fn stream() {
+        let items = stream::iter([1, 2, 3]).map(|i| {
+            if i == 2 {
+                panic!("bad item");
+            }
+            Ok::<_, Error>(i)
+        });
+        let results: Vec<_> = catch_stream_panics(items).collect().await;
+        assert_eq!(results.len(), 2);
+        assert!(matches!(results[0], Ok(1)));
+        assert!(matches!(&results[1], Err(Error::Panic(m)) if m == "bad item"));
+    }
+}
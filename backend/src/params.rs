@@ -0,0 +1,276 @@
+//! Binding of dashboard template variables to statement parameters.
+//!
+//! Rather than interpolating variable values into SQL as text, statements refer
+//! to variables using named placeholders such as `$region`. Before a statement is
+//! run each placeholder is rewritten to a positional parameter (`$1`, `$2`, ...)
+//! and the variable's value is sent alongside the statement as a real parameter.
+//!
+//! Multi-value variables are sent as arrays, so should be used with `ANY`, e.g.
+//! `WHERE region = ANY($region)`.
+
+use std::{collections::BTreeMap, error::Error as StdError, fmt::Write};
+
+use bytes::{BufMut, BytesMut};
+use serde::{Deserialize, Serialize};
+use tokio_postgres::types::{to_sql_checked, Format, IsNull, ToSql, Type};
+
+use crate::{sql, Error, Result};
+
+/// The value of a template variable.
+#[derive(Clone, Debug, Hash, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum ParamValue {
+    /// A single value.
+    Single(String),
+    /// Multiple values, sent as an array.
+    Multi(Vec<String>),
+}
+
+impl ParamValue {
+    /// Encode this value in Postgres' text format.
+    ///
+    /// Arrays are encoded as `{"a","b"}`, with every element quoted and any
+    /// backslashes or double quotes escaped.
+    fn to_text(&self) -> String {
+        match self {
+            Self::Single(value) => value.clone(),
+            Self::Multi(values) => {
+                let mut out = String::from("{");
+                for (i, value) in values.iter().enumerate() {
+                    if i > 0 {
+                        out.push(',');
+                    }
+                    out.push('"');
+                    for c in value.chars() {
+                        if c == '"' || c == '\\' {
+                            out.push('\\');
+                        }
+                        out.push(c);
+                    }
+                    out.push('"');
+                }
+                out.push('}');
+                out
+            }
+        }
+    }
+}
+
+/// Values are sent in text format so that the server can parse them as whatever
+/// type it infers for the parameter, just as if they'd been written as literals.
+impl ToSql for ParamValue {
+    fn to_sql(
+        &self,
+        _ty: &Type,
+        out: &mut BytesMut,
+    ) -> std::result::Result<IsNull, Box<dyn StdError + Sync + Send>> {
+        out.put_slice(self.to_text().as_bytes());
+        Ok(IsNull::No)
+    }
+
+    fn accepts(_ty: &Type) -> bool {
+        true
+    }
+
+    fn encode_format(&self, _ty: &Type) -> Format {
+        Format::Text
+    }
+
+    to_sql_checked!();
+}
+
+/// The values of template variables, keyed by variable name.
+pub type Variables = BTreeMap<String, ParamValue>;
+
+/// Rewrite the named placeholders in `sql` to positional parameters, returning
+/// the rewritten statement and the value of each parameter in order.
+///
+/// A placeholder is a `$` followed by an identifier which doesn't begin with `__`
+/// (which is reserved for [macros](crate::macros)). Placeholders inside string
+/// literals, quoted identifiers and comments are left untouched.
+///
+/// # Errors
+///
+/// Returns [`Error::MissingVariable`] if a placeholder refers to a variable not
+/// present in `variables`, and [`Error::InvalidMacro`] if the statement contains
+/// a positional placeholder such as `$1`, which would collide with those
+/// generated here.
+pub fn bind(sql: &str, variables: &Variables) -> Result<(String, Vec<ParamValue>)> {
+    let mut out = String::with_capacity(sql.len());
+    let mut names: Vec<&str> = Vec::new();
+    let mut params = Vec::new();
+    let mut rest = sql;
+    while let Some(idx) = sql::next_special(rest) {
+        out.push_str(&rest[..idx]);
+        rest = &rest[idx..];
+        if let Some(len) = sql::quoted_len(rest) {
+            out.push_str(&rest[..len]);
+            rest = &rest[len..];
+            continue;
+        }
+        let after = &rest[1..];
+        let name_len = after
+            .find(|c: char| !(c.is_alphanumeric() || c == '_'))
+            .unwrap_or(after.len());
+        let name = &after[..name_len];
+        if name.starts_with(|c: char| c.is_ascii_digit()) {
+            return Err(Error::InvalidMacro(format!(
+                "positional parameter ${name} isn't supported; refer to template variables by name"
+            )));
+        }
+        let is_placeholder = name
+            .chars()
+            .next()
+            .is_some_and(|c| c.is_alphabetic() || c == '_')
+            && !name.starts_with("__");
+        if !is_placeholder {
+            out.push('$');
+            rest = after;
+            continue;
+        }
+        let position = match names.iter().position(|n| *n == name) {
+            Some(position) => position,
+            None => {
+                let value = variables
+                    .get(name)
+                    .ok_or_else(|| Error::MissingVariable(name.to_string()))?;
+                names.push(name);
+                params.push(value.clone());
+                names.len() - 1
+            }
+        };
+        write!(out, "${}", position + 1).expect("writing to a string must not fail");
+        rest = &after[name_len..];
+    }
+    out.push_str(rest);
+    Ok((out, params))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn variables() -> Variables {
+        Variables::from([
+            ("region".to_string(), ParamValue::Single("eu".to_string())),
+            (
+                "hosts".to_string(),
+                ParamValue::Multi(vec!["a".to_string(), "b".to_string()]),
+            ),
+        ])
+    }
+
+    #[test]
+    fn bind_placeholders() {
+        let (sql, params) = bind(
+            "SELECT * FROM t WHERE region = $region AND host = ANY($hosts) AND other = $region",
+            &variables(),
+        )
+        .unwrap();
+        assert_eq!(
+            sql,
+            "SELECT * FROM t WHERE region = $1 AND host = ANY($2) AND other = $1"
+        );
+        assert_eq!(
+            params,
+            vec![
+                ParamValue::Single("eu".to_string()),
+                ParamValue::Multi(vec!["a".to_string(), "b".to_string()]),
+            ]
+        );
+    }
+
+    #[test]
+    fn bind_leaves_other_text() {
+        for sql in [
+            "SELECT '$region', \"$region\" FROM t",
+            "SELECT 'it''s $region'",
+            "SELECT $$dollar $region$$, $tag$ $region $tag$",
+            "SELECT 1 -- don't bind $region\nWHERE x = 'y'",
+            "SELECT /* don't /* bind */ $region */ 1",
+            r"SELECT E'it\'s $region', e'\\' || '$region'",
+            "SELECT 1 WHERE $__timeFilter(ts)",
+            "SELECT '$' || 'x'",
+        ] {
+            let (bound, params) = bind(sql, &variables()).unwrap();
+            assert_eq!(bound, sql);
+            assert!(params.is_empty());
+        }
+    }
+
+    #[test]
+    fn bind_after_comments_and_escapes() {
+        for (sql, expected) in [
+            (
+                "SELECT 1 -- don't\nWHERE region = $region",
+                "SELECT 1 -- don't\nWHERE region = $1",
+            ),
+            ("SELECT /* it's */ $region", "SELECT /* it's */ $1"),
+            (
+                r"SELECT E'it\'s' WHERE region = $region",
+                r"SELECT E'it\'s' WHERE region = $1",
+            ),
+        ] {
+            let (bound, params) = bind(sql, &variables()).unwrap();
+            assert_eq!(bound, expected);
+            assert_eq!(params, vec![ParamValue::Single("eu".to_string())]);
+        }
+    }
+
+    #[test]
+    fn bind_positional() {
+        for sql in [
+            "SELECT $1",
+            "SELECT * FROM t WHERE region = $region AND x = $2",
+        ] {
+            assert!(matches!(
+                bind(sql, &variables()),
+                Err(Error::InvalidMacro(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn bind_missing_variable() {
+        assert!(matches!(
+            bind("SELECT $missing", &variables()),
+            Err(Error::MissingVariable(name)) if name == "missing"
+        ));
+    }
+
+    #[test]
+    fn text_encoding() {
+        assert_eq!(
+            ParamValue::Single(r#"it's "quoted" \ here"#.to_string()).to_text(),
+            r#"it's "quoted" \ here"#
+        );
+        assert_eq!(
+            ParamValue::Multi(vec![
+                "plain".to_string(),
+                "with,comma".to_string(),
+                r#"with "quotes""#.to_string(),
+                r"back\slash".to_string(),
+                "{braces}".to_string(),
+                "NULL".to_string(),
+                "".to_string(),
+            ])
+            .to_text(),
+            r#"{"plain","with,comma","with \"quotes\"","back\\slash","{braces}","NULL",""}"#
+        );
+        assert_eq!(ParamValue::Multi(vec![]).to_text(), "{}");
+    }
+
+    #[test]
+    fn deserialize_values() {
+        assert_eq!(
+            serde_json::from_str::<Variables>(r#"{"a": "x", "b": ["y", "z"]}"#).unwrap(),
+            Variables::from([
+                ("a".to_string(), ParamValue::Single("x".to_string())),
+                (
+                    "b".to_string(),
+                    ParamValue::Multi(vec!["y".to_string(), "z".to_string()])
+                ),
+            ])
+        );
+    }
+}
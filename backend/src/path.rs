@@ -1,9 +1,18 @@
 //! Describes how targets should be represented in 'paths'
 //! of Grafana Live channels.
 
-use std::fmt::{self, Write};
+use std::{
+    fmt::{self, Write},
+    io::Read,
+};
 
-use crate::queries::{Query, SelectStatement, SourceName, TailTarget};
+use flate2::{read::DeflateDecoder, write::DeflateEncoder, Compression};
+
+use crate::{
+    output::OutputFormat,
+    queries::{Query, SelectStatement, SourceName, StatementParts, TailTarget},
+    Error, Result,
+};
 
 /// Trait describing how a type should be serialized to a [`Channel`]'s path.
 ///
@@ -25,15 +34,107 @@ pub trait PathDisplay {
     }
 }
 
+/// Prefix marking a [`SourceName`] path segment as base64-encoded.
+///
+/// `=` can't appear in an unquoted identifier so this can't be confused
+/// with a plain name.
+const ENCODED_NAME_PREFIX: char = '=';
+
+/// Whether `part` can be written unquoted in both SQL and a channel path.
+fn is_plain_ident(part: &str) -> bool {
+    let mut chars = part.chars();
+    chars
+        .next()
+        .is_some_and(|c| c.is_ascii_lowercase() || c == '_')
+        && chars.all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_')
+}
+
+/// Names consisting only of plain identifiers are written as-is, e.g.
+/// `materialize.public.my_view`. Anything else is written as the quoted SQL
+/// form, base64-encoded with a URL-safe alphabet and prefixed with `=`.
 impl PathDisplay for SourceName {
     fn fmt_path(&self, f: &mut String) -> fmt::Result {
-        f.write_str(self.as_str())
+        if self.parts().all(is_plain_ident) {
+            let mut parts = self.parts();
+            if let Some(first) = parts.next() {
+                f.write_str(first)?;
+            }
+            for part in parts {
+                write!(f, ".{part}")?;
+            }
+        } else {
+            f.write_char(ENCODED_NAME_PREFIX)?;
+            f.write_str(&base64::encode_config(
+                self.to_string(),
+                base64::URL_SAFE_NO_PAD,
+            ))?;
+        }
+        Ok(())
     }
 }
 
+impl SourceName {
+    /// Parse a `SourceName` from its path representation, as written by
+    /// [`PathDisplay::fmt_path`].
+    pub fn from_path(s: &str) -> Result<Self> {
+        match s.strip_prefix(ENCODED_NAME_PREFIX) {
+            Some(encoded) => {
+                let decoded = base64::decode_config(encoded, base64::URL_SAFE_NO_PAD)
+                    .ok()
+                    .and_then(|bytes| String::from_utf8(bytes).ok())
+                    .ok_or_else(|| {
+                        Error::InvalidTailTarget(format!("Invalid encoded relation name {s}"))
+                    })?;
+                decoded.parse()
+            }
+            None => s.parse(),
+        }
+    }
+}
+
+/// Statements are written in full, as by [`SelectStatement::to_inline_path`],
+/// since a [`QueryId`] depends on the context the statement was registered in.
 impl PathDisplay for SelectStatement {
     fn fmt_path(&self, f: &mut String) -> fmt::Result {
-        f.write_str(QueryId::from_statement(self).as_str())
+        f.write_str(&self.to_inline_path())
+    }
+}
+
+/// The largest statement which will be decompressed from a path, in bytes.
+const MAX_INLINE_STATEMENT: u64 = 64 * 1024;
+
+impl SelectStatement {
+    /// Encode this statement and its parameters so they can be embedded in a
+    /// channel path, rather than referred to by [`QueryId`].
+    ///
+    /// The statement is written as JSON, deflated, base64-encoded with a
+    /// URL-safe alphabet and prefixed with `=`, which can't appear in a query ID.
+    pub fn to_inline_path(&self) -> String {
+        let mut encoder = DeflateEncoder::new(Vec::new(), Compression::best());
+        serde_json::to_writer(&mut encoder, &StatementParts::from(self)).expect("valid JSON");
+        let compressed = encoder.finish().expect("writing to a vec must not fail");
+        let mut s = String::from(ENCODED_NAME_PREFIX);
+        s.push_str(&base64::encode_config(compressed, base64::URL_SAFE_NO_PAD));
+        s
+    }
+
+    /// Parse a statement embedded in a path by [`SelectStatement::to_inline_path`].
+    ///
+    /// Anyone can subscribe to a channel, so the statement is validated as
+    /// if it had been typed into the query editor.
+    pub fn from_inline_path(s: &str) -> Result<Self> {
+        let invalid = || Error::InvalidTailTarget(format!("Invalid encoded statement {s}"));
+        let compressed = s
+            .strip_prefix(ENCODED_NAME_PREFIX)
+            .and_then(|encoded| base64::decode_config(encoded, base64::URL_SAFE_NO_PAD).ok())
+            .ok_or_else(invalid)?;
+        let mut json = Vec::new();
+        DeflateDecoder::new(compressed.as_slice())
+            .take(MAX_INLINE_STATEMENT)
+            .read_to_end(&mut json)
+            .map_err(|_| invalid())?;
+        let parts: StatementParts = serde_json::from_slice(&json).map_err(|_| invalid())?;
+        parts.into_validated_statement()
     }
 }
 
@@ -47,8 +148,10 @@ impl PathDisplay for SelectStatement {
 /// This is required when we upgrade from a data query to a stream
 /// query and the only thing we can use to link the two is a [`Channel`].
 ///
-/// Internally, this is just an md5 hash of the original SQL query.
-#[derive(Clone, Debug, Hash, PartialEq, Eq)]
+/// Internally, this is a keyed hash of the datasource and org the
+/// statement was registered for, along with its SQL and parameters; see
+/// [`QueryRegistry::query_id`](crate::registry::QueryRegistry::query_id).
+#[derive(Clone, Debug, Hash, PartialEq, Eq, PartialOrd, Ord)]
 pub struct QueryId(String);
 
 impl QueryId {
@@ -57,21 +160,30 @@ impl QueryId {
         Self(s)
     }
 
-    /// Create a `QueryId` from a SQL statement.
-    pub fn from_statement(statement: &SelectStatement) -> Self {
-        Self(format!("{:x}", md5::compute(statement.as_str())))
+    /// Get the `QueryId` referred to by a channel path such as
+    /// `tail/select/<query id>`, if it has one.
+    ///
+    /// Paths with a statement embedded in them don't refer to a `QueryId`.
+    pub fn from_channel_path(p: &str) -> Option<Self> {
+        let mut segments = p.split('/');
+        match (segments.next(), segments.next(), segments.next()) {
+            (Some("tail"), Some("select"), Some(id)) if !id.starts_with(ENCODED_NAME_PREFIX) => {
+                Some(Self(id.to_string()))
+            }
+            _ => None,
+        }
     }
 
     /// Access the inner `QueryId` as a string.
     ///
-    /// Note that this gets the md5 hash, not the original SQL query.
+    /// Note that this gets the hash, not the original SQL query.
     pub fn into_inner(self) -> String {
         self.0
     }
 
     /// Access the inner `QueryId` as a `&str`.
     ///
-    /// Note that this gets the md5 hash, not the original SQL query.
+    /// Note that this gets the hash, not the original SQL query.
     pub fn as_str(&self) -> &str {
         &self.0
     }
@@ -85,26 +197,116 @@ impl PathDisplay for TailTarget {
                 name.fmt_path(f)?;
             }
             Self::Select { statement } => {
-                let query_id = QueryId::from_statement(statement);
-                write!(f, "select/{}", &query_id.0)?;
+                f.write_str("select/")?;
+                statement.fmt_path(f)?;
             }
         }
         Ok(())
     }
 }
 
+impl TailTarget {
+    /// Create the path of the channel streaming this target in `format`.
+    ///
+    /// SELECT statements are embedded in the path if it fits in `max_len`
+    /// characters, so any plugin process can serve the channel. Otherwise the
+    /// path refers to the statement by the query ID created by `query_id`,
+    /// which is also returned so the statement can be registered under it.
+    pub fn stream_path(
+        &self,
+        format: &OutputFormat,
+        max_len: usize,
+        query_id: impl FnOnce(&SelectStatement) -> QueryId,
+    ) -> (String, Option<QueryId>) {
+        let mut suffix = String::new();
+        if *format != OutputFormat::Table {
+            suffix.push('/');
+            format
+                .fmt_path(&mut suffix)
+                .expect("writing to a string must not fail");
+        }
+        match self {
+            Self::Select { statement } => {
+                let inline = format!("tail/select/{}{suffix}", statement.to_inline_path());
+                if inline.len() <= max_len {
+                    return (inline, None);
+                }
+                let query_id = query_id(statement);
+                let path = format!("tail/select/{}{suffix}", query_id.as_str());
+                (path, Some(query_id))
+            }
+            Self::Relation { .. } => (format!("tail/{}{suffix}", self.to_path()), None),
+        }
+    }
+}
+
+/// Only `TAIL` queries are ever given a channel, so only their paths can be
+/// parsed by [`Query::try_from_path`]; other operations are still given a
+/// distinct path so they can't be mistaken for a stream.
 impl PathDisplay for Query {
     fn fmt_path(&self, f: &mut String) -> fmt::Result {
-        f.write_str("tail/")?;
         match self {
-            Self::Tail(target) => target.fmt_path(f)?,
+            Self::Tail(target) => {
+                f.write_str("tail/")?;
+                target.fmt_path(f)?;
+            }
+            Self::Select(target) => {
+                f.write_str("select/")?;
+                target.fmt_path(f)?;
+            }
+            Self::Explain { target, .. } => {
+                f.write_str("explain/")?;
+                target.fmt_path(f)?;
+            }
+            Self::Graph { name, .. } => {
+                f.write_str("graph/")?;
+                name.fmt_path(f)?;
+            }
+            Self::Introspection { query, .. } => {
+                write!(f, "introspection/{}", query.as_str())?;
+            }
         };
         Ok(())
     }
 }
 
+/// The default table format is written as an empty string, so paths of table
+/// queries are unchanged. Other formats are written as their JSON form, base64-encoded
+/// with a URL-safe alphabet and prefixed with `=`, and follow the query in a path.
+impl PathDisplay for OutputFormat {
+    fn fmt_path(&self, f: &mut String) -> fmt::Result {
+        if *self != Self::Table {
+            f.write_char(ENCODED_NAME_PREFIX)?;
+            f.write_str(&base64::encode_config(
+                serde_json::to_vec(self).expect("valid JSON"),
+                base64::URL_SAFE_NO_PAD,
+            ))?;
+        }
+        Ok(())
+    }
+}
+
+impl OutputFormat {
+    /// Parse the `OutputFormat` of a streaming query from a full channel path,
+    /// such as `tail/relation/<name>/<format>`.
+    ///
+    /// Paths without a format segment use [`OutputFormat::Table`].
+    pub fn from_channel_path(p: &str) -> Result<Self> {
+        let Some(segment) = p.splitn(4, '/').nth(3) else {
+            return Ok(Self::Table);
+        };
+        segment
+            .strip_prefix(ENCODED_NAME_PREFIX)
+            .and_then(|encoded| base64::decode_config(encoded, base64::URL_SAFE_NO_PAD).ok())
+            .and_then(|bytes| serde_json::from_slice(&bytes).ok())
+            .ok_or_else(|| Error::InvalidFormat(format!("Invalid encoded format {segment}")))
+    }
+}
+
 #[cfg(test)]
 mod tests {
+    use crate::params::ParamValue;
+
     use super::*;
 
     #[test]
@@ -116,12 +318,90 @@ mod tests {
             .to_path(),
             "tail/relation/some_table"
         );
+        let statement: SelectStatement = "SELECT * FROM my_table".parse().unwrap();
+        let path = Query::Tail(TailTarget::Select {
+            statement: statement.clone(),
+        })
+        .to_path();
         assert_eq!(
-            Query::Tail(TailTarget::Select {
-                statement: "SELECT * FROM my_table".parse().unwrap()
+            SelectStatement::from_inline_path(path.strip_prefix("tail/select/").unwrap()).unwrap(),
+            statement
+        );
+        assert_eq!(
+            Query::Select(TailTarget::Relation {
+                name: "some_table".parse().unwrap()
             })
             .to_path(),
-            "tail/select/9ebfce3b05a248842876e8ed1706a451"
+            "select/relation/some_table"
         );
     }
+
+    #[test]
+    fn source_name_round_trip() {
+        for (name, path) in [
+            ("some_table", "some_table"),
+            (
+                "materialize.public.some_table",
+                "materialize.public.some_table",
+            ),
+            (r#"public."My-View""#, "=InB1YmxpYyIuIk15LVZpZXci"),
+            (r#""vüe""#, "=InbDvGUi"),
+        ] {
+            let name: SourceName = name.parse().unwrap();
+            assert_eq!(name.to_path(), path);
+            assert_eq!(SourceName::from_path(path).unwrap(), name);
+            // Every encoded path must be a valid channel path.
+            grafana_plugin_sdk::live::Path::new(format!("tail/relation/{path}")).unwrap();
+        }
+        assert!(SourceName::from_path("=not base64").is_err());
+    }
+
+    #[test]
+    fn inline_statement() {
+        let statement = SelectStatement::from_parts(
+            "SELECT * FROM orders WHERE region = ANY($1)".to_string(),
+            vec![ParamValue::Multi(vec!["eu".to_string(), "us".to_string()])],
+        );
+        let target = TailTarget::Select {
+            statement: statement.clone(),
+        };
+        let id = |_: &SelectStatement| QueryId::new("0123abcd".to_string());
+        let (path, query_id) = target.stream_path(&OutputFormat::Table, 140, id);
+        assert_eq!(query_id, None);
+        grafana_plugin_sdk::live::Path::new(path.clone()).unwrap();
+        let segment = path.strip_prefix("tail/select/").unwrap();
+        assert_eq!(
+            SelectStatement::from_inline_path(segment).unwrap(),
+            statement
+        );
+
+        // Statements which don't fit are referred to by ID.
+        let (path, query_id) = target.stream_path(&OutputFormat::Table, 40, id);
+        let query_id = query_id.unwrap();
+        assert_eq!(path, "tail/select/0123abcd");
+        assert_eq!(QueryId::from_channel_path(&path), Some(query_id));
+        assert_eq!(QueryId::from_channel_path("tail/relation/orders"), None);
+
+        assert!(SelectStatement::from_inline_path("=not base64").is_err());
+        // Statements from paths must still be read-only.
+        let writing = SelectStatement::from_parts("DELETE FROM orders".to_string(), vec![]);
+        assert!(SelectStatement::from_inline_path(&writing.to_inline_path()).is_err());
+    }
+
+    #[test]
+    fn output_format_round_trip() {
+        assert_eq!(OutputFormat::Table.to_path(), "");
+        assert_eq!(
+            OutputFormat::from_channel_path("tail/relation/some_table").unwrap(),
+            OutputFormat::Table
+        );
+
+        let format: OutputFormat =
+            serde_json::from_str(r#"{"type": "annotations", "time": "ts", "tags": ["Env"]}"#)
+                .unwrap();
+        let path = format!("tail/relation/some_table/{}", format.to_path());
+        grafana_plugin_sdk::live::Path::new(path.clone()).unwrap();
+        assert_eq!(OutputFormat::from_channel_path(&path).unwrap(), format);
+        assert!(OutputFormat::from_channel_path("tail/relation/some_table/nope").is_err());
+    }
 }
@@ -1,77 +1,217 @@
 //! Internal representations of queries requested by the frontend.
 
+use futures_util::TryStreamExt;
 use grafana_plugin_sdk::live::Path;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use serde_with::DeserializeFromStr;
 use std::{fmt, str::FromStr};
 use tokio_postgres::{Client, Row, RowStream};
 
-use crate::{path, Error, Result, SqlQueries};
+use crate::{
+    explain::ExplainPlan,
+    filters::AdHocFilter,
+    introspection::IntrospectionQuery,
+    macros::{self, MacroContext},
+    output::OutputFormat,
+    params::{self, ParamValue, Variables},
+    path,
+    registry::{QueryRegistry, QueryScope},
+    sql, Error, Result,
+};
 
 /// The name of a source the user wishes to tail.
 ///
-/// This is a thin newtype wrapper around a string that
-/// just does some very basic validation on creation.
+/// This may optionally be qualified with a schema, or a database and
+/// a schema. Each part is stored unquoted, exactly as it appears in the
+/// catalog, and is quoted again when the name is displayed.
 #[derive(Clone, Debug, Hash, PartialEq, Eq, DeserializeFromStr)]
-pub struct SourceName(String);
+pub struct SourceName {
+    database: Option<String>,
+    schema: Option<String>,
+    name: String,
+}
 
 impl SourceName {
-    /// Get the inner source name as a `&str`.
-    pub fn as_str(&self) -> &str {
-        &self.0
+    /// Create a new `SourceName` from its unquoted parts.
+    pub fn new(database: Option<String>, schema: Option<String>, name: String) -> Self {
+        Self {
+            database,
+            schema,
+            name,
+        }
+    }
+
+    /// Get the database part of the name, if present.
+    pub fn database(&self) -> Option<&str> {
+        self.database.as_deref()
+    }
+
+    /// Get the schema part of the name, if present.
+    pub fn schema(&self) -> Option<&str> {
+        self.schema.as_deref()
+    }
+
+    /// Get the unqualified name of the relation.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Iterate over the unquoted parts of the name, from outermost to innermost.
+    pub fn parts(&self) -> impl Iterator<Item = &str> {
+        self.database
+            .as_deref()
+            .into_iter()
+            .chain(self.schema.as_deref())
+            .chain(std::iter::once(self.name.as_str()))
     }
 }
 
+/// Formats the name as a fully quoted SQL identifier, e.g. `"db"."schema"."name"`.
 impl fmt::Display for SourceName {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        self.0.fmt(f)
+        let mut parts = self.parts();
+        if let Some(first) = parts.next() {
+            f.write_str(&sql::quote_ident(first))?;
+        }
+        for part in parts {
+            write!(f, ".{}", sql::quote_ident(part))?;
+        }
+        Ok(())
     }
 }
 
 impl FromStr for SourceName {
     type Err = Error;
     fn from_str(s: &str) -> Result<Self> {
-        if s.find(|c: char| !(c.is_ascii_alphanumeric() || c == '.' || c == '_'))
-            .is_some()
-        {
-            Err(Error::InvalidTailTarget(format!(
-                "Invalid relation name {s}"
-            )))
-        } else {
-            Ok(Self(s.to_string()))
+        let mut parts = sql::parse_object_name(s)?;
+        let Some(name) = parts.pop() else {
+            return Err(Error::InvalidTailTarget(format!(
+                "Invalid relation name {s}: missing name"
+            )));
+        };
+        match parts.as_slice() {
+            [] => Ok(Self::new(None, None, name)),
+            [schema] => Ok(Self::new(None, Some(schema.clone()), name)),
+            [database, schema] => Ok(Self::new(
+                Some(database.clone()),
+                Some(schema.clone()),
+                name,
+            )),
+            _ => Err(Error::InvalidTailTarget(format!(
+                "Invalid relation name {s}: too many qualifiers"
+            ))),
         }
     }
 }
 
 /// A select statement that the user wishes to tail.
 ///
-/// This is a thin newtype wrapper around a string that
-/// just does some very basic validation on creation.
+/// This is a thin wrapper around a string which is guaranteed to
+/// contain a single read-only query, once any [macros](crate::macros)
+/// have been expanded, along with the values of any parameters
+/// bound using [`SelectStatement::bind`].
 #[derive(Clone, Debug, Hash, PartialEq, Eq, DeserializeFromStr)]
-pub struct SelectStatement(String);
+pub struct SelectStatement {
+    sql: String,
+    params: Vec<ParamValue>,
+}
 
 impl SelectStatement {
+    /// Create a statement from SQL built by the plugin itself, along with the
+    /// values of its positional parameters.
+    ///
+    /// The SQL is not validated, so callers must ensure it is read-only.
+    pub(crate) fn from_parts(sql: String, params: Vec<ParamValue>) -> Self {
+        Self { sql, params }
+    }
+
     /// Get the inner SQL statement as a `&str`.
     pub fn as_str(&self) -> &str {
-        &self.0
+        &self.sql
+    }
+
+    /// Get the values of the statement's positional parameters.
+    pub fn params(&self) -> &[ParamValue] {
+        &self.params
+    }
+
+    /// Expand any macros in this statement using the values in `ctx`.
+    pub fn expand_macros(&self, ctx: &MacroContext) -> Result<Self> {
+        // Macros only ever expand to literals or wrap their argument in a
+        // condition, so the expanded statement is still read-only.
+        Ok(Self {
+            sql: macros::expand(&self.sql, ctx)?,
+            params: self.params.clone(),
+        })
+    }
+
+    /// Bind the named placeholders in this statement to the values of `variables`.
+    ///
+    /// See the [`params`](crate::params) module for details.
+    pub fn bind(&self, variables: &Variables) -> Result<Self> {
+        if !self.params.is_empty() {
+            return Err(Error::InvalidTailTarget(
+                "statement parameters have already been bound".to_string(),
+            ));
+        }
+        let (sql, params) = params::bind(&self.sql, variables)?;
+        Ok(Self { sql, params })
     }
 }
 
 impl FromStr for SelectStatement {
     type Err = Error;
     fn from_str(s: &str) -> Result<Self> {
-        // TODO: actually validate here.
-        Ok(Self(s.to_string()))
+        // The statement is embedded in others, such as `TAIL (...)`, so it
+        // can't end with a semicolon.
+        let s = sql::trim_statement(s);
+        sql::validate_read_only(&macros::expand(s, &MacroContext::placeholder())?)?;
+        Ok(Self {
+            sql: s.to_string(),
+            params: Vec::new(),
+        })
+    }
+}
+
+/// The serialized form of a [`SelectStatement`], as embedded in channel paths
+/// and persisted by the [`QueryRegistry`](crate::registry::QueryRegistry).
+#[derive(Deserialize, Serialize)]
+pub(crate) struct StatementParts {
+    sql: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    params: Vec<ParamValue>,
+}
+
+impl From<&SelectStatement> for StatementParts {
+    fn from(statement: &SelectStatement) -> Self {
+        Self {
+            sql: statement.sql.clone(),
+            params: statement.params.clone(),
+        }
+    }
+}
+
+impl StatementParts {
+    /// Convert back to a statement, trusting that it was created by the plugin.
+    pub(crate) fn into_statement(self) -> SelectStatement {
+        SelectStatement::from_parts(self.sql, self.params)
+    }
+
+    /// Convert back to a statement, validating that it is read-only since it
+    /// came from an untrusted source.
+    pub(crate) fn into_validated_statement(self) -> Result<SelectStatement> {
+        sql::validate_read_only(&self.sql)?;
+        Ok(self.into_statement())
     }
 }
 
 impl fmt::Display for SelectStatement {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        self.0.fmt(f)
+        self.sql.fmt(f)
     }
 }
 
-/// The target of a `TAIL` query.
+/// The target of a `TAIL` or one-shot `SELECT` query.
 #[derive(Clone, Debug, Hash, PartialEq, Eq, Deserialize)]
 #[serde(tag = "target", rename_all = "camelCase")]
 #[non_exhaustive]
@@ -83,6 +223,34 @@ pub enum TailTarget {
 }
 
 impl TailTarget {
+    /// Expand any macros in this target using the values in `ctx`.
+    pub fn expand_macros(&self, ctx: &MacroContext) -> Result<Self> {
+        Ok(match self {
+            Self::Relation { .. } => self.clone(),
+            Self::Select { statement } => Self::Select {
+                statement: statement.expand_macros(ctx)?,
+            },
+        })
+    }
+
+    /// Bind any named placeholders in this target to the values of `variables`.
+    pub fn bind(&self, variables: &Variables) -> Result<Self> {
+        Ok(match self {
+            Self::Relation { .. } => self.clone(),
+            Self::Select { statement } => Self::Select {
+                statement: statement.bind(variables)?,
+            },
+        })
+    }
+
+    /// The SQL used to select from this target, along with its parameters.
+    pub(crate) fn select_sql(&self) -> (String, &[ParamValue]) {
+        match self {
+            Self::Relation { name } => (format!("SELECT * FROM {name}"), &[]),
+            Self::Select { statement } => (statement.sql.clone(), statement.params()),
+        }
+    }
+
     /// `TAIL` this target using the provided client,
     /// returning a stream of rows from the target.
     ///
@@ -92,28 +260,72 @@ impl TailTarget {
     /// and Grafana `run_stream` requests; only the first user to subscribe
     /// triggers `run_stream`, so we need to provide the initial data another
     /// way. See [`TailTarget::select_all`] for a method of doing so.
+    ///
+    /// The `TAIL` runs in a read-only transaction, which is left open until
+    /// the client is dropped.
     pub async fn tail(&self, client: &Client) -> Result<RowStream> {
-        let query = match self {
-            Self::Relation { name } => format!("TAIL {name} WITH (SNAPSHOT = false)"),
-            Self::Select { statement } => format!("TAIL ({statement}) WITH (SNAPSHOT = false)"),
-        };
-        let params: &[&str] = &[];
+        let (query, params) = self.tail_sql();
+        begin_read_only(client).await?;
         Ok(client.query_raw(&query, params).await?)
     }
 
+    /// The SQL used to `TAIL` this target, along with its parameters.
+    pub(crate) fn tail_sql(&self) -> (String, &[ParamValue]) {
+        match self {
+            Self::Relation { name } => (format!("TAIL {name} WITH (SNAPSHOT = false)"), &[]),
+            Self::Select { statement } => (
+                format!("TAIL ({statement}) WITH (SNAPSHOT = false)"),
+                statement.params(),
+            ),
+        }
+    }
+
     /// Select all rows from this target into a `Vec`.
     ///
     /// This exists as a method of getting hold of the 'initial data'
     /// for a stream and should be called and returned to the user
     /// as part of their stream subscription (i.e. in `subscribe_stream`).
     pub async fn select_all(&self, client: &Client) -> Result<Vec<Row>> {
-        Ok(match self {
-            Self::Relation { name } => client.query(&format!("SELECT * FROM {name}"), &[]).await?,
-            Self::Select { statement } => client.query(&statement.0, &[]).await?,
-        })
+        let (query, params) = self.select_sql();
+        begin_read_only(client).await?;
+        let rows = client
+            .query_raw(&query, params)
+            .await?
+            .try_collect()
+            .await?;
+        client.batch_execute("COMMIT").await?;
+        Ok(rows)
     }
 }
 
+/// Start a read-only transaction on `client`.
+///
+/// Materialize can't make a whole session read-only, so statements written by
+/// users are run in a read-only transaction instead, and the server rejects
+/// any which try to modify data with [`Error::ReadOnlyViolation`].
+pub(crate) async fn begin_read_only(client: &Client) -> Result<()> {
+    Ok(client.batch_execute("BEGIN READ ONLY").await?)
+}
+
+/// A [`Query`] along with the dashboard template state it should be run with.
+///
+/// This is the JSON sent by the frontend for each query in a data request.
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct TemplatedQuery {
+    #[serde(flatten)]
+    pub query: Query,
+    /// The values of dashboard template variables, bound to named placeholders.
+    #[serde(default)]
+    pub variables: Variables,
+    /// Ad-hoc filters applied to the query's output.
+    #[serde(default)]
+    pub adhoc_filters: Vec<AdHocFilter>,
+    /// How the query's rows should be shaped into frames.
+    #[serde(default)]
+    pub format: OutputFormat,
+}
+
 /// The query a user wishes to run.
 #[derive(Clone, Debug, Deserialize, PartialEq, Eq, Hash)]
 #[serde(tag = "operation", rename_all = "camelCase")]
@@ -121,31 +333,67 @@ impl TailTarget {
 pub enum Query {
     /// Tail the output of a relation.
     Tail(TailTarget),
+    /// Select the current contents of a relation once, without streaming updates.
+    Select(TailTarget),
+    /// Explain the plan used to compute a relation or statement.
+    Explain {
+        #[serde(flatten)]
+        target: TailTarget,
+        #[serde(default)]
+        plan: ExplainPlan,
+    },
+    /// Show the objects a relation depends on, and those depending on it, as a graph.
+    Graph {
+        name: SourceName,
+        /// Whether to include the size of each object's arrangements.
+        #[serde(default)]
+        stats: bool,
+    },
+    /// Run a predefined introspection query, suited to the server's version.
+    Introspection {
+        query: IntrospectionQuery,
+        /// Whether to stream updates to the results, as for [`Query::Tail`].
+        #[serde(default)]
+        stream: bool,
+    },
 }
 
 impl Query {
-    /// Try to convert a [`Path`] to a [`Query`], using the provided [`SqlQueries`]
-    /// to lookup a query ID if the path contains one.
+    /// Try to convert a [`Path`] to a [`Query`], decoding a SELECT statement
+    /// embedded in the path or using the provided [`QueryRegistry`] to lookup a
+    /// query ID if the path contains one.
     ///
     /// # Errors
     ///
     /// This will fail if:
-    /// - the path does not match a known format (`/tail/relation/<name>` or `/tail/select/<query id>`)
-    /// - the query ID in the 'select' form is not present in `queries`
-    pub async fn try_from_path(p: &Path, queries: SqlQueries) -> Result<Self> {
-        let mut iter = p.as_str().splitn(3, '/');
+    /// - the path does not match a known format (`/tail/relation/<name>` or
+    ///   `/tail/select/<query id or encoded statement>`)
+    /// - an embedded statement can't be decoded or isn't read-only
+    /// - the query ID in the 'select' form is not present in `queries`, or was
+    ///   registered for a different datasource or org than `scope`
+    ///
+    /// Any segments following the target, such as an [`OutputFormat`], are ignored.
+    pub async fn try_from_path(
+        p: &Path,
+        queries: &QueryRegistry,
+        scope: &QueryScope,
+    ) -> Result<Self> {
+        let mut iter = p.as_str().split('/');
         match (iter.next(), iter.next(), iter.next()) {
             (Some("tail"), Some("relation"), Some(name)) => Ok(Self::Tail(TailTarget::Relation {
-                name: name.parse()?,
+                name: SourceName::from_path(name)?,
             })),
+            (Some("tail"), Some("select"), Some(encoded)) if encoded.starts_with('=') => {
+                Ok(Self::Tail(TailTarget::Select {
+                    statement: SelectStatement::from_inline_path(encoded)?,
+                }))
+            }
             (Some("tail"), Some("select"), Some(query_id)) => {
                 let query_id = path::QueryId::new(query_id.to_string());
                 Ok(Self::Tail(TailTarget::Select {
                     statement: queries
-                        .read()
+                        .get(&query_id, scope)
                         .await
-                        .get(&query_id)
-                        .cloned()
                         .ok_or_else(|| Error::InvalidTailTarget(query_id.into_inner()))?,
                 }))
             }
@@ -154,29 +402,20 @@ impl Query {
         }
     }
 
-    /// Attempt to access this query as `&TailTarget`, or return an `Err` if it doesn't match.
-    ///
-    /// This is just a helper function to avoid having to match all over the place, since
-    /// we know the query can't take any other form for now.
+    /// Attempt to access this query as `&TailTarget`, or return an `Err` if it isn't a `TAIL`.
     pub(crate) fn as_tail(&self) -> Result<&TailTarget> {
-        // If this enum changes in future we'll probably want to early return
-        // hence using `match` instead of `if let`.
         match self {
             Self::Tail(target) => Ok(target),
-            // This could change in future; don't want a catch-all
-            // pattern though as we should handle it properly.
+            Self::Select(_)
+            | Self::Explain { .. }
+            | Self::Graph { .. }
+            | Self::Introspection { .. } => Err(Error::NotStreaming),
         }
     }
 }
 
 #[cfg(test)]
 mod test {
-    use std::{collections::HashMap, sync::Arc};
-
-    use tokio::sync::RwLock;
-
-    use crate::path::QueryId;
-
     use super::*;
 
     #[test]
@@ -187,7 +426,20 @@ mod test {
             )
             .unwrap(),
             Query::Tail(TailTarget::Relation {
-                name: SourceName("some_table".parse().unwrap())
+                name: SourceName::new(None, None, "some_table".to_string())
+            })
+        );
+        assert_eq!(
+            serde_json::from_str::<Query>(
+                r#"{"operation": "tail", "target": "relation", "name": "materialize.public.\"My-View\""}"#
+            )
+            .unwrap(),
+            Query::Tail(TailTarget::Relation {
+                name: SourceName::new(
+                    Some("materialize".to_string()),
+                    Some("public".to_string()),
+                    "My-View".to_string()
+                )
             })
         );
         assert!(serde_json::from_str::<Query>(
@@ -204,21 +456,159 @@ mod test {
             )
             .unwrap(),
             Query::Tail(TailTarget::Select {
-                statement: SelectStatement("SELECT * FROM my_table".parse().unwrap())
+                statement: "SELECT * FROM my_table".parse().unwrap()
+            })
+        );
+        assert!(serde_json::from_str::<Query>(
+            r#"{"operation": "tail", "target": "select", "statement": "DROP VIEW x; SELECT 1"}"#
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn deserialize_explain() {
+        assert_eq!(
+            serde_json::from_str::<Query>(
+                r#"{"operation": "explain", "target": "relation", "name": "some_table"}"#
+            )
+            .unwrap(),
+            Query::Explain {
+                target: TailTarget::Relation {
+                    name: "some_table".parse().unwrap()
+                },
+                plan: ExplainPlan::Optimized,
+            }
+        );
+        assert_eq!(
+            serde_json::from_str::<Query>(
+                r#"{"operation": "explain", "plan": "physical", "target": "select", "statement": "SELECT 1"}"#
+            )
+            .unwrap(),
+            Query::Explain {
+                target: TailTarget::Select {
+                    statement: "SELECT 1".parse().unwrap()
+                },
+                plan: ExplainPlan::Physical,
+            }
+        );
+    }
+
+    #[test]
+    fn deserialize_graph() {
+        let query = serde_json::from_str::<Query>(
+            r#"{"operation": "graph", "name": "public.orders", "stats": true}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            query,
+            Query::Graph {
+                name: "public.orders".parse().unwrap(),
+                stats: true,
+            }
+        );
+    }
+
+    #[test]
+    fn deserialize_introspection() {
+        assert_eq!(
+            serde_json::from_str::<Query>(
+                r#"{"operation": "introspection", "query": "freshness", "stream": true}"#
+            )
+            .unwrap(),
+            Query::Introspection {
+                query: IntrospectionQuery::Freshness,
+                stream: true,
+            }
+        );
+    }
+
+    #[test]
+    fn deserialize_select() {
+        let query = serde_json::from_str::<Query>(
+            r#"{"operation": "select", "target": "relation", "name": "some_table"}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            query,
+            Query::Select(TailTarget::Relation {
+                name: "some_table".parse().unwrap()
+            })
+        );
+        assert!(matches!(query.as_tail(), Err(Error::NotStreaming)));
+        assert_eq!(
+            serde_json::from_str::<Query>(
+                r#"{"operation": "select", "target": "select", "statement": "SELECT * FROM my_table"}"#
+            )
+            .unwrap(),
+            Query::Select(TailTarget::Select {
+                statement: "SELECT * FROM my_table".parse().unwrap()
             })
         );
     }
 
+    #[test]
+    fn deserialize_templated() {
+        let templated: TemplatedQuery = serde_json::from_str(
+            r#"{
+                "refId": "A",
+                "operation": "tail",
+                "target": "select",
+                "statement": "SELECT * FROM t WHERE region = ANY($region)",
+                "variables": {"region": ["eu", "us"]}
+            }"#,
+        )
+        .unwrap();
+        let target = templated
+            .query
+            .as_tail()
+            .unwrap()
+            .bind(&templated.variables)
+            .unwrap();
+        match target {
+            TailTarget::Select { statement } => {
+                assert_eq!(statement.as_str(), "SELECT * FROM t WHERE region = ANY($1)");
+                assert_eq!(
+                    statement.params(),
+                    &[ParamValue::Multi(vec!["eu".to_string(), "us".to_string()])]
+                );
+            }
+            other => panic!("unexpected target {other:?}"),
+        }
+
+        let templated: TemplatedQuery = serde_json::from_str(
+            r#"{"operation": "tail", "target": "relation", "name": "some_table"}"#,
+        )
+        .unwrap();
+        assert!(templated.variables.is_empty());
+        assert!(templated.adhoc_filters.is_empty());
+
+        let templated: TemplatedQuery = serde_json::from_str(
+            r#"{
+                "operation": "tail",
+                "target": "relation",
+                "name": "some_table",
+                "adhocFilters": [{"key": "region", "operator": "=", "value": "eu"}]
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(templated.adhoc_filters.len(), 1);
+    }
+
     #[tokio::test]
     async fn query_from_str() {
-        let queries = Arc::new(RwLock::new(HashMap::from([(
-            QueryId::new("9ebfce3b05a248842876e8ed1706a451".to_string()),
-            "SELECT * FROM my_table".parse().unwrap(),
-        )])));
+        let queries = QueryRegistry::default();
+        let scope = QueryScope {
+            datasource_uid: "abc".to_string(),
+            org_id: 1,
+        };
+        let query_id = queries
+            .insert(scope.clone(), "SELECT * FROM my_table".parse().unwrap())
+            .await;
         assert_eq!(
             Query::try_from_path(
                 &Path::new("tail/relation/some_table".to_string()).unwrap(),
-                Arc::clone(&queries)
+                &queries,
+                &scope
             )
             .await
             .unwrap(),
@@ -228,8 +618,9 @@ mod test {
         );
         assert_eq!(
             Query::try_from_path(
-                &Path::new("tail/select/9ebfce3b05a248842876e8ed1706a451".to_string()).unwrap(),
-                Arc::clone(&queries)
+                &Path::new(format!("tail/select/{}", query_id.as_str())).unwrap(),
+                &queries,
+                &scope
             )
             .await
             .unwrap(),
@@ -237,5 +628,42 @@ mod test {
                 statement: "SELECT * FROM my_table".parse().unwrap()
             })
         );
+        // Embedded statements don't need to be registered.
+        let statement: SelectStatement = "SELECT * FROM other_table".parse().unwrap();
+        let path = format!("tail/select/{}", statement.to_inline_path());
+        assert_eq!(
+            Query::try_from_path(&Path::new(path).unwrap(), &QueryRegistry::default(), &scope)
+                .await
+                .unwrap(),
+            Query::Tail(TailTarget::Select { statement })
+        );
+        assert!(Query::try_from_path(
+            &Path::new("tail/select/0123abcd".to_string()).unwrap(),
+            &queries,
+            &scope
+        )
+        .await
+        .is_err());
+        // Statements registered for another org aren't found.
+        let other_org = QueryScope { org_id: 2, ..scope };
+        assert!(Query::try_from_path(
+            &Path::new(format!("tail/select/{}", query_id.as_str())).unwrap(),
+            &queries,
+            &other_org
+        )
+        .await
+        .is_err());
+    }
+
+    #[test]
+    fn trailing_semicolons() {
+        let statement: SelectStatement = "SELECT * FROM t;; -- all of it\n".parse().unwrap();
+        assert_eq!(statement.as_str(), "SELECT * FROM t");
+        let target = TailTarget::Select { statement };
+        assert_eq!(
+            target.tail_sql().0,
+            "TAIL (SELECT * FROM t) WITH (SNAPSHOT = false)"
+        );
+        assert_eq!(target.select_sql().0, "SELECT * FROM t");
     }
 }
@@ -0,0 +1,899 @@
+//! The registry of SELECT statements behind `tail/select/<query ID>` channels.
+//!
+//! A channel path can only hold a statement which is short enough, so longer
+//! ones are referred to by their [`QueryId`] and looked up here when Grafana
+//! subscribes to the channel. If a data directory is configured, statements are
+//! also written to it so that open dashboards keep streaming after the plugin
+//! restarts. Statements read back from disk are validated again before use.
+//!
+//! Query IDs are a keyed hash of the statement and the datasource and org it
+//! was registered for, and lookups must come from the same datasource and org,
+//! so statements are never shared between them.
+//!
+//! The registry is bounded: statements which haven't been used for a while are
+//! evicted, as are the least recently used ones once it is full. Statements
+//! being streamed are pinned and never evicted.
+
+use std::{
+    collections::{BTreeSet, HashMap},
+    env, fmt,
+    io::{self, Read, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, MutexGuard},
+    time::{Duration, Instant, SystemTime},
+};
+
+use grafana_plugin_sdk::backend;
+use hmac::{Hmac, KeyInit, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use tokio::{fs, task::JoinHandle};
+use tracing::{debug, warn};
+
+use crate::{
+    metrics::{EvictionReason, Metrics},
+    path::QueryId,
+    queries::{SelectStatement, StatementParts},
+    Error, Result,
+};
+
+/// Environment variable overriding the directory statements are stored in.
+const DATA_DIR_ENV: &str = "MATERIALIZE_DATASOURCE_DATA_DIR";
+
+/// Limits on the statements held by a [`QueryRegistry`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RegistryLimits {
+    /// The most statements to hold, unless more than this many are pinned.
+    pub capacity: usize,
+    /// How long a statement is kept after it was last used.
+    pub ttl: Duration,
+}
+
+impl Default for RegistryLimits {
+    fn default() -> Self {
+        Self {
+            capacity: 10_000,
+            ttl: Duration::from_secs(24 * 60 * 60),
+        }
+    }
+}
+
+/// The datasource instance and org a statement was registered for.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct QueryScope {
+    pub datasource_uid: String,
+    pub org_id: i64,
+}
+
+impl QueryScope {
+    /// Get the scope of a request from its plugin context.
+    pub fn from_context(context: &backend::PluginContext) -> Result<Self> {
+        let settings = context
+            .datasource_instance_settings
+            .as_ref()
+            .ok_or(Error::MissingDatasource)?;
+        Ok(Self {
+            datasource_uid: settings.uid.clone(),
+            org_id: context.org_id,
+        })
+    }
+}
+
+/// The secret key used to create query IDs.
+///
+/// Without the key, nobody can create a query ID for a statement, or find
+/// two statements with the same ID.
+#[derive(Clone)]
+pub struct QueryKey([u8; 32]);
+
+impl QueryKey {
+    /// Read the key stored in `file`, or create a new key and store it there
+    /// if there isn't a valid one.
+    ///
+    /// The key must be kept between restarts, since query IDs of persisted
+    /// statements depend on it.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the key can't be read or stored, or if `file` isn't
+    /// owned by the current user or is accessible to anyone else, since they
+    /// could then create query IDs for their own statements.
+    pub fn load_or_create(file: &Path) -> io::Result<Self> {
+        let mut existing = match std::fs::File::open(file) {
+            Ok(existing) => existing,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                let key = Self::default();
+                key.store(file, true)?;
+                return Ok(key);
+            }
+            Err(e) => return Err(e),
+        };
+        check_private(file, &existing.metadata()?, 0o077)?;
+        let mut bytes = Vec::new();
+        existing.read_to_end(&mut bytes)?;
+        match bytes.try_into() {
+            Ok(key) => Ok(Self(key)),
+            Err(_) => {
+                warn!("replacing invalid query key");
+                let key = Self::default();
+                key.store(file, false)?;
+                Ok(key)
+            }
+        }
+    }
+
+    /// Write the key to `file`, readable only by the current user.
+    ///
+    /// If `new` is set, the file must not already exist, so a file created by
+    /// someone else in the meantime is never written to.
+    fn store(&self, file: &Path, new: bool) -> io::Result<()> {
+        let mut options = std::fs::File::options();
+        options.write(true);
+        if new {
+            options.create_new(true);
+        } else {
+            options.truncate(true);
+        }
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        options.open(file)?.write_all(&self.0)
+    }
+}
+
+/// Check that `path` is owned by the current user, and that none of the
+/// permission bits in `forbidden` are set in its `metadata`.
+#[cfg(unix)]
+fn check_private(path: &Path, metadata: &std::fs::Metadata, forbidden: u32) -> io::Result<()> {
+    use std::os::unix::fs::MetadataExt;
+
+    // SAFETY: `geteuid` has no preconditions and always succeeds.
+    let uid = unsafe { libc::geteuid() };
+    if metadata.uid() != uid || metadata.mode() & forbidden != 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!(
+                "{} must be owned by the current user and not accessible to other users",
+                path.display()
+            ),
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn check_private(_path: &Path, _metadata: &std::fs::Metadata, _forbidden: u32) -> io::Result<()> {
+    Ok(())
+}
+
+/// Create the data directory `dir` if necessary, accessible only to the current
+/// user, and load the query key stored in it.
+///
+/// An existing directory must be owned by the current user and not writable by
+/// anyone else, since they could otherwise replace the key.
+fn open_data_dir(dir: &Path) -> io::Result<QueryKey> {
+    let mut builder = std::fs::DirBuilder::new();
+    builder.recursive(true);
+    #[cfg(unix)]
+    std::os::unix::fs::DirBuilderExt::mode(&mut builder, 0o700);
+    builder.create(dir)?;
+    check_private(dir, &std::fs::metadata(dir)?, 0o022)?;
+    QueryKey::load_or_create(&dir.join("query.key"))
+}
+
+/// Create a new random key.
+impl Default for QueryKey {
+    fn default() -> Self {
+        Self(rand::random())
+    }
+}
+
+impl fmt::Debug for QueryKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("QueryKey(<redacted>)")
+    }
+}
+
+/// A statement as persisted to disk.
+#[derive(Deserialize, Serialize)]
+struct Persisted {
+    #[serde(flatten)]
+    statement: StatementParts,
+    scope: QueryScope,
+}
+
+/// A registered statement.
+#[derive(Debug)]
+struct Entry {
+    statement: SelectStatement,
+    scope: QueryScope,
+    last_used: Instant,
+    /// The number of streams currently using the statement.
+    pins: usize,
+}
+
+/// The registered statements, along with an index of those which can be
+/// evicted in the order they were last used.
+#[derive(Debug, Default)]
+struct Entries {
+    by_id: HashMap<QueryId, Entry>,
+    /// The unpinned entries, least recently used first.
+    ///
+    /// Pinned entries are left out, so eviction never has to skip them.
+    lru: BTreeSet<(Instant, QueryId)>,
+}
+
+impl Entries {
+    fn len(&self) -> usize {
+        self.by_id.len()
+    }
+
+    /// Add an entry which isn't already present.
+    fn insert(&mut self, id: QueryId, entry: Entry) {
+        if entry.pins == 0 {
+            self.lru.insert((entry.last_used, id.clone()));
+        }
+        self.by_id.insert(id, entry);
+    }
+
+    /// Get the entry for `id`, marking it as used at `now`.
+    fn touch(&mut self, id: &QueryId, now: Instant) -> Option<&Entry> {
+        let entry = self.by_id.get_mut(id)?;
+        if entry.pins == 0 {
+            self.lru.remove(&(entry.last_used, id.clone()));
+            self.lru.insert((now, id.clone()));
+        }
+        entry.last_used = now;
+        Some(entry)
+    }
+
+    /// Pin the entry for `id`, returning whether it exists.
+    fn pin(&mut self, id: &QueryId) -> bool {
+        let Some(entry) = self.by_id.get_mut(id) else {
+            return false;
+        };
+        if entry.pins == 0 {
+            self.lru.remove(&(entry.last_used, id.clone()));
+        }
+        entry.pins += 1;
+        true
+    }
+
+    /// Release a pin on the entry for `id`, which was in use until `now`.
+    fn unpin(&mut self, id: &QueryId, now: Instant) {
+        let Some(entry) = self.by_id.get_mut(id) else {
+            return;
+        };
+        entry.pins -= 1;
+        entry.last_used = now;
+        if entry.pins == 0 {
+            self.lru.insert((now, id.clone()));
+        }
+    }
+
+    /// Remove the entries which should be evicted at `now`.
+    ///
+    /// Pinned entries are never evicted, even if that leaves more than
+    /// `limits.capacity` entries.
+    fn evict(&mut self, limits: RegistryLimits, now: Instant) -> Vec<(QueryId, EvictionReason)> {
+        let mut evicted = Vec::new();
+        while let Some((last_used, _)) = self.lru.first() {
+            let reason = if now.saturating_duration_since(*last_used) > limits.ttl {
+                EvictionReason::Expired
+            } else if self.by_id.len() > limits.capacity {
+                EvictionReason::Capacity
+            } else {
+                break;
+            };
+            let Some((_, id)) = self.lru.pop_first() else {
+                break;
+            };
+            self.by_id.remove(&id);
+            evicted.push((id, reason));
+        }
+        evicted
+    }
+}
+
+/// A shareable registry of the SELECT statements served by this plugin,
+/// keyed by query ID.
+#[derive(Clone, Debug, Default)]
+pub struct QueryRegistry {
+    entries: Arc<Mutex<Entries>>,
+    limits: RegistryLimits,
+    key: QueryKey,
+    /// The directory statements are persisted to, if any.
+    dir: Option<PathBuf>,
+    metrics: Arc<Metrics>,
+}
+
+impl QueryRegistry {
+    /// Create a registry persisting statements to `dir`, if given, and
+    /// recording evictions in `metrics`.
+    pub fn new(key: QueryKey, dir: Option<PathBuf>, metrics: Arc<Metrics>) -> Self {
+        Self {
+            entries: Default::default(),
+            limits: RegistryLimits::default(),
+            key,
+            dir,
+            metrics,
+        }
+    }
+
+    /// Create a registry persisting statements to the directory named by
+    /// `MATERIALIZE_DATASOURCE_DATA_DIR`, or holding them only in memory if it
+    /// isn't set.
+    pub fn from_env(metrics: Arc<Metrics>) -> Self {
+        match env::var_os(DATA_DIR_ENV) {
+            Some(dir) => Self::persisted(PathBuf::from(dir), metrics),
+            None => Self::new(QueryKey::default(), None, metrics),
+        }
+    }
+
+    /// Create a registry persisting statements to `dir`, along with the key
+    /// used to create query IDs.
+    ///
+    /// Statements persisted by previous processes which have since expired
+    /// are removed. If `dir` or the key in it aren't private to the current
+    /// user, statements are only held in memory.
+    pub fn persisted(dir: PathBuf, metrics: Arc<Metrics>) -> Self {
+        match open_data_dir(&dir) {
+            Ok(key) => {
+                let registry = Self::new(key, Some(dir.join("queries")), metrics);
+                registry.prune_persisted();
+                registry
+            }
+            Err(e) => {
+                warn!(error = %e, "not persisting queries");
+                Self::new(QueryKey::default(), None, metrics)
+            }
+        }
+    }
+
+    /// Use `limits` instead of the default limits.
+    pub fn with_limits(mut self, limits: RegistryLimits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    fn entries(&self) -> MutexGuard<'_, Entries> {
+        self.entries.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Create the ID of `statement` when registered for `scope`.
+    pub fn query_id(&self, scope: &QueryScope, statement: &SelectStatement) -> QueryId {
+        let mut mac =
+            Hmac::<Sha256>::new_from_slice(&self.key.0).expect("HMAC accepts keys of any length");
+        // JSON keeps the boundaries between the parts unambiguous.
+        let input = (
+            &scope.datasource_uid,
+            scope.org_id,
+            statement.as_str(),
+            statement.params(),
+        );
+        mac.update(&serde_json::to_vec(&input).expect("valid JSON"));
+        let hash = mac.finalize().into_bytes();
+        QueryId::new(hash.iter().map(|b| format!("{b:02x}")).collect())
+    }
+
+    /// The file `id` is stored in, if statements are persisted.
+    ///
+    /// Query IDs come from channel paths, so anything other than a hex digest
+    /// is rejected rather than used as a file name.
+    fn file(&self, id: &QueryId) -> Option<PathBuf> {
+        let dir = self.dir.as_ref()?;
+        let valid = !id.as_str().is_empty() && id.as_str().bytes().all(|b| b.is_ascii_hexdigit());
+        valid.then(|| dir.join(format!("{}.json", id.as_str())))
+    }
+
+    /// Register `statement` for `scope`, persisting it if it wasn't already
+    /// known, and return its ID.
+    ///
+    /// Failing to persist a statement is logged rather than returned, since
+    /// the statement can still be streamed until the plugin restarts.
+    pub async fn insert(&self, scope: QueryScope, statement: SelectStatement) -> QueryId {
+        let id = self.query_id(&scope, &statement);
+        let file = self.file(&id);
+        if self.cache(id.clone(), scope.clone(), statement.clone()) {
+            return id;
+        }
+        if let Some(file) = file {
+            if let Err(e) = write(file, scope, &statement).await {
+                warn!(error = %e, "failed to persist query");
+            }
+        }
+        id
+    }
+
+    /// Hold `statement` in memory, evicting others if necessary.
+    ///
+    /// Returns whether the statement was already held.
+    fn cache(&self, id: QueryId, scope: QueryScope, statement: SelectStatement) -> bool {
+        let now = Instant::now();
+        let (existed, evicted) = {
+            let mut entries = self.entries();
+            let existed = entries.touch(&id, now).is_some();
+            if !existed {
+                entries.insert(
+                    id,
+                    Entry {
+                        statement,
+                        scope,
+                        last_used: now,
+                        pins: 0,
+                    },
+                );
+            }
+            (existed, entries.evict(self.limits, now))
+        };
+        self.record_evictions(evicted);
+        existed
+    }
+
+    /// Record the eviction of some statements, removing their files.
+    fn record_evictions(&self, evicted: Vec<(QueryId, EvictionReason)>) {
+        for (id, reason) in evicted {
+            debug!(query_id = id.as_str(), ?reason, "evicted query");
+            self.metrics.record_eviction(reason);
+            if let Some(file) = self.file(&id) {
+                // The file may not exist if persisting it failed.
+                tokio::spawn(async move {
+                    let _ = fs::remove_file(file).await;
+                });
+            }
+        }
+    }
+
+    /// Get the statement registered under `id` for `scope`, reading it from
+    /// disk if it was registered by a previous plugin process.
+    ///
+    /// Returns `None` if the statement was registered for a different
+    /// datasource or org.
+    pub async fn get(&self, id: &QueryId, scope: &QueryScope) -> Option<SelectStatement> {
+        let now = Instant::now();
+        let (found, evicted) = {
+            let mut entries = self.entries();
+            // Statements also expire here, not just when others are inserted,
+            // so they stop being served a TTL after they were last used.
+            let evicted = entries.evict(self.limits, now);
+            let found = match entries.by_id.get(id) {
+                Some(entry) if entry.scope != *scope => {
+                    warn!(
+                        query_id = id.as_str(),
+                        "query requested from another datasource or org"
+                    );
+                    Some(None)
+                }
+                Some(_) => Some(entries.touch(id, now).map(|entry| entry.statement.clone())),
+                None => None,
+            };
+            (found, evicted)
+        };
+        self.record_evictions(evicted);
+        if let Some(found) = found {
+            return found;
+        }
+        let file = self.file(id)?;
+        // An expired file may not have been removed yet, so it's not read back.
+        let modified = fs::metadata(&file).await.ok()?.modified().ok()?;
+        if self.expired(modified) {
+            return None;
+        }
+        let bytes = fs::read(file).await.ok()?;
+        let stored: Persisted = serde_json::from_slice(&bytes)
+            .map_err(|e| warn!(error = %e, "ignoring invalid persisted query"))
+            .ok()?;
+        // Anyone able to write to the directory could have written the file,
+        // so it's validated like any other statement from outside the plugin.
+        let statement = stored
+            .statement
+            .into_validated_statement()
+            .map_err(|e| warn!(error = %e, "ignoring invalid persisted query"))
+            .ok()?;
+        // Checking the ID also rejects files written with a different key.
+        if stored.scope != *scope || self.query_id(scope, &statement) != *id {
+            warn!(
+                query_id = id.as_str(),
+                "persisted query doesn't match request"
+            );
+            return None;
+        }
+        debug!(query_id = id.as_str(), "restored persisted query");
+        self.cache(id.clone(), stored.scope, statement.clone());
+        Some(statement)
+    }
+
+    /// Pin the statement registered under `id`, so it isn't evicted until the
+    /// returned guard is dropped.
+    ///
+    /// While pinned, the statement's file is touched regularly so it isn't
+    /// pruned as expired if the plugin restarts.
+    ///
+    /// Returns `None` if no statement is registered under `id`.
+    pub fn pin(&self, id: &QueryId) -> Option<PinnedQuery> {
+        if !self.entries().pin(id) {
+            return None;
+        }
+        let interval = self.limits.ttl / 2;
+        let keepalive = self.file(id).map(|file| {
+            tokio::spawn(async move {
+                loop {
+                    let touched = file.clone();
+                    let _ = tokio::task::spawn_blocking(move || {
+                        std::fs::File::options()
+                            .write(true)
+                            .open(touched)?
+                            .set_modified(SystemTime::now())
+                    })
+                    .await;
+                    tokio::time::sleep(interval).await;
+                }
+            })
+        });
+        Some(PinnedQuery {
+            entries: Arc::clone(&self.entries),
+            id: id.clone(),
+            keepalive,
+        })
+    }
+
+    /// The number of statements held in memory, once any which have expired
+    /// are evicted.
+    pub fn len(&self) -> usize {
+        let (len, evicted) = {
+            let mut entries = self.entries();
+            let evicted = entries.evict(self.limits, Instant::now());
+            (entries.len(), evicted)
+        };
+        self.record_evictions(evicted);
+        len
+    }
+
+    /// Whether a persisted statement last modified at `modified` has expired.
+    fn expired(&self, modified: SystemTime) -> bool {
+        SystemTime::now()
+            .duration_since(modified)
+            .is_ok_and(|age| age > self.limits.ttl)
+    }
+
+    /// Remove persisted statements which haven't been used for longer than the TTL.
+    fn prune_persisted(&self) {
+        let Some(files) = self
+            .dir
+            .as_ref()
+            .and_then(|dir| std::fs::read_dir(dir).ok())
+        else {
+            return;
+        };
+        for file in files.filter_map(|f| f.ok()) {
+            if file
+                .metadata()
+                .and_then(|m| m.modified())
+                .is_ok_and(|modified| self.expired(modified))
+            {
+                let _ = std::fs::remove_file(file.path());
+            }
+        }
+    }
+}
+
+/// A guard pinning a statement in the registry, returned by [`QueryRegistry::pin`].
+#[derive(Debug)]
+pub struct PinnedQuery {
+    entries: Arc<Mutex<Entries>>,
+    id: QueryId,
+    /// The task touching the statement's file, if it is persisted.
+    keepalive: Option<JoinHandle<()>>,
+}
+
+impl Drop for PinnedQuery {
+    fn drop(&mut self) {
+        if let Some(keepalive) = &self.keepalive {
+            keepalive.abort();
+        }
+        // The statement was in use until now, so it expires from now.
+        self.entries
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .unpin(&self.id, Instant::now());
+    }
+}
+
+/// Write `statement` to `file`, via a temporary file so that a partially
+/// written statement is never read.
+async fn write(
+    file: PathBuf,
+    scope: QueryScope,
+    statement: &SelectStatement,
+) -> std::io::Result<()> {
+    let stored = Persisted {
+        statement: StatementParts::from(statement),
+        scope,
+    };
+    if let Some(dir) = file.parent() {
+        fs::create_dir_all(dir).await?;
+    }
+    let tmp = file.with_extension("json.tmp");
+    fs::write(&tmp, serde_json::to_vec(&stored)?).await?;
+    fs::rename(tmp, file).await
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::params::ParamValue;
+
+    use super::*;
+
+    fn scope(uid: &str, org_id: i64) -> QueryScope {
+        QueryScope {
+            datasource_uid: uid.to_string(),
+            org_id,
+        }
+    }
+
+    fn statement(table: &str) -> SelectStatement {
+        format!("SELECT * FROM {table}").parse().unwrap()
+    }
+
+    #[test]
+    fn query_ids() {
+        let registry = QueryRegistry::default();
+        let id = registry.query_id(&scope("abc", 1), &statement("a"));
+        assert_eq!(id.as_str().len(), 64);
+        assert_eq!(registry.query_id(&scope("abc", 1), &statement("a")), id);
+        for (scope, statement) in [
+            (scope("abc", 2), statement("a")),
+            (scope("abd", 1), statement("a")),
+            (scope("abc", 1), statement("b")),
+        ] {
+            assert_ne!(registry.query_id(&scope, &statement), id);
+        }
+        // A different key gives different IDs.
+        assert_ne!(
+            QueryRegistry::default().query_id(&scope("abc", 1), &statement("a")),
+            id
+        );
+    }
+
+    #[tokio::test]
+    async fn scoped() {
+        let registry = QueryRegistry::default();
+        let id = registry.insert(scope("abc", 1), statement("a")).await;
+        assert_eq!(
+            registry.get(&id, &scope("abc", 1)).await,
+            Some(statement("a"))
+        );
+        assert_eq!(registry.get(&id, &scope("abc", 2)).await, None);
+        assert_eq!(registry.get(&id, &scope("xyz", 1)).await, None);
+    }
+
+    /// Statements expire a TTL after they were last used even if nothing else
+    /// is inserted, and aren't read back from disk once expired.
+    #[tokio::test]
+    async fn expires_without_inserts() {
+        let dir = data_dir("expires");
+        let metrics = Arc::new(Metrics::default());
+        let mut registry = QueryRegistry::persisted(dir.clone(), Arc::clone(&metrics));
+        registry.limits.ttl = Duration::from_millis(50);
+        let scope = scope("abc", 1);
+        let id = registry.insert(scope.clone(), statement("orders")).await;
+        assert!(registry.get(&id, &scope).await.is_some());
+        assert_eq!(registry.len(), 1);
+
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(registry.get(&id, &scope).await, None);
+        assert_eq!(registry.len(), 0);
+
+        // The gauge reported by `collect_metrics` doesn't count it either.
+        let other = registry.insert(scope.clone(), statement("customers")).await;
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(registry.len(), 0);
+        assert_eq!(registry.get(&other, &scope).await, None);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    /// A fresh data directory for a test.
+    fn data_dir(name: &str) -> PathBuf {
+        env::temp_dir().join(format!(
+            "materialize-registry-{name}-{}",
+            std::process::id()
+        ))
+    }
+
+    #[tokio::test]
+    async fn persisted() {
+        let dir = data_dir("persisted");
+        let statement = SelectStatement::from_parts(
+            "SELECT * FROM orders WHERE region = $1".to_string(),
+            vec![ParamValue::Single("eu".to_string())],
+        );
+
+        let registry = QueryRegistry::persisted(dir.clone(), Default::default());
+        let id = registry.insert(scope("abc", 1), statement.clone()).await;
+        assert_eq!(
+            registry.get(&id, &scope("abc", 1)).await,
+            Some(statement.clone())
+        );
+
+        // A new process finds the statement on disk, using the stored key.
+        let restarted = QueryRegistry::persisted(dir.clone(), Default::default());
+        assert_eq!(restarted.len(), 0);
+        assert_eq!(restarted.get(&id, &scope("abc", 2)).await, None);
+        assert_eq!(restarted.get(&id, &scope("abc", 1)).await, Some(statement));
+        assert_eq!(restarted.len(), 1);
+
+        assert_eq!(
+            restarted
+                .get(&QueryId::new("../secret".to_string()), &scope("abc", 1))
+                .await,
+            None
+        );
+        // Statements persisted with another key aren't trusted.
+        let rekeyed = QueryRegistry::new(
+            QueryKey::default(),
+            Some(dir.join("queries")),
+            Default::default(),
+        );
+        assert_eq!(rekeyed.get(&id, &scope("abc", 1)).await, None);
+        fs::remove_dir_all(dir).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn tampered() {
+        let dir = data_dir("tampered");
+        let registry = QueryRegistry::persisted(dir.clone(), Default::default());
+        let scope = scope("abc", 1);
+        let id = registry.insert(scope.clone(), statement("orders")).await;
+        let file = registry.file(&id).unwrap();
+        let restarted = || QueryRegistry::persisted(dir.clone(), Default::default());
+
+        // A statement changed on disk no longer matches its ID.
+        let original = fs::read_to_string(&file).await.unwrap();
+        fs::write(&file, original.replace("orders", "customers"))
+            .await
+            .unwrap();
+        assert_eq!(restarted().get(&id, &scope).await, None);
+
+        // A statement which isn't read-only is rejected, even with a valid ID.
+        let delete = SelectStatement::from_parts("DELETE FROM orders".to_string(), Vec::new());
+        let id = registry.query_id(&scope, &delete);
+        write(registry.file(&id).unwrap(), scope.clone(), &delete)
+            .await
+            .unwrap();
+        assert_eq!(restarted().get(&id, &scope).await, None);
+        fs::remove_dir_all(dir).await.unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn private_key() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = data_dir("private");
+        let key_file = dir.join("query.key");
+        let key = open_data_dir(&dir).unwrap();
+        let mode = |path: &Path| std::fs::metadata(path).unwrap().permissions().mode() & 0o777;
+        assert_eq!(mode(&dir), 0o700);
+        assert_eq!(mode(&key_file), 0o600);
+        assert_eq!(QueryKey::load_or_create(&key_file).unwrap().0, key.0);
+
+        // A key which others can read or write is refused.
+        std::fs::set_permissions(&key_file, std::fs::Permissions::from_mode(0o644)).unwrap();
+        assert!(QueryKey::load_or_create(&key_file).is_err());
+        let registry = QueryRegistry::persisted(dir.clone(), Default::default());
+        assert!(registry.dir.is_none());
+
+        // As is a directory which others can write to.
+        std::fs::set_permissions(&key_file, std::fs::Permissions::from_mode(0o600)).unwrap();
+        std::fs::set_permissions(&dir, std::fs::Permissions::from_mode(0o777)).unwrap();
+        assert!(open_data_dir(&dir).is_err());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn capacity() {
+        let registry = QueryRegistry::default().with_limits(RegistryLimits {
+            capacity: 2,
+            ..RegistryLimits::default()
+        });
+        let scope = scope("abc", 1);
+        let a = registry.insert(scope.clone(), statement("a")).await;
+        let b = registry.insert(scope.clone(), statement("b")).await;
+        // Using `a` makes `b` the least recently used.
+        assert!(registry.get(&a, &scope).await.is_some());
+        let c = registry.insert(scope.clone(), statement("c")).await;
+        assert_eq!(registry.len(), 2);
+        assert!(registry.get(&b, &scope).await.is_none());
+
+        // Pinned statements are kept even when the registry is full.
+        let pinned = registry.pin(&a).unwrap();
+        registry.insert(scope.clone(), statement("b")).await;
+        assert!(registry.get(&a, &scope).await.is_some());
+        assert!(registry.get(&c, &scope).await.is_none());
+        drop(pinned);
+        assert!(registry.pin(&c).is_none());
+    }
+
+    #[test]
+    fn expiry() {
+        let limits = RegistryLimits {
+            capacity: 10,
+            ttl: Duration::from_secs(60),
+        };
+        let start = Instant::now();
+        let entry = |table| Entry {
+            statement: statement(table),
+            scope: scope("abc", 1),
+            last_used: start,
+            pins: 0,
+        };
+        let id = |s: &str| QueryId::new(s.to_string());
+        let mut entries = Entries::default();
+        entries.insert(id("a"), entry("a"));
+        entries.insert(id("b"), entry("b"));
+        assert!(entries.pin(&id("b")));
+        assert!(entries
+            .evict(limits, start + Duration::from_secs(30))
+            .is_empty());
+        assert_eq!(
+            entries.evict(limits, start + Duration::from_secs(90)),
+            vec![(id("a"), EvictionReason::Expired)]
+        );
+        // Pinned statements never expire.
+        assert_eq!(entries.len(), 1);
+        // Once unpinned, a statement expires a TTL after it was last used.
+        entries.unpin(&id("b"), start + Duration::from_secs(90));
+        assert!(entries
+            .evict(limits, start + Duration::from_secs(120))
+            .is_empty());
+        assert_eq!(
+            entries.evict(limits, start + Duration::from_secs(151)),
+            vec![(id("b"), EvictionReason::Expired)]
+        );
+        assert!(entries.lru.is_empty());
+    }
+
+    /// A pinned statement survives both capacity and TTL eviction, while the
+    /// others are evicted least recently used first.
+    #[test]
+    fn pinned_survives_eviction() {
+        let limits = RegistryLimits {
+            capacity: 2,
+            ttl: Duration::from_secs(60),
+        };
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+        let id = |s: &str| QueryId::new(s.to_string());
+        let mut entries = Entries::default();
+        let insert = |entries: &mut Entries, table: &str, secs| {
+            entries.insert(
+                id(table),
+                Entry {
+                    statement: statement(table),
+                    scope: scope("abc", 1),
+                    last_used: at(secs),
+                    pins: 0,
+                },
+            );
+            entries.evict(limits, at(secs))
+        };
+        assert!(insert(&mut entries, "pinned", 0).is_empty());
+        assert!(entries.pin(&id("pinned")));
+        assert!(insert(&mut entries, "a", 1).is_empty());
+        assert_eq!(
+            insert(&mut entries, "b", 2),
+            vec![(id("a"), EvictionReason::Capacity)]
+        );
+        assert!(entries.touch(&id("b"), at(3)).is_some());
+        assert_eq!(
+            insert(&mut entries, "c", 4),
+            vec![(id("b"), EvictionReason::Capacity)]
+        );
+        // Long after the pinned statement was last used, only `c` expires.
+        assert_eq!(
+            entries.evict(limits, at(1000)),
+            vec![(id("c"), EvictionReason::Expired)]
+        );
+        assert_eq!(entries.len(), 1);
+        assert!(entries.by_id.contains_key(&id("pinned")));
+    }
+}
@@ -1,16 +1,53 @@
 //! The 'resource' service, which responds to arbitrary HTTP requests from the plugin.
 //!
-//! In practice, the only path handled is /relations, which returns a JSON array containing
-//! the list of relations present in the Materialize database, useful for populating a dropdown
-//! of potential `TAIL` options.
+//! The paths handled, each of which returns a JSON array, are:
+//!
+//! - `/relations`: the relations present in the Materialize database along with their kind,
+//!   useful for populating a dropdown of potential `TAIL` options. Relations can be filtered
+//!   using the `schema` and `kind` query parameters, and `format=names` returns a plain array
+//!   of relation names instead.
+//! - `/databases`: the databases present in the Materialize instance.
+//! - `/schemas`: the schemas present, optionally filtered by the `database` query parameter.
+//! - `/clusters`: the clusters present in the Materialize instance.
+//! - `/columns`: the columns of the relation given in the `relation` query parameter.
+//! - `/tag-keys`: `text` objects naming the columns of the relation given in the `relation`
+//!   query parameter, used as ad-hoc filter keys.
+//! - `/tag-values`: `text` objects holding distinct values of the column given in the `key`
+//!   query parameter, in the relation given in the `relation` parameter, used as ad-hoc
+//!   filter values.
+//! - `/explain`: the lines of the plan used to compute the relation given in the `relation`
+//!   query parameter, or the statement given in the `statement` parameter. The `plan`
+//!   parameter chooses between the `optimized` (default) and `physical` plans. Statements
+//!   are templated in the same way as for `/variable`.
+//! - `/variable`: `text`/`value` pairs for a template variable, produced by running the
+//!   read-only statement given in the `statement` query parameter. Macros are expanded
+//!   using the time range given by the `from` and `to` parameters, in epoch milliseconds,
+//!   and placeholders are bound to the JSON object of values in the `variables` parameter.
+//!
+//! Each path is handled by a [`Route`], which delegates to a handler function taking
+//! a client and the request's query string.
 
 use bytes::Bytes;
 use futures_util::stream;
 use grafana_plugin_sdk::backend;
 use http::{Response, StatusCode};
-use serde::Serialize;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_with::{json::JsonString, serde_as};
+use tracing::{warn, Instrument};
 
-use crate::{Error, MaterializePlugin};
+use chrono::prelude::*;
+
+use crate::{
+    catalog::{Catalog, RelationKind},
+    explain::{ExplainPlan, Explainer},
+    macros::MacroContext,
+    panics::catch_panics,
+    params::Variables,
+    queries::{SelectStatement, SourceName, TailTarget},
+    trace::{request_span, TraceContext},
+    variable::VariableSource,
+    Error, MaterializePlugin,
+};
 
 #[derive(Debug, thiserror::Error)]
 pub enum ResourceError {
@@ -25,6 +62,9 @@ pub enum ResourceError {
 
     #[error("Invalid datasource settings")]
     InvalidDatasourceSettings(#[from] serde_json::Error),
+
+    #[error("Invalid query parameters: {0}")]
+    InvalidQueryParams(#[from] serde_urlencoded::de::Error),
 }
 
 #[derive(Debug, Serialize)]
@@ -36,10 +76,10 @@ impl backend::ErrIntoHttpResponse for ResourceError {
     fn into_http_response(self) -> Result<Response<Bytes>, Box<dyn std::error::Error>> {
         let status = match self {
             Self::NotFound => StatusCode::NOT_FOUND,
-            Self::Plugin(_) => StatusCode::INTERNAL_SERVER_ERROR,
-            Self::MissingDatasourceSettings | Self::InvalidDatasourceSettings(_) => {
-                StatusCode::BAD_REQUEST
-            }
+            Self::Plugin(ref e) => e.status(),
+            Self::MissingDatasourceSettings
+            | Self::InvalidDatasourceSettings(_)
+            | Self::InvalidQueryParams(_) => StatusCode::BAD_REQUEST,
         };
         Ok(Response::builder().status(status).body(Bytes::from(
             serde_json::to_vec(&JsonError {
@@ -50,6 +90,283 @@ impl backend::ErrIntoHttpResponse for ResourceError {
     }
 }
 
+/// The resource paths served by the plugin.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Route {
+    Relations,
+    Databases,
+    Schemas,
+    Clusters,
+    Columns,
+    TagKeys,
+    TagValues,
+    Explain,
+    Variable,
+}
+
+impl Route {
+    /// Find the route matching a request path, if any.
+    fn from_path(path: &str) -> Option<Self> {
+        match path {
+            "/relations" => Some(Self::Relations),
+            "/databases" => Some(Self::Databases),
+            "/schemas" => Some(Self::Schemas),
+            "/clusters" => Some(Self::Clusters),
+            "/columns" => Some(Self::Columns),
+            "/tag-keys" => Some(Self::TagKeys),
+            "/tag-values" => Some(Self::TagValues),
+            "/explain" => Some(Self::Explain),
+            "/variable" => Some(Self::Variable),
+            _ => None,
+        }
+    }
+
+    /// Handle a request for this route, returning the JSON response body.
+    async fn handle<C: Catalog + Explainer + VariableSource + Sync>(
+        self,
+        client: &C,
+        query: &str,
+    ) -> Result<Vec<u8>, ResourceError> {
+        match self {
+            Self::Relations => relations(client, parse_params(query)?).await,
+            Self::Databases => databases(client).await,
+            Self::Schemas => schemas(client, parse_params(query)?).await,
+            Self::Clusters => clusters(client).await,
+            Self::Columns => columns(client, parse_params(query)?).await,
+            Self::TagKeys => tag_keys(client, parse_params(query)?).await,
+            Self::TagValues => tag_values(client, parse_params(query)?).await,
+            Self::Explain => explain(client, parse_params(query)?).await,
+            Self::Variable => variable(client, parse_params(query)?).await,
+        }
+    }
+}
+
+fn parse_params<T: DeserializeOwned>(query: &str) -> Result<T, ResourceError> {
+    Ok(serde_urlencoded::from_str(query)?)
+}
+
+fn to_json<T: Serialize>(value: &T) -> Vec<u8> {
+    serde_json::to_vec(value).expect("valid JSON")
+}
+
+/// The format of the `/relations` response.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "camelCase")]
+enum RelationsFormat {
+    /// A JSON array of [`Relation`] objects.
+    ///
+    /// [`Relation`]: crate::catalog::Relation
+    #[default]
+    Objects,
+    /// A JSON array of distinct, unqualified relation names.
+    ///
+    /// This is the response format used before relation metadata was
+    /// available, and is still used by `metricFindQuery` in the frontend.
+    Names,
+}
+
+/// Query parameters accepted by the `/relations` resource.
+#[derive(Debug, Default, Deserialize)]
+struct RelationsParams {
+    /// Only return relations in schemas with this name.
+    schema: Option<String>,
+    /// Only return relations of this kind.
+    kind: Option<RelationKind>,
+    #[serde(default)]
+    format: RelationsFormat,
+}
+
+async fn relations<C: Catalog + Sync>(
+    catalog: &C,
+    params: RelationsParams,
+) -> Result<Vec<u8>, ResourceError> {
+    let relations = catalog
+        .relations(params.schema.as_deref(), params.kind)
+        .await?;
+    Ok(match params.format {
+        RelationsFormat::Objects => to_json(&relations),
+        RelationsFormat::Names => {
+            let mut names: Vec<&str> = relations.iter().map(|r| r.name.as_str()).collect();
+            names.sort_unstable();
+            names.dedup();
+            to_json(&names)
+        }
+    })
+}
+
+async fn databases<C: Catalog + Sync>(catalog: &C) -> Result<Vec<u8>, ResourceError> {
+    Ok(to_json(&catalog.databases().await?))
+}
+
+/// Query parameters accepted by the `/schemas` resource.
+#[derive(Debug, Default, Deserialize)]
+struct SchemasParams {
+    /// Only return schemas in the database with this name.
+    database: Option<String>,
+}
+
+async fn schemas<C: Catalog + Sync>(
+    catalog: &C,
+    params: SchemasParams,
+) -> Result<Vec<u8>, ResourceError> {
+    Ok(to_json(&catalog.schemas(params.database.as_deref()).await?))
+}
+
+async fn clusters<C: Catalog + Sync>(catalog: &C) -> Result<Vec<u8>, ResourceError> {
+    Ok(to_json(&catalog.clusters().await?))
+}
+
+/// Query parameters accepted by the `/columns` resource.
+#[derive(Debug, Deserialize)]
+struct ColumnsParams {
+    /// The relation whose columns should be returned.
+    relation: SourceName,
+}
+
+async fn columns<C: Catalog + Sync>(
+    catalog: &C,
+    params: ColumnsParams,
+) -> Result<Vec<u8>, ResourceError> {
+    Ok(to_json(&catalog.columns(&params.relation).await?))
+}
+
+/// The maximum number of values returned by `/tag-values`.
+const TAG_VALUES_LIMIT: i64 = 1000;
+
+/// A suggested ad-hoc filter key or value, in the shape Grafana expects.
+#[derive(Debug, Serialize)]
+struct Tag {
+    text: String,
+}
+
+async fn tag_keys<C: Catalog + Sync>(
+    catalog: &C,
+    params: ColumnsParams,
+) -> Result<Vec<u8>, ResourceError> {
+    let keys: Vec<_> = catalog
+        .columns(&params.relation)
+        .await?
+        .into_iter()
+        .map(|column| Tag { text: column.name })
+        .collect();
+    Ok(to_json(&keys))
+}
+
+/// Query parameters accepted by the `/tag-values` resource.
+#[derive(Debug, Deserialize)]
+struct TagValuesParams {
+    /// The relation whose values should be returned.
+    relation: SourceName,
+    /// The column whose values should be returned.
+    key: String,
+}
+
+async fn tag_values<C: Catalog + Sync>(
+    catalog: &C,
+    params: TagValuesParams,
+) -> Result<Vec<u8>, ResourceError> {
+    let columns = catalog.columns(&params.relation).await?;
+    if !columns.iter().any(|column| column.name == params.key) {
+        return Err(Error::InvalidFilter(format!(
+            "{} is not a column of {}",
+            params.key, params.relation
+        ))
+        .into());
+    }
+    let values: Vec<_> = catalog
+        .column_values(&params.relation, &params.key, TAG_VALUES_LIMIT)
+        .await?
+        .into_iter()
+        .map(|text| Tag { text })
+        .collect();
+    Ok(to_json(&values))
+}
+
+/// Query parameters accepted by the `/variable` resource.
+#[serde_as]
+#[derive(Debug, Deserialize)]
+struct VariableParams {
+    /// The statement producing the variable's options.
+    statement: SelectStatement,
+    /// The values of other template variables, as a JSON object.
+    #[serde_as(as = "JsonString")]
+    #[serde(default)]
+    variables: Variables,
+    /// The start of the dashboard time range, in milliseconds since the epoch.
+    ///
+    /// Defaults to the current time.
+    from: Option<i64>,
+    /// The end of the dashboard time range, in milliseconds since the epoch.
+    ///
+    /// Defaults to the current time.
+    to: Option<i64>,
+}
+
+/// Create a macro context from optional `from` and `to` query parameters, given
+/// in milliseconds since the epoch and defaulting to the current time.
+fn macro_context(from: Option<i64>, to: Option<i64>) -> MacroContext {
+    let now = Utc::now();
+    let timestamp = |ms: Option<i64>| {
+        ms.and_then(|ms| Utc.timestamp_millis_opt(ms).single())
+            .unwrap_or(now)
+    };
+    MacroContext::new(timestamp(from), timestamp(to), Default::default())
+}
+
+async fn variable<C: VariableSource + Sync>(
+    source: &C,
+    params: VariableParams,
+) -> Result<Vec<u8>, ResourceError> {
+    let statement = params
+        .statement
+        .expand_macros(&macro_context(params.from, params.to))?
+        .bind(&params.variables)?;
+    Ok(to_json(&source.variable_values(&statement).await?))
+}
+
+/// Query parameters accepted by the `/explain` resource.
+///
+/// Exactly one of `relation` and `statement` must be given.
+#[serde_as]
+#[derive(Debug, Deserialize)]
+struct ExplainParams {
+    /// The relation to explain.
+    relation: Option<SourceName>,
+    /// The statement to explain.
+    statement: Option<SelectStatement>,
+    #[serde(default)]
+    plan: ExplainPlan,
+    /// The values of template variables, as a JSON object.
+    #[serde_as(as = "JsonString")]
+    #[serde(default)]
+    variables: Variables,
+    /// The start of the dashboard time range, in milliseconds since the epoch.
+    from: Option<i64>,
+    /// The end of the dashboard time range, in milliseconds since the epoch.
+    to: Option<i64>,
+}
+
+async fn explain<C: Explainer + Sync>(
+    explainer: &C,
+    params: ExplainParams,
+) -> Result<Vec<u8>, ResourceError> {
+    let target = match (params.relation, params.statement) {
+        (Some(name), None) => TailTarget::Relation { name },
+        (None, Some(statement)) => TailTarget::Select {
+            statement: statement
+                .expand_macros(&macro_context(params.from, params.to))?
+                .bind(&params.variables)?,
+        },
+        _ => {
+            return Err(Error::InvalidTailTarget(
+                "exactly one of relation and statement must be given".to_string(),
+            )
+            .into())
+        }
+    };
+    Ok(to_json(&explainer.explain(&target, params.plan).await?))
+}
+
 #[backend::async_trait]
 impl backend::ResourceService for MaterializePlugin {
     type Error = ResourceError;
@@ -62,33 +379,367 @@ impl backend::ResourceService for MaterializePlugin {
         &self,
         request: backend::CallResourceRequest,
     ) -> Result<(Self::InitialResponse, Self::Stream), Self::Error> {
-        // We only serve relations for now.
-        if request.request.uri().path() != "/relations" {
-            return Err(ResourceError::NotFound);
+        let trace = TraceContext::from_headers(
+            request
+                .request
+                .headers()
+                .iter()
+                .filter_map(|(name, value)| Some((name.as_str(), value.to_str().ok()?))),
+        );
+        let span = request_span(
+            "call_resource",
+            request.plugin_context.as_ref(),
+            trace.as_ref(),
+        );
+        span.record("path", &request.request.uri().path());
+        catch_panics(async move {
+            let route =
+                Route::from_path(request.request.uri().path()).ok_or(ResourceError::NotFound)?;
+            let datasource_settings = request
+                .plugin_context
+                .and_then(|pc| pc.datasource_instance_settings)
+                .ok_or(ResourceError::MissingDatasourceSettings)?;
+            let client = self.get_client(&datasource_settings).await?;
+
+            let body = route
+                .handle(&client, request.request.uri().query().unwrap_or_default())
+                .await
+                .map_err(|e| {
+                    warn!(error = %e, "resource request failed");
+                    e
+                })?;
+            let initial_response = Response::new(Bytes::from(body));
+            Ok((initial_response, Box::pin(stream::empty()) as Self::Stream))
+        })
+        .instrument(span)
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use grafana_plugin_sdk::{backend::ResourceService, pluginv2};
+    use serde_json::{json, Value};
+
+    use crate::{
+        catalog::{Cluster, Column, Database, Relation, Schema},
+        graph::ObjectGraph,
+        variable::VariableValue,
+        Result as PluginResult,
+    };
+
+    use super::*;
+
+    /// A catalog returning canned data, for testing handlers.
+    struct StubCatalog;
+
+    #[backend::async_trait]
+    impl Catalog for StubCatalog {
+        async fn relations(
+            &self,
+            schema: Option<&str>,
+            kind: Option<RelationKind>,
+        ) -> PluginResult<Vec<Relation>> {
+            Ok([
+                ("public", "orders", RelationKind::Table),
+                ("public", "orders_by_region", RelationKind::MaterializedView),
+                ("staging", "orders", RelationKind::View),
+            ]
+            .into_iter()
+            .filter(|(s, _, k)| schema.is_none_or(|x| x == *s) && kind.is_none_or(|x| x == *k))
+            .map(|(s, n, k)| {
+                Relation::new("materialize".to_string(), s.to_string(), n.to_string(), k)
+            })
+            .collect())
+        }
+
+        async fn databases(&self) -> PluginResult<Vec<Database>> {
+            Ok(vec![Database {
+                name: "materialize".to_string(),
+            }])
+        }
+
+        async fn schemas(&self, database: Option<&str>) -> PluginResult<Vec<Schema>> {
+            Ok(["public", "staging"]
+                .into_iter()
+                .filter(|_| database.is_none_or(|d| d == "materialize"))
+                .map(|name| Schema {
+                    database: "materialize".to_string(),
+                    name: name.to_string(),
+                })
+                .collect())
+        }
+
+        async fn clusters(&self) -> PluginResult<Vec<Cluster>> {
+            Ok(vec![Cluster {
+                name: "default".to_string(),
+            }])
+        }
+
+        async fn columns(&self, relation: &SourceName) -> PluginResult<Vec<Column>> {
+            if relation.name() != "orders" {
+                return Err(Error::TailTargetNotFound(relation.to_string()));
+            }
+            Ok(vec![
+                Column {
+                    name: "id".to_string(),
+                    sql_type: "bigint".to_string(),
+                    nullable: false,
+                    field_type: "number",
+                },
+                Column {
+                    name: "region".to_string(),
+                    sql_type: "text".to_string(),
+                    nullable: true,
+                    field_type: "string",
+                },
+            ])
+        }
+
+        async fn column_values(
+            &self,
+            relation: &SourceName,
+            column: &str,
+            limit: i64,
+        ) -> PluginResult<Vec<String>> {
+            Ok(["eu", "us"]
+                .into_iter()
+                .map(|region| format!("{relation}.{column}={region}"))
+                .take(limit as usize)
+                .collect())
         }
-        let datasource_settings = request
-            .plugin_context
-            .and_then(|pc| pc.datasource_instance_settings)
-            .ok_or(ResourceError::MissingDatasourceSettings)?;
-        let client = self.get_client(&datasource_settings).await?;
-
-        let rows = client
-            .query(
-                r#"
-            SELECT DISTINCT mzr.name AS name
-            FROM mz_catalog.mz_relations mzr
-            JOIN mz_catalog.mz_schemas mzs ON mzr.schema_id = mzs.id
-            WHERE database_id IS NOT NULL
-            ORDER BY mzr.name
-        "#,
-                &[],
+
+        async fn object_graph(&self, _stats: bool) -> PluginResult<ObjectGraph> {
+            Ok(ObjectGraph::default())
+        }
+    }
+
+    #[backend::async_trait]
+    impl Explainer for StubCatalog {
+        async fn explain(
+            &self,
+            target: &TailTarget,
+            plan: ExplainPlan,
+        ) -> PluginResult<Vec<String>> {
+            // Echo the target's SQL back so tests can check templating.
+            let (sql, params) = target.select_sql();
+            Ok(vec![
+                format!("{plan:?}"),
+                sql,
+                serde_json::to_string(params).unwrap(),
+            ])
+        }
+    }
+
+    #[backend::async_trait]
+    impl VariableSource for StubCatalog {
+        async fn variable_values(
+            &self,
+            statement: &SelectStatement,
+        ) -> PluginResult<Vec<VariableValue>> {
+            // Echo the executed statement back so tests can check macro expansion
+            // and parameter binding.
+            Ok(vec![VariableValue {
+                text: statement.to_string(),
+                value: serde_json::to_string(statement.params()).unwrap(),
+            }])
+        }
+    }
+
+    async fn get(path: &str, query: &str) -> Result<Value, ResourceError> {
+        let route = Route::from_path(path).ok_or(ResourceError::NotFound)?;
+        let body = route.handle(&StubCatalog, query).await?;
+        Ok(serde_json::from_slice(&body).unwrap())
+    }
+
+    #[tokio::test]
+    async fn relations() {
+        assert_eq!(
+            get("/relations", "schema=staging").await.unwrap(),
+            json!([{
+                "database": "materialize",
+                "schema": "staging",
+                "name": "orders",
+                "fullyQualifiedName": r#""materialize"."staging"."orders""#,
+                "kind": "view",
+            }])
+        );
+        assert_eq!(
+            get("/relations", "kind=materializedView").await.unwrap()[0]["name"],
+            "orders_by_region"
+        );
+        assert_eq!(
+            get("/relations", "format=names").await.unwrap(),
+            json!(["orders", "orders_by_region"])
+        );
+        assert!(matches!(
+            get("/relations", "kind=secret").await,
+            Err(ResourceError::InvalidQueryParams(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn databases_schemas_clusters() {
+        assert_eq!(
+            get("/databases", "").await.unwrap(),
+            json!([{"name": "materialize"}])
+        );
+        assert_eq!(
+            get("/schemas", "database=materialize").await.unwrap(),
+            json!([
+                {"database": "materialize", "name": "public"},
+                {"database": "materialize", "name": "staging"},
+            ])
+        );
+        assert_eq!(get("/schemas", "database=other").await.unwrap(), json!([]));
+        assert_eq!(
+            get("/clusters", "").await.unwrap(),
+            json!([{"name": "default"}])
+        );
+    }
+
+    #[tokio::test]
+    async fn columns() {
+        assert_eq!(
+            get("/columns", "relation=public.orders").await.unwrap(),
+            json!([
+                {"name": "id", "sqlType": "bigint", "nullable": false, "fieldType": "number"},
+                {"name": "region", "sqlType": "text", "nullable": true, "fieldType": "string"},
+            ])
+        );
+        assert!(matches!(
+            get("/columns", "").await,
+            Err(ResourceError::InvalidQueryParams(_))
+        ));
+        assert!(matches!(
+            get("/columns", "relation=missing").await,
+            Err(ResourceError::Plugin(Error::TailTargetNotFound(_)))
+        ));
+    }
+
+    #[tokio::test]
+    async fn tags() {
+        assert_eq!(
+            get("/tag-keys", "relation=orders").await.unwrap(),
+            json!([{"text": "id"}, {"text": "region"}])
+        );
+        assert_eq!(
+            get("/tag-values", "relation=orders&key=region")
+                .await
+                .unwrap(),
+            json!([
+                {"text": r#""orders".region=eu"#},
+                {"text": r#""orders".region=us"#},
+            ])
+        );
+        assert!(matches!(
+            get("/tag-values", "relation=orders&key=secret").await,
+            Err(ResourceError::Plugin(Error::InvalidFilter(_)))
+        ));
+        assert!(matches!(
+            get("/tag-values", "relation=orders").await,
+            Err(ResourceError::InvalidQueryParams(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn variable() {
+        assert_eq!(
+            get(
+                "/variable",
+                "statement=SELECT+region+FROM+orders+WHERE+%24__timeFilter%28ts%29&from=0&to=1000"
+            )
+            .await
+            .unwrap(),
+            json!([{
+                "text": "SELECT region FROM orders WHERE (ts BETWEEN '1970-01-01T00:00:00.000Z'::timestamptz AND '1970-01-01T00:00:01.000Z'::timestamptz)",
+                "value": "[]",
+            }])
+        );
+        assert_eq!(
+            get(
+                "/variable",
+                "statement=SELECT+host+FROM+hosts+WHERE+region+%3D+ANY%28%24region%29&variables=%7B%22region%22%3A%5B%22eu%22%5D%7D"
             )
             .await
-            .map_err(Error::Connection)?;
-        let names: Vec<&str> = rows.iter().map(|row| row.get("name")).collect();
+            .unwrap(),
+            json!([{
+                "text": "SELECT host FROM hosts WHERE region = ANY($1)",
+                "value": r#"[["eu"]]"#,
+            }])
+        );
+        assert!(matches!(
+            get("/variable", "statement=DROP+VIEW+orders").await,
+            Err(ResourceError::InvalidQueryParams(_))
+        ));
+    }
 
-        let initial_response =
-            Response::new(Bytes::from(serde_json::to_vec(&names).expect("valid JSON")));
-        Ok((initial_response, Box::pin(stream::empty())))
+    #[tokio::test]
+    async fn explain() {
+        assert_eq!(
+            get("/explain", "relation=orders").await.unwrap(),
+            json!(["Optimized", r#"SELECT * FROM "orders""#, "[]"])
+        );
+        assert_eq!(
+            get(
+                "/explain",
+                "plan=physical&statement=SELECT+*+FROM+orders+WHERE+region+%3D+%24region&variables=%7B%22region%22%3A%22eu%22%7D"
+            )
+            .await
+            .unwrap(),
+            json!(["Physical", "SELECT * FROM orders WHERE region = $1", r#"["eu"]"#])
+        );
+        assert!(matches!(
+            get("/explain", "").await,
+            Err(ResourceError::Plugin(Error::InvalidTailTarget(_)))
+        ));
+    }
+
+    #[tokio::test]
+    async fn not_found() {
+        assert!(matches!(
+            get("/unknown", "").await,
+            Err(ResourceError::NotFound)
+        ));
+    }
+
+    #[tokio::test]
+    async fn malformed_requests() {
+        let call = |path: &str, settings: Option<&[u8]>| {
+            let request = pluginv2::CallResourceRequest {
+                plugin_context: Some(pluginv2::PluginContext {
+                    data_source_instance_settings: settings.map(|json| {
+                        pluginv2::DataSourceInstanceSettings {
+                            json_data: json.to_vec(),
+                            ..Default::default()
+                        }
+                    }),
+                    ..Default::default()
+                }),
+                // Grafana sends the URL without its leading slash.
+                path: path.to_string(),
+                method: "GET".to_string(),
+                url: path.to_string(),
+                ..Default::default()
+            };
+            async move {
+                MaterializePlugin::default()
+                    .call_resource(request.try_into().unwrap())
+                    .await
+                    .map(|_| ())
+            }
+        };
+        assert!(matches!(
+            call("unknown", Some(b"{}")).await,
+            Err(ResourceError::NotFound)
+        ));
+        assert!(matches!(
+            call("databases", None).await,
+            Err(ResourceError::MissingDatasourceSettings)
+        ));
+        assert!(matches!(
+            call("databases", Some(b"{}")).await,
+            Err(ResourceError::Plugin(Error::InvalidDatasourceSettings(_)))
+        ));
     }
 }
@@ -0,0 +1,411 @@
+//! Parsing and validation of user-provided SQL.
+//!
+//! Statements entered in the query editor are run as the datasource user,
+//! so we parse them up-front and only accept a single read-only query.
+
+use sqlparser::{
+    ast::{Query, SetExpr, Statement},
+    dialect::PostgreSqlDialect,
+    keywords::Keyword,
+    parser::Parser,
+    tokenizer::{Location, Token, Tokenizer},
+};
+
+use crate::{Error, Result};
+
+/// Find the first position in `s` where a quoted section of SQL, a comment or
+/// a `$` may begin.
+///
+/// Used with [`quoted_len`] when scanning statements for macros and placeholders,
+/// which shouldn't be replaced inside quotes or comments.
+pub fn next_special(s: &str) -> Option<usize> {
+    let mut prev = None;
+    let mut chars = s.char_indices().peekable();
+    while let Some((i, c)) = chars.next() {
+        let next = chars.peek().map(|(_, c)| *c);
+        match (c, next) {
+            ('\'' | '"' | '$', _) | ('-', Some('-')) | ('/', Some('*')) => return Some(i),
+            // An escape string, unless the `E` ends an identifier.
+            ('E' | 'e', Some('\''))
+                if !prev.is_some_and(|p: char| p.is_alphanumeric() || p == '_') =>
+            {
+                return Some(i)
+            }
+            _ => prev = Some(c),
+        }
+    }
+    None
+}
+
+/// If `s` begins with a section of SQL which can't contain macros or placeholders
+/// (a string literal, escape string, quoted identifier, dollar-quoted string or
+/// comment), return its length including any delimiters.
+///
+/// Unterminated sections extend to the end of `s`.
+pub fn quoted_len(s: &str) -> Option<usize> {
+    if let Some(comment) = s.strip_prefix("--") {
+        return Some(comment.find('\n').map_or(s.len(), |i| i + 3));
+    }
+    if s.starts_with("/*") {
+        return Some(block_comment_len(s));
+    }
+    if let Some(literal) = s.strip_prefix(['E', 'e']) {
+        return literal
+            .starts_with('\'')
+            .then(|| escape_string_len(literal) + 1);
+    }
+    let quote = if s.starts_with(['\'', '"']) {
+        &s[..1]
+    } else {
+        // Dollar quotes look like `$$` or `$tag$`, where the tag can't begin with a digit.
+        let after = s.strip_prefix('$')?;
+        let tag_len = after
+            .find(|c: char| !(c.is_alphanumeric() || c == '_'))
+            .unwrap_or(after.len());
+        if after[tag_len..].starts_with('$') && !after.starts_with(|c: char| c.is_ascii_digit()) {
+            &s[..tag_len + 2]
+        } else {
+            return None;
+        }
+    };
+    // Doubled quotes inside literals are escapes, which this handles naturally
+    // as two adjacent quoted sections.
+    Some(
+        s[quote.len()..]
+            .find(quote)
+            .map_or(s.len(), |i| i + 2 * quote.len()),
+    )
+}
+
+/// The length of the block comment at the start of `s`, which may be nested.
+fn block_comment_len(s: &str) -> usize {
+    let mut depth = 0;
+    let mut i = 0;
+    while i < s.len() {
+        if s[i..].starts_with("/*") {
+            depth += 1;
+            i += 2;
+        } else if s[i..].starts_with("*/") {
+            depth -= 1;
+            i += 2;
+            if depth == 0 {
+                return i;
+            }
+        } else {
+            i += s[i..].chars().next().map_or(1, char::len_utf8);
+        }
+    }
+    s.len()
+}
+
+/// The length of the escape string literal at the start of `s`, without its
+/// `E` prefix, in which a backslash escapes the following character.
+fn escape_string_len(s: &str) -> usize {
+    let mut chars = s.char_indices().skip(1);
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '\\' => {
+                chars.next();
+            }
+            // A doubled quote is an escape, like in other literals.
+            '\'' if s[i + 1..].starts_with('\'') => {
+                chars.next();
+            }
+            '\'' => return i + 1,
+            _ => {}
+        }
+    }
+    s.len()
+}
+
+/// Quote `ident` as a SQL identifier, escaping any embedded double quotes.
+pub fn quote_ident(ident: &str) -> String {
+    format!("\"{}\"", ident.replace('"', "\"\""))
+}
+
+/// Parse a possibly qualified object name, such as `db.schema."My View"`,
+/// into its parts.
+///
+/// Unquoted parts are folded to lowercase, matching how the server resolves them.
+pub fn parse_object_name(s: &str) -> Result<Vec<String>> {
+    let invalid = |e: &dyn std::fmt::Display| {
+        Error::InvalidTailTarget(format!("Invalid relation name {s}: {e}"))
+    };
+    let dialect = PostgreSqlDialect {};
+    let mut parser = Parser::new(&dialect)
+        .try_with_sql(s)
+        .map_err(|e| invalid(&e))?;
+    let name = parser.parse_object_name(false).map_err(|e| invalid(&e))?;
+    let next = parser.peek_token();
+    if next != Token::EOF {
+        return Err(invalid(&format_args!(
+            "unexpected {}{}",
+            next.token, next.span.start
+        )));
+    }
+    Ok(name
+        .0
+        .into_iter()
+        .map(|ident| match ident.quote_style {
+            Some(_) => ident.value,
+            None => ident.value.to_lowercase(),
+        })
+        .collect())
+}
+
+/// Remove any semicolons, comments and whitespace following the last token of `sql`.
+///
+/// Statements are embedded in larger ones such as `TAIL (<statement>)`, where
+/// a trailing semicolon or line comment would produce invalid SQL.
+/// If `sql` can't be tokenized it is returned unchanged, so that validation
+/// can report the error.
+pub fn trim_statement(sql: &str) -> &str {
+    let dialect = PostgreSqlDialect {};
+    let Ok(tokens) = Tokenizer::new(&dialect, sql).tokenize_with_location() else {
+        return sql;
+    };
+    let end = tokens
+        .iter()
+        .rev()
+        .find(|t| !matches!(t.token, Token::Whitespace(_) | Token::SemiColon))
+        .map_or(0, |t| byte_offset(sql, t.span.end));
+    &sql[..end]
+}
+
+/// Convert a tokenizer location, counted in characters from line 1 and
+/// column 1, into a byte offset in `sql`.
+fn byte_offset(sql: &str, location: Location) -> usize {
+    let mut line = 1;
+    let mut column = 1;
+    for (i, c) in sql.char_indices() {
+        if (line, column) >= (location.line, location.column) {
+            return i;
+        }
+        if c == '\n' {
+            line += 1;
+            column = 1;
+        } else {
+            column += 1;
+        }
+    }
+    sql.len()
+}
+
+/// Ensure that `sql` contains exactly one read-only query.
+///
+/// A read-only query is a `SELECT`, `WITH`, `VALUES` or `TABLE` statement which
+/// doesn't modify data, either directly (e.g. `SELECT ... INTO`) or via a
+/// data-modifying common table expression.
+///
+/// # Errors
+///
+/// Returns [`Error::InvalidTailTarget`] if the statement can't be parsed, if it
+/// contains more than one statement, or if it isn't a read-only query. The error
+/// message includes the location of the offending token where possible.
+pub fn validate_read_only(sql: &str) -> Result<()> {
+    let dialect = PostgreSqlDialect {};
+    let mut parser = Parser::new(&dialect)
+        .try_with_sql(sql)
+        .map_err(|e| Error::InvalidTailTarget(e.to_string()))?;
+
+    let start = parser.peek_token().span.start;
+    // `parse_statement` doesn't recognise a bare `TABLE <name>` query,
+    // even though it's valid as a query body.
+    let statement = if parser.peek_keyword(Keyword::TABLE) {
+        parser.parse_query().map(Statement::Query)
+    } else {
+        parser.parse_statement()
+    }
+    .map_err(|e| Error::InvalidTailTarget(e.to_string()))?;
+
+    while parser.consume_token(&Token::SemiColon) {}
+    let next = parser.peek_token();
+    if next != Token::EOF {
+        return Err(Error::InvalidTailTarget(format!(
+            "expected a single statement, found {}{}",
+            next.token, next.span.start
+        )));
+    }
+
+    match statement {
+        Statement::Query(query) => validate_query(&query, start),
+        other => Err(not_read_only(&other.to_string(), start)),
+    }
+}
+
+/// Recursively check that a query and its CTEs don't modify data.
+fn validate_query(query: &Query, start: Location) -> Result<()> {
+    if let Some(with) = &query.with {
+        for cte in &with.cte_tables {
+            validate_query(&cte.query, start)?;
+        }
+    }
+    if !query.locks.is_empty() {
+        return Err(Error::InvalidTailTarget(format!(
+            "locking clauses are not allowed{start}"
+        )));
+    }
+    validate_set_expr(&query.body, start)
+}
+
+fn validate_set_expr(expr: &SetExpr, start: Location) -> Result<()> {
+    match expr {
+        SetExpr::Select(select) if select.into.is_some() => Err(Error::InvalidTailTarget(format!(
+            "SELECT ... INTO is not allowed{start}"
+        ))),
+        SetExpr::Select(_) | SetExpr::Values(_) | SetExpr::Table(_) => Ok(()),
+        SetExpr::Query(query) => validate_query(query, start),
+        SetExpr::SetOperation { left, right, .. } => {
+            validate_set_expr(left, start)?;
+            validate_set_expr(right, start)
+        }
+        SetExpr::Insert(statement) | SetExpr::Update(statement) => {
+            Err(not_read_only(&statement.to_string(), start))
+        }
+    }
+}
+
+fn not_read_only(statement: &str, start: Location) -> Error {
+    Error::InvalidTailTarget(format!(
+        "only read-only queries (SELECT, WITH, VALUES or TABLE) are allowed, found `{statement}`{start}"
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn object_names() {
+        assert_eq!(parse_object_name("my_view").unwrap(), vec!["my_view"]);
+        assert_eq!(parse_object_name("My_View").unwrap(), vec!["my_view"]);
+        assert_eq!(
+            parse_object_name(r#"db.Schema."My-View""#).unwrap(),
+            vec!["db", "schema", "My-View"]
+        );
+        assert_eq!(
+            parse_object_name(r#""say ""hi""""#).unwrap(),
+            vec![r#"say "hi""#]
+        );
+        assert_eq!(parse_object_name(r#""vüe""#).unwrap(), vec!["vüe"]);
+        assert!(parse_object_name("my-view").is_err());
+        assert!(parse_object_name("little bobby tables").is_err());
+        assert!(parse_object_name("a; DROP VIEW b").is_err());
+        assert!(parse_object_name("").is_err());
+    }
+
+    #[test]
+    fn quoted_sections() {
+        assert_eq!(quoted_len("'abc' rest"), Some(5));
+        assert_eq!(quoted_len(r#""ab""c" rest"#), Some(4));
+        assert_eq!(quoted_len("$$a 'b' c$$ rest"), Some(11));
+        assert_eq!(quoted_len("$tag$a $$ b$tag$ rest"), Some(16));
+        assert_eq!(quoted_len("'unterminated"), Some(13));
+        assert_eq!(quoted_len("$1 rest"), None);
+        assert_eq!(quoted_len("$name rest"), None);
+        assert_eq!(quoted_len("abc"), None);
+        assert_eq!(quoted_len("-- don't $x\nrest"), Some(12));
+        assert_eq!(quoted_len("-- unterminated"), Some(15));
+        assert_eq!(quoted_len("/* a /* 'b */ c */ rest"), Some(18));
+        assert_eq!(quoted_len("- 1"), None);
+        assert_eq!(quoted_len(r"E'it\'s $x' rest"), Some(11));
+        assert_eq!(quoted_len(r"e'a''b\\' rest"), Some(9));
+        assert_eq!(quoted_len("end"), None);
+    }
+
+    #[test]
+    fn special_positions() {
+        assert_eq!(next_special("SELECT 1 - 2 / 3"), None);
+        assert_eq!(next_special("SELECT x -- c"), Some(9));
+        assert_eq!(next_special("SELECT x /* c */"), Some(9));
+        assert_eq!(next_special("SELECT E'x'"), Some(7));
+        assert_eq!(next_special("SELECT date'x'"), Some(11));
+        assert_eq!(next_special("SELECT $x"), Some(7));
+    }
+
+    #[test]
+    fn quoting() {
+        assert_eq!(quote_ident("my_view"), r#""my_view""#);
+        assert_eq!(quote_ident(r#"say "hi""#), r#""say ""hi""""#);
+    }
+
+    #[test]
+    fn allowed() {
+        for sql in [
+            "SELECT 1",
+            "SELECT * FROM my_table",
+            "select * from my_table;",
+            "SELECT 1;;\n",
+            "SELECT a, count(*) FROM t WHERE b > 1 GROUP BY a ORDER BY 2 DESC LIMIT 10",
+            "WITH x AS (SELECT * FROM t) SELECT * FROM x",
+            "SELECT * FROM a UNION ALL SELECT * FROM b",
+            "VALUES (1, 'a'), (2, 'b')",
+            "TABLE my_table",
+            "(SELECT 1)",
+            "SELECT * FROM (SELECT * FROM t) sub",
+        ] {
+            assert!(
+                validate_read_only(sql).is_ok(),
+                "expected `{sql}` to be allowed"
+            );
+        }
+    }
+
+    #[test]
+    fn rejected() {
+        for sql in [
+            "",
+            "DROP VIEW x",
+            "DROP VIEW x; SELECT 1",
+            "SELECT 1; DROP VIEW x",
+            "SELECT 1; SELECT 2",
+            "INSERT INTO t VALUES (1)",
+            "UPDATE t SET a = 1",
+            "DELETE FROM t",
+            "CREATE MATERIALIZED VIEW v AS SELECT 1",
+            "SELECT * INTO new_table FROM t",
+            "SELECT * FROM t FOR UPDATE",
+            "WITH x AS (INSERT INTO t VALUES (1) RETURNING *) SELECT * FROM x",
+            "SELECT * FROM",
+            "little bobby tables",
+        ] {
+            assert!(
+                matches!(validate_read_only(sql), Err(Error::InvalidTailTarget(_))),
+                "expected `{sql}` to be rejected"
+            );
+        }
+    }
+
+    #[test]
+    fn trimming() {
+        assert_eq!(trim_statement("SELECT 1"), "SELECT 1");
+        assert_eq!(trim_statement("SELECT 1;"), "SELECT 1");
+        assert_eq!(trim_statement("SELECT 1 ; ;\n"), "SELECT 1");
+        assert_eq!(trim_statement("SELECT 1; -- done"), "SELECT 1");
+        assert_eq!(trim_statement("SELECT 1 /* one */"), "SELECT 1");
+        assert_eq!(
+            trim_statement("SELECT 'a;'\n  FROM t;"),
+            "SELECT 'a;'\n  FROM t"
+        );
+        assert_eq!(trim_statement("SELECT 'ü';"), "SELECT 'ü'");
+        assert_eq!(trim_statement(" ;"), "");
+        assert_eq!(
+            trim_statement("SELECT 'unterminated;"),
+            "SELECT 'unterminated;"
+        );
+    }
+
+    #[test]
+    fn error_location() {
+        let err = validate_read_only("SELECT 1;\nDROP VIEW x").unwrap_err();
+        assert!(
+            err.to_string().contains("Line: 2, Column: 1"),
+            "unexpected error: {err}"
+        );
+        let err = validate_read_only("SELECT * FROM t WHERE )").unwrap_err();
+        assert!(
+            err.to_string().contains("Line: 1"),
+            "unexpected error: {err}"
+        );
+    }
+}
@@ -0,0 +1,138 @@
+//! Statistics about the queries run for a frame, shown in Grafana's Query Inspector.
+
+use std::time::Duration;
+
+use grafana_plugin_sdk::data;
+use serde_json::json;
+use tokio_postgres::{
+    types::{FromSql, Type},
+    Row,
+};
+
+/// The size of a value as sent by the server, regardless of its type.
+struct RawLen(usize);
+
+impl<'a> FromSql<'a> for RawLen {
+    fn from_sql(
+        _: &Type,
+        raw: &'a [u8],
+    ) -> std::result::Result<Self, Box<dyn std::error::Error + Sync + Send>> {
+        Ok(Self(raw.len()))
+    }
+
+    fn accepts(_: &Type) -> bool {
+        true
+    }
+}
+
+/// The number of bytes used by the values in `row`, as sent by the server.
+fn row_bytes(row: &Row) -> usize {
+    (0..row.len())
+        .filter_map(|i| row.try_get::<_, Option<RawLen>>(i).ok().flatten())
+        .map(|len| len.0)
+        .sum()
+}
+
+/// Statistics about the rows returned for a query and how long they took.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct QueryStats {
+    pub rows: usize,
+    pub bytes: usize,
+    /// How long the query took to run, if it has finished.
+    pub duration: Option<Duration>,
+    /// How long it took to connect to the server.
+    pub connect: Option<Duration>,
+}
+
+impl QueryStats {
+    /// Count `rows` towards these statistics.
+    pub fn record(&mut self, rows: &[Row]) {
+        self.rows += rows.len();
+        self.bytes += rows.iter().map(row_bytes).sum::<usize>();
+    }
+
+    /// Convert these statistics to those shown in the Query Inspector.
+    fn to_query_stats(self) -> Vec<data::QueryStat> {
+        let millis = |d: Duration| d.as_secs_f64() * 1000.0;
+        [
+            Some(("Rows", None, self.rows as f64)),
+            Some(("Bytes", Some("decbytes"), self.bytes as f64)),
+            self.duration
+                .map(|d| ("Query duration", Some("ms"), millis(d))),
+            self.connect
+                .map(|d| ("Connection time", Some("ms"), millis(d))),
+        ]
+        .into_iter()
+        .flatten()
+        .filter_map(|(name, unit, value)| stat(name, unit, value))
+        .collect()
+    }
+}
+
+/// Build a single statistic.
+///
+/// `data::ConfFloat64` has no public constructor, so this goes via JSON.
+fn stat(name: &str, unit: Option<&str>, value: f64) -> Option<data::QueryStat> {
+    serde_json::from_value(json!({
+        "displayName": name,
+        "unit": unit,
+        "value": value,
+    }))
+    .ok()
+}
+
+/// Record the SQL which was run to produce `frame` and statistics about it in
+/// the frame's metadata.
+pub fn annotate(frame: &mut data::Frame, sql: Option<String>, stats: QueryStats) {
+    let meta = frame.meta.get_or_insert_with(Default::default);
+    if sql.is_some() {
+        meta.executed_query_string = sql;
+    }
+    meta.stats = Some(stats.to_query_stats());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn query_stats() {
+        let stats = QueryStats {
+            rows: 3,
+            bytes: 120,
+            duration: Some(Duration::from_millis(25)),
+            connect: None,
+        };
+        let mut frame = data::Frame::new("orders");
+        annotate(&mut frame, Some("SELECT * FROM orders".to_string()), stats);
+        let meta = frame.meta.unwrap();
+        assert_eq!(
+            meta.executed_query_string.as_deref(),
+            Some("SELECT * FROM orders")
+        );
+        let stats = serde_json::to_value(meta.stats.unwrap()).unwrap();
+        assert_eq!(
+            stats,
+            json!([
+                {"displayName": "Rows", "value": 3.0},
+                {"displayName": "Bytes", "unit": "decbytes", "value": 120.0},
+                {"displayName": "Query duration", "unit": "ms", "value": 25.0},
+            ])
+        );
+    }
+
+    #[test]
+    fn annotate_keeps_sql() {
+        let mut frame = data::Frame::new("orders");
+        annotate(
+            &mut frame,
+            Some("SELECT 1".to_string()),
+            QueryStats::default(),
+        );
+        annotate(&mut frame, None, QueryStats::default());
+        assert_eq!(
+            frame.meta.unwrap().executed_query_string.as_deref(),
+            Some("SELECT 1")
+        );
+    }
+}
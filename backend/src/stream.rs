@@ -1,9 +1,23 @@
 /// The `grafana_plugin_sdk::backend::StreamService` implementation for the Materialize plugin.
+use std::time::Instant;
+
 use futures_util::TryStreamExt;
-use grafana_plugin_sdk::{backend, data};
-use tracing::debug;
+use grafana_plugin_sdk::{backend, data, live::Path};
+use serde_json::json;
+use tracing::{debug, info, trace, warn, Instrument, Span};
 
-use crate::{queries::Query, rows_to_frame, Error, MaterializePlugin, Result};
+use crate::{
+    metrics::EmitKind,
+    notices,
+    output::OutputFormat,
+    panics::{catch_panics, catch_stream_panics},
+    path::QueryId,
+    queries::Query,
+    registry::QueryScope,
+    stats::{self, QueryStats},
+    trace::request_span,
+    Error, MaterializePlugin, Result,
+};
 
 /// Convert a Grafana Plugin SDK Frame to some initial data to send to new subscribers.
 fn frame_to_initial_data(frame: &data::Frame) -> Result<backend::InitialData> {
@@ -14,6 +28,20 @@ fn frame_to_initial_data(frame: &data::Frame) -> Result<backend::InitialData> {
     )?)
 }
 
+/// Create the span for a request to stream `path`, recording the path and the
+/// query ID it refers to, if any.
+///
+/// Stream requests don't carry any headers in this version of the SDK, so
+/// unlike other requests there's no trace context to record.
+fn stream_span(service: &'static str, context: &backend::PluginContext, path: &Path) -> Span {
+    let span = request_span(service, Some(context), None);
+    span.record("path", &path.as_str());
+    if let Some(query_id) = QueryId::from_channel_path(path.as_str()) {
+        span.record("query_id", &query_id.as_str());
+    }
+    span
+}
+
 #[backend::async_trait]
 impl backend::StreamService for MaterializePlugin {
     type JsonValue = ();
@@ -25,19 +53,44 @@ impl backend::StreamService for MaterializePlugin {
         &self,
         request: backend::SubscribeStreamRequest,
     ) -> Result<backend::SubscribeStreamResponse> {
-        let query = Query::try_from_path(&request.path, self.sql_queries.clone()).await?;
-        let target = query.as_tail()?;
-        let datasource_settings = request
-            .plugin_context
-            .datasource_instance_settings
-            .ok_or(Error::MissingDatasource)?;
-        let client = self.get_client(&datasource_settings).await?;
-
-        let initial_rows = target.select_all(&client).await?;
-
-        Ok(backend::SubscribeStreamResponse::ok(Some(
-            frame_to_initial_data(&rows_to_frame(&initial_rows))?,
-        )))
+        let span = stream_span("subscribe_stream", &request.plugin_context, &request.path);
+        catch_panics(async move {
+            let scope = QueryScope::from_context(&request.plugin_context)?;
+            let query = Query::try_from_path(&request.path, &self.sql_queries, &scope).await?;
+            let target = query.as_tail()?;
+            let format = OutputFormat::from_channel_path(request.path.as_str())?;
+            let datasource_settings = request
+                .plugin_context
+                .datasource_instance_settings
+                .ok_or(Error::MissingDatasource)?;
+            let started = Instant::now();
+            // Subscribing isn't retried by Grafana, so a failure to connect is
+            // only counted as a failed connection, not a reconnect.
+            let (client, mut notices) = self.connect(&datasource_settings).await?;
+            let mut stats = QueryStats {
+                connect: Some(started.elapsed()),
+                ..QueryStats::default()
+            };
+
+            let started = Instant::now();
+            let initial_rows = target.select_all(&client).await?;
+            let duration = started.elapsed();
+            self.metrics
+                .record_snapshot_duration(duration.as_secs_f64());
+            self.metrics
+                .record_frame(EmitKind::Snapshot, initial_rows.len());
+            stats.duration = Some(duration);
+            stats.record(&initial_rows);
+
+            let mut frame = format.rows_to_frame(&initial_rows)?;
+            notices::attach(&mut frame, notices.drain());
+            stats::annotate(&mut frame, Some(target.select_sql().0), stats);
+            Ok(backend::SubscribeStreamResponse::ok(Some(
+                frame_to_initial_data(&frame)?,
+            )))
+        })
+        .instrument(span)
+        .await
     }
 
     type Error = Error;
@@ -49,35 +102,226 @@ impl backend::StreamService for MaterializePlugin {
     /// is multiplexed to all clients by Grafana's backend. This is in contrast to the
     /// `subscribe_stream` method which is called for every client that wishes to connect.
     async fn run_stream(&self, request: backend::RunStreamRequest) -> Result<Self::Stream> {
-        let query = Query::try_from_path(&request.path, self.sql_queries.clone()).await?;
-        let target = query.as_tail()?;
-        let datasource_settings = request
-            .plugin_context
-            .datasource_instance_settings
-            .ok_or(Error::MissingDatasource)?;
-        let client = self.get_client(&datasource_settings).await?;
-
-        let stream = Box::pin(
-            target
+        let span = stream_span("run_stream", &request.plugin_context, &request.path);
+        catch_panics(async move {
+            let scope = QueryScope::from_context(&request.plugin_context)?;
+            let query = Query::try_from_path(&request.path, &self.sql_queries, &scope).await?;
+            // Keep the statement registered for as long as it's streamed.
+            let pinned = QueryId::from_channel_path(request.path.as_str())
+                .and_then(|id| self.sql_queries.pin(&id));
+            let target = query.as_tail()?;
+            let format = OutputFormat::from_channel_path(request.path.as_str())?;
+            let datasource_settings = request
+                .plugin_context
+                .datasource_instance_settings
+                .ok_or(Error::MissingDatasource)?;
+            let (client, mut notices) = self
+                .connect(&datasource_settings)
+                .await
+                .inspect_err(|_| self.metrics.record_stream_failure())?;
+
+            // The guards are moved into the stream so the stream is counted as active
+            // until Grafana drops it.
+            let active = self.metrics.stream_started(&datasource_settings.uid);
+            let metrics = self.metrics.clone();
+            let failures = self.metrics.clone();
+            // Statistics for streamed frames are running totals since the stream began.
+            let mut stats = QueryStats::default();
+            let sql = target.tail_sql().0;
+            // Rows are processed outside of this method, so events are emitted
+            // in its span explicitly.
+            let span = Span::current();
+            let error_span = span.clone();
+            info!("starting tail");
+            let rows = target
                 .tail(&client)
-                .await?
-                .map_err(Error::Connection)
-                .and_then(|row| async {
-                    rows_to_frame(&[row])
+                .await
+                .inspect_err(|_| self.metrics.record_stream_failure())?
+                .map_err(move |e| {
+                    failures.record_stream_failure();
+                    let e = Error::from(e);
+                    error_span.in_scope(|| warn!(error = %e, "tail failed"));
+                    e
+                });
+            let stream = Box::pin(catch_stream_panics(rows.and_then(move |row| {
+                let _guards = (&active, &pinned);
+                metrics.record_frame(EmitKind::Stream, 1);
+                let rows = [row];
+                stats.record(&rows);
+                span.in_scope(|| trace!(rows = stats.rows, bytes = stats.bytes, "tail progress"));
+                // Notices are sent with the next frame, since a frame without
+                // any rows would replace the panel's fields.
+                let frame = format.rows_to_frame(&rows).map(|mut frame| {
+                    notices::attach(&mut frame, notices.drain());
+                    stats::annotate(&mut frame, Some(sql.clone()), stats);
+                    frame
+                });
+                async move {
+                    frame?
                         .check()
-                        .map_err(Error::Data)
+                        .map_err(Error::from)
                         .and_then(|f| Ok(backend::StreamPacket::from_frame(f)?))
-                }),
-        );
+                }
+            })));
 
-        Ok(stream)
+            Ok(stream as Self::Stream)
+        })
+        .instrument(span)
+        .await
     }
 
     async fn publish_stream(
         &self,
-        _request: backend::PublishStreamRequest,
+        request: backend::PublishStreamRequest,
     ) -> Result<backend::PublishStreamResponse> {
-        debug!("Publishing to stream is not implemented");
-        unimplemented!()
+        debug!(path = %request.path.as_str(), "rejecting publish to stream");
+        Ok(backend::PublishStreamResponse::permission_denied(json!({
+            "error": "publishing to Materialize streams is not supported",
+        })))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_util::StreamExt;
+    use grafana_plugin_sdk::{backend::StreamService, pluginv2};
+    use tokio_postgres::types::Type;
+
+    use crate::testing::{FakeServer, FakeValue};
+
+    use super::*;
+
+    fn context() -> Option<pluginv2::PluginContext> {
+        Some(pluginv2::PluginContext {
+            data_source_instance_settings: Some(pluginv2::DataSourceInstanceSettings {
+                json_data: b"{}".to_vec(),
+                ..Default::default()
+            }),
+            ..Default::default()
+        })
+    }
+
+    #[tokio::test]
+    async fn unknown_path() {
+        let plugin = MaterializePlugin::default();
+        let request = pluginv2::SubscribeStreamRequest {
+            plugin_context: context(),
+            path: "unknown/path".to_string(),
+            ..Default::default()
+        };
+        assert!(plugin
+            .subscribe_stream(request.try_into().unwrap())
+            .await
+            .is_err());
+
+        let request = pluginv2::RunStreamRequest {
+            plugin_context: context(),
+            path: "unknown/path".to_string(),
+            ..Default::default()
+        };
+        assert!(plugin
+            .run_stream(request.try_into().unwrap())
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn publish() {
+        let request = pluginv2::PublishStreamRequest {
+            plugin_context: context(),
+            path: "unknown/path".to_string(),
+            ..Default::default()
+        };
+        let response = MaterializePlugin::default()
+            .publish_stream(request.try_into().unwrap())
+            .await
+            .unwrap();
+        assert!(matches!(
+            response.status,
+            backend::PublishStreamStatus::PermissionDenied
+        ));
+    }
+
+    /// The value of the counter called `name` in `plugin`'s metrics.
+    fn counter(plugin: &MaterializePlugin, name: &str) -> String {
+        let text = String::from_utf8(plugin.metrics.encode(0).unwrap()).unwrap();
+        let prefix = format!("materialize_datasource_{name} ");
+        text.lines()
+            .find_map(|line| line.strip_prefix(&prefix))
+            .unwrap()
+            .to_string()
+    }
+
+    /// The value of the reconnect counter in `plugin`'s metrics.
+    fn reconnects(plugin: &MaterializePlugin) -> String {
+        counter(plugin, "stream_reconnects_total")
+    }
+
+    #[tokio::test]
+    async fn reconnects_after_failures() {
+        let plugin = MaterializePlugin::default();
+        let request = |settings| {
+            pluginv2::RunStreamRequest {
+                plugin_context: Some(pluginv2::PluginContext {
+                    data_source_instance_settings: Some(settings),
+                    ..Default::default()
+                }),
+                path: "tail/relation/orders".to_string(),
+                ..Default::default()
+            }
+            .try_into()
+            .unwrap()
+        };
+
+        // A stream which runs to completion isn't counted.
+        let server = FakeServer::start(
+            &[("orders", Type::FLOAT8)],
+            vec![vec![FakeValue::Float(1.0)]],
+        )
+        .await;
+        let packets: Vec<_> = plugin
+            .run_stream(request(server.settings()))
+            .await
+            .unwrap()
+            .collect()
+            .await;
+        assert_eq!(packets.len(), 1);
+        assert!(server.statements().iter().any(|s| s.starts_with("TAIL")));
+        assert_eq!(reconnects(&plugin), "0");
+
+        // A stream which can't connect is, since Grafana will run it again.
+        let port = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let settings = pluginv2::DataSourceInstanceSettings {
+            json_data: serde_json::to_vec(&json!({
+                "host": "127.0.0.1",
+                "port": port,
+                "username": "materialize",
+            }))
+            .unwrap(),
+            ..server.settings()
+        };
+        assert!(plugin.run_stream(request(settings.clone())).await.is_err());
+        assert_eq!(reconnects(&plugin), "1");
+        assert_eq!(counter(&plugin, "connection_failures_total"), "1");
+
+        // A subscription which can't connect isn't, since it isn't retried.
+        let subscribe = pluginv2::SubscribeStreamRequest {
+            plugin_context: Some(pluginv2::PluginContext {
+                data_source_instance_settings: Some(settings),
+                ..Default::default()
+            }),
+            path: "tail/relation/orders".to_string(),
+            ..Default::default()
+        };
+        assert!(plugin
+            .subscribe_stream(subscribe.try_into().unwrap())
+            .await
+            .is_err());
+        assert_eq!(reconnects(&plugin), "1");
+        assert_eq!(counter(&plugin, "connection_failures_total"), "2");
     }
 }
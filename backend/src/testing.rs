@@ -0,0 +1,198 @@
+//! A fake Materialize server for tests which need a database connection.
+//!
+//! It speaks just enough of the Postgres wire protocol to accept a connection
+//! from `tokio_postgres`, acknowledge `SET` and transaction statements and answer
+//! every other query with a fixed set of rows. Like Materialize, it refuses to
+//! make sessions read-only. The statements it receives are recorded so
+//! tests can check what the plugin sent.
+
+use std::sync::{Arc, Mutex};
+
+use chrono::{DateTime, Utc};
+use grafana_plugin_sdk::pluginv2;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+use tokio_postgres::types::Type;
+
+/// A value in a row returned by a [`FakeServer`].
+#[derive(Clone, Debug)]
+pub enum FakeValue {
+    Time(DateTime<Utc>),
+    Text(&'static str),
+    Float(f64),
+}
+
+impl FakeValue {
+    /// The value in the binary format requested by `tokio_postgres`.
+    fn encode(&self) -> Vec<u8> {
+        match self {
+            // Microseconds since 2000-01-01.
+            Self::Time(t) => ((t.timestamp() - 946_684_800) * 1_000_000
+                + i64::from(t.timestamp_subsec_micros()))
+            .to_be_bytes()
+            .to_vec(),
+            Self::Text(s) => s.as_bytes().to_vec(),
+            Self::Float(f) => f.to_be_bytes().to_vec(),
+        }
+    }
+}
+
+/// A server answering every query with the same rows.
+pub struct FakeServer {
+    port: u16,
+    statements: Arc<Mutex<Vec<String>>>,
+}
+
+impl FakeServer {
+    /// Start a server whose results have the given columns and rows.
+    pub async fn start(columns: &[(&'static str, Type)], rows: Vec<Vec<FakeValue>>) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let statements = Arc::new(Mutex::new(Vec::new()));
+        let results = Arc::new((columns.to_vec(), rows));
+        let recorded = Arc::clone(&statements);
+        tokio::spawn(async move {
+            while let Ok((socket, _)) = listener.accept().await {
+                tokio::spawn(serve(socket, Arc::clone(&results), Arc::clone(&recorded)));
+            }
+        });
+        Self { port, statements }
+    }
+
+    /// Settings for a datasource connecting to this server.
+    pub fn settings(&self) -> pluginv2::DataSourceInstanceSettings {
+        pluginv2::DataSourceInstanceSettings {
+            uid: "materialize".to_string(),
+            json_data: serde_json::to_vec(&serde_json::json!({
+                "host": "127.0.0.1",
+                "port": self.port,
+                "username": "materialize",
+            }))
+            .unwrap(),
+            ..Default::default()
+        }
+    }
+
+    /// The statements received so far, in order.
+    pub fn statements(&self) -> Vec<String> {
+        self.statements.lock().unwrap().clone()
+    }
+}
+
+type Results = Arc<(Vec<(&'static str, Type)>, Vec<Vec<FakeValue>>)>;
+
+/// Append a message with the given tag and body to `buf`.
+fn message(buf: &mut Vec<u8>, tag: u8, body: &[u8]) {
+    buf.push(tag);
+    buf.extend((body.len() as i32 + 4).to_be_bytes());
+    buf.extend(body);
+}
+
+fn cstr(bytes: &[u8]) -> String {
+    let end = bytes.iter().position(|b| *b == 0).unwrap_or(bytes.len());
+    String::from_utf8_lossy(&bytes[..end]).into_owned()
+}
+
+async fn serve(mut socket: TcpStream, results: Results, statements: Arc<Mutex<Vec<String>>>) {
+    let (columns, rows) = &*results;
+    // The startup message has no tag; the client authenticates without a password.
+    let Ok(len) = socket.read_i32().await else {
+        return;
+    };
+    let mut startup = vec![0; len as usize - 4];
+    if socket.read_exact(&mut startup).await.is_err() {
+        return;
+    }
+    let mut out = Vec::new();
+    message(&mut out, b'R', &0i32.to_be_bytes());
+    message(&mut out, b'Z', b"I");
+    if socket.write_all(&out).await.is_err() {
+        return;
+    }
+    out.clear();
+
+    loop {
+        let Ok(tag) = socket.read_u8().await else {
+            return;
+        };
+        let Ok(len) = socket.read_i32().await else {
+            return;
+        };
+        let mut body = vec![0; len as usize - 4];
+        if socket.read_exact(&mut body).await.is_err() {
+            return;
+        }
+        match tag {
+            // A simple query, only used by the plugin to configure the session
+            // and manage transactions.
+            b'Q' => {
+                let sql = cstr(&body);
+                statements.lock().unwrap().push(sql.clone());
+                let command = sql.split_whitespace().next().unwrap_or_default();
+                let error: Option<&[u8]> = match command.to_uppercase().as_str() {
+                    _ if sql.contains("default_transaction_read_only") => {
+                        Some(b"SERROR\0C42704\0Munrecognized configuration parameter\0\0")
+                    }
+                    "SET" | "BEGIN" | "COMMIT" => None,
+                    _ => Some(b"SERROR\0C0A000\0Mfake server only supports SET\0\0"),
+                };
+                match error {
+                    Some(error) => message(&mut out, b'E', error),
+                    None => message(&mut out, b'C', format!("{command}\0").as_bytes()),
+                }
+                message(&mut out, b'Z', b"I");
+            }
+            // Parse: the statement name comes first, then its SQL.
+            b'P' => {
+                let name_len = body.iter().position(|b| *b == 0).unwrap_or(0) + 1;
+                statements.lock().unwrap().push(cstr(&body[name_len..]));
+                message(&mut out, b'1', &[]);
+            }
+            // Describe a statement: no parameters, then the result columns.
+            b'D' => {
+                message(&mut out, b't', &0i16.to_be_bytes());
+                let mut description = (columns.len() as i16).to_be_bytes().to_vec();
+                for (name, type_) in columns {
+                    description.extend(name.as_bytes());
+                    description.push(0);
+                    description.extend(0i32.to_be_bytes());
+                    description.extend(0i16.to_be_bytes());
+                    description.extend(type_.oid().to_be_bytes());
+                    description.extend((-1i16).to_be_bytes());
+                    description.extend((-1i32).to_be_bytes());
+                    description.extend(0i16.to_be_bytes());
+                }
+                message(&mut out, b'T', &description);
+            }
+            b'B' => message(&mut out, b'2', &[]),
+            b'E' => {
+                for row in rows {
+                    let mut data = (row.len() as i16).to_be_bytes().to_vec();
+                    for value in row {
+                        let encoded = value.encode();
+                        data.extend((encoded.len() as i32).to_be_bytes());
+                        data.extend(encoded);
+                    }
+                    message(&mut out, b'D', &data);
+                }
+                message(
+                    &mut out,
+                    b'C',
+                    format!("SELECT {}\0", rows.len()).as_bytes(),
+                );
+            }
+            b'C' => message(&mut out, b'3', &[]),
+            b'S' => message(&mut out, b'Z', b"I"),
+            b'H' => {}
+            _ => return,
+        }
+        if matches!(tag, b'Q' | b'S' | b'H') {
+            if socket.write_all(&out).await.is_err() {
+                return;
+            }
+            out.clear();
+        }
+    }
+}
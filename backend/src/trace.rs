@@ -0,0 +1,154 @@
+//! Tracing spans for requests to the plugin's services.
+//!
+//! Each service method runs inside a span identifying the datasource and org it
+//! was called for. When Grafana forwards a W3C `traceparent` header, its trace
+//! and span IDs are recorded on the span too, so the plugin's logs can be
+//! correlated with the rest of the trace by searching for them.
+//!
+//! The plugin doesn't export spans to a tracing backend, so its spans are not
+//! made children of Grafana's and don't appear in the trace itself.
+
+use std::collections::HashMap;
+
+use grafana_plugin_sdk::backend;
+use tracing::{field::Empty, info_span, Span};
+
+/// The W3C trace context header.
+const TRACEPARENT: &str = "traceparent";
+
+/// A trace context, as sent in a W3C `traceparent` header.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TraceContext {
+    /// The ID of the whole trace, as 32 lowercase hex digits.
+    pub trace_id: String,
+    /// The ID of the caller's span, as 16 lowercase hex digits.
+    pub parent_id: String,
+}
+
+impl TraceContext {
+    /// Parse a `traceparent` header value such as
+    /// `00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01`.
+    ///
+    /// Returns `None` if the value is malformed or either ID is all zeroes,
+    /// which the specification defines as invalid.
+    pub fn from_traceparent(value: &str) -> Option<Self> {
+        let mut parts = value.trim().split('-');
+        let (version, trace_id, parent_id, flags) =
+            (parts.next()?, parts.next()?, parts.next()?, parts.next()?);
+        let hex = |s: &str, len: usize| {
+            s.len() == len && s.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'))
+        };
+        let nonzero = |s: &str| s.bytes().any(|b| b != b'0');
+        // Future versions may append fields, but version 00 has exactly four.
+        if !hex(version, 2)
+            || version == "ff"
+            || (version == "00" && parts.next().is_some())
+            || !hex(trace_id, 32)
+            || !hex(parent_id, 16)
+            || !hex(flags, 2)
+            || !nonzero(trace_id)
+            || !nonzero(parent_id)
+        {
+            return None;
+        }
+        Some(Self {
+            trace_id: trace_id.to_string(),
+            parent_id: parent_id.to_string(),
+        })
+    }
+
+    /// Find the trace context in some request headers, if present.
+    ///
+    /// Grafana may forward headers with an `http_` prefix, so that is ignored.
+    pub fn from_headers<'a>(headers: impl IntoIterator<Item = (&'a str, &'a str)>) -> Option<Self> {
+        headers
+            .into_iter()
+            .find(|(name, _)| {
+                name.strip_prefix("http_")
+                    .unwrap_or(name)
+                    .eq_ignore_ascii_case(TRACEPARENT)
+            })
+            .and_then(|(_, value)| Self::from_traceparent(value))
+    }
+
+    /// Find the trace context in the headers sent with a data or health check request.
+    pub fn from_header_map(headers: &HashMap<String, String>) -> Option<Self> {
+        Self::from_headers(headers.iter().map(|(k, v)| (k.as_str(), v.as_str())))
+    }
+}
+
+/// Create the span for a request to one of the plugin's services.
+///
+/// `path` and `query_id` are left empty, to be recorded once they're known.
+pub fn request_span(
+    service: &'static str,
+    context: Option<&backend::PluginContext>,
+    trace: Option<&TraceContext>,
+) -> Span {
+    let span = info_span!(
+        "request",
+        service,
+        datasource = Empty,
+        org_id = Empty,
+        path = Empty,
+        query_id = Empty,
+        trace_id = Empty,
+        parent_span_id = Empty,
+    );
+    if let Some(context) = context {
+        span.record("org_id", &context.org_id);
+        if let Some(settings) = &context.datasource_instance_settings {
+            span.record("datasource", &settings.uid.as_str());
+        }
+    }
+    if let Some(trace) = trace {
+        span.record("trace_id", &trace.trace_id.as_str());
+        span.record("parent_span_id", &trace.parent_id.as_str());
+    }
+    span
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn traceparent() {
+        assert_eq!(
+            TraceContext::from_traceparent(
+                "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01"
+            ),
+            Some(TraceContext {
+                trace_id: "4bf92f3577b34da6a3ce929d0e0e4736".to_string(),
+                parent_id: "00f067aa0ba902b7".to_string(),
+            })
+        );
+        for invalid in [
+            "",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7",
+            "00-00000000000000000000000000000000-00f067aa0ba902b7-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-0000000000000000-01",
+            "00-4BF92F3577B34DA6A3CE929D0E0E4736-00f067aa0ba902b7-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01-extra",
+            "ff-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+        ] {
+            assert_eq!(TraceContext::from_traceparent(invalid), None, "{invalid}");
+        }
+    }
+
+    #[test]
+    fn headers() {
+        let headers = HashMap::from([
+            ("FromAlert".to_string(), "true".to_string()),
+            (
+                "http_Traceparent".to_string(),
+                "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-00".to_string(),
+            ),
+        ]);
+        assert_eq!(
+            TraceContext::from_header_map(&headers).map(|t| t.parent_id),
+            Some("00f067aa0ba902b7".to_string())
+        );
+        assert_eq!(TraceContext::from_header_map(&HashMap::new()), None);
+    }
+}
@@ -0,0 +1,59 @@
+//! Queries used to populate the options of dashboard template variables.
+
+use futures_util::TryStreamExt;
+use serde::Serialize;
+use tokio_postgres::{Client, Row};
+
+use crate::{
+    convert::value_to_string,
+    queries::{begin_read_only, SelectStatement},
+    Result,
+};
+
+/// A single option of a template variable.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct VariableValue {
+    /// The text displayed in the variable dropdown.
+    pub text: String,
+    /// The value substituted when the option is selected.
+    pub value: String,
+}
+
+/// Something that can run a statement to produce template variable options.
+#[grafana_plugin_sdk::backend::async_trait]
+pub trait VariableSource {
+    /// Run `statement`, returning one option per row.
+    ///
+    /// By convention the first column is used as the text of each option and
+    /// the second column, if present, as its value. If there is only one column
+    /// it's used for both. `NULL` values are returned as empty strings.
+    async fn variable_values(&self, statement: &SelectStatement) -> Result<Vec<VariableValue>>;
+}
+
+#[grafana_plugin_sdk::backend::async_trait]
+impl VariableSource for Client {
+    async fn variable_values(&self, statement: &SelectStatement) -> Result<Vec<VariableValue>> {
+        begin_read_only(self).await?;
+        let rows: Vec<Row> = self
+            .query_raw(statement.as_str(), statement.params())
+            .await?
+            .try_collect()
+            .await?;
+        self.batch_execute("COMMIT").await?;
+        Ok(rows
+            .iter()
+            .map(|row| {
+                let text = (!row.is_empty())
+                    .then(|| value_to_string(row, 0))
+                    .flatten()
+                    .unwrap_or_default();
+                let value = if row.len() > 1 {
+                    value_to_string(row, 1).unwrap_or_default()
+                } else {
+                    text.clone()
+                };
+                VariableValue { text, value }
+            })
+            .collect())
+    }
+}
@@ -52,6 +52,30 @@ export const ConfigEditor = ({ options, onOptionsChange }: Props): JSX.Element =
                   }
                 />
               </InlineField>
+
+              <InlineField label="Database" labelWidth={20} tooltip="Leave empty to use the server's default">
+                <Input
+                  value={options.jsonData.database}
+                  placeholder="materialize"
+                  onChange={(event) =>
+                    onSettingsChange({
+                      jsonData: { ...options.jsonData, database: event.currentTarget.value || undefined },
+                    })
+                  }
+                />
+              </InlineField>
+
+              <InlineField label="Cluster" labelWidth={20} tooltip="Leave empty to use the server's default">
+                <Input
+                  value={options.jsonData.cluster}
+                  placeholder="default"
+                  onChange={(event) =>
+                    onSettingsChange({
+                      jsonData: { ...options.jsonData, cluster: event.currentTarget.value || undefined },
+                    })
+                  }
+                />
+              </InlineField>
             </FieldSet>
           </>
         )}
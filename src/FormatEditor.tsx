@@ -0,0 +1,120 @@
+import React from 'react';
+import { SelectableValue } from '@grafana/data';
+import { Input, Select } from '@grafana/ui';
+
+import { AnnotationFormat, LogsFormat, OutputFormat } from './types';
+
+type FormatType = OutputFormat['type'];
+
+const formatOptions: Array<SelectableValue<FormatType>> = [
+  { label: 'Table', value: 'table', description: 'One field per column.' },
+  { label: 'Annotations', value: 'annotations', description: 'Map columns to annotation fields.' },
+  { label: 'Logs', value: 'logs', description: 'Map columns to log lines, for the Logs panel.' },
+];
+
+/// Split a comma separated list of column names.
+const splitColumns = (value: string): string[] =>
+  value
+    .split(',')
+    .map((column) => column.trim())
+    .filter((column) => column !== '');
+
+interface Props {
+  format?: OutputFormat;
+  onChange: (format: OutputFormat) => void;
+  onBlur: () => void;
+}
+
+/// Edit the output format of a query, and the columns it uses.
+export const FormatEditor = ({ format, onChange, onBlur }: Props): JSX.Element => {
+  const type = format?.type ?? 'table';
+
+  const onTypeChange = (event: SelectableValue<FormatType>) => {
+    switch (event.value) {
+      case 'annotations':
+        onChange({ type: 'annotations', time: '' });
+        break;
+      case 'logs':
+        onChange({ type: 'logs', time: '', body: '' });
+        break;
+      default:
+        onChange({ type: 'table' });
+    }
+  };
+
+  return (
+    <>
+      <Select menuShouldPortal options={formatOptions} value={type} onChange={onTypeChange} />
+      {format?.type === 'annotations' ? <AnnotationColumns format={format} onChange={onChange} onBlur={onBlur} /> : null}
+      {format?.type === 'logs' ? <LogColumns format={format} onChange={onChange} onBlur={onBlur} /> : null}
+    </>
+  );
+};
+
+interface AnnotationProps {
+  format: AnnotationFormat;
+  onChange: (format: AnnotationFormat) => void;
+  onBlur: () => void;
+}
+
+const AnnotationColumns = ({ format, onChange, onBlur }: AnnotationProps): JSX.Element => {
+  const column = (key: 'time' | 'timeEnd' | 'title' | 'text', placeholder: string) => (
+    <Input
+      placeholder={placeholder}
+      value={format[key] ?? ''}
+      onChange={(event) => onChange({ ...format, [key]: event.currentTarget.value || undefined })}
+      onBlur={onBlur}
+    />
+  );
+  return (
+    <>
+      {column('time', 'Time column')}
+      {column('timeEnd', 'End time column')}
+      {column('title', 'Title column')}
+      {column('text', 'Text column')}
+      <Input
+        placeholder="Tag columns, comma separated"
+        defaultValue={(format.tags ?? []).join(', ')}
+        onBlur={(event) => {
+          onChange({
+            ...format,
+            tags: splitColumns(event.currentTarget.value),
+          });
+          onBlur();
+        }}
+      />
+    </>
+  );
+};
+
+interface LogProps {
+  format: LogsFormat;
+  onChange: (format: LogsFormat) => void;
+  onBlur: () => void;
+}
+
+const LogColumns = ({ format, onChange, onBlur }: LogProps): JSX.Element => {
+  const column = (key: 'time' | 'body' | 'level', placeholder: string) => (
+    <Input
+      placeholder={placeholder}
+      value={format[key] ?? ''}
+      onChange={(event) => onChange({ ...format, [key]: event.currentTarget.value || undefined })}
+      onBlur={onBlur}
+    />
+  );
+  return (
+    <>
+      {column('time', 'Time column')}
+      {column('body', 'Body column')}
+      {column('level', 'Level column')}
+      <Input
+        placeholder="Label columns, comma separated"
+        defaultValue={(format.labels ?? []).join(', ')}
+        onBlur={(event) => {
+          onChange({ ...format, labels: splitColumns(event.currentTarget.value) });
+          onBlur();
+        }}
+      />
+    </>
+  );
+};
@@ -2,26 +2,95 @@ import { defaults } from 'lodash';
 
 import React, { useEffect, useState } from 'react';
 import { QueryEditorProps, SelectableValue } from '@grafana/data';
-import { Select, TextArea } from '@grafana/ui';
+import { Checkbox, Select, TextArea } from '@grafana/ui';
 
 import { DataSource } from './datasource';
-import { defaultQuery, DataSourceOptions, MaterializeQuery, MaterializeTarget } from './types';
+import { FormatEditor } from './FormatEditor';
+import {
+  defaultQuery,
+  DataSourceOptions,
+  ExplainPlan,
+  IntrospectionQuery,
+  MaterializeOperation,
+  MaterializeQuery,
+  MaterializeTarget,
+  OutputFormat,
+  Relation,
+} from './types';
 
 type Props = QueryEditorProps<DataSource, MaterializeQuery, DataSourceOptions>;
 
+const operationOptions = [
+  { label: 'Tail', value: MaterializeOperation.Tail, description: 'Stream updates as they happen.' },
+  {
+    label: 'Select',
+    value: MaterializeOperation.Select,
+    description: 'Return the current results once, without streaming.',
+  },
+  { label: 'Explain', value: MaterializeOperation.Explain, description: 'Show the plan used to compute the results.' },
+  {
+    label: 'Dependency graph',
+    value: MaterializeOperation.Graph,
+    description: 'Show the objects a relation depends on, and those depending on it.',
+  },
+  {
+    label: 'Introspection',
+    value: MaterializeOperation.Introspection,
+    description: 'Run a predefined query about the health of the Materialize instance.',
+  },
+];
+
+const introspectionOptions: Array<SelectableValue<IntrospectionQuery>> = [
+  { label: 'Source status', value: 'sourceStatus' },
+  { label: 'Sink status', value: 'sinkStatus' },
+  { label: 'Replica utilization', value: 'replicaUtilization' },
+  { label: 'Freshness', value: 'freshness', description: 'How far each object lags behind the current time.' },
+];
+
+const planOptions: Array<SelectableValue<ExplainPlan>> = [
+  { label: 'Optimized plan', value: 'optimized' },
+  { label: 'Physical plan', value: 'physical' },
+];
+
 const targetOptions = [
-  { label: 'Relation', value: MaterializeTarget.Relation, description: 'Tail the output of a source, table or view.' },
+  { label: 'Relation', value: MaterializeTarget.Relation, description: 'Query a source, table or view.' },
   {
     label: 'Select statement',
     value: MaterializeTarget.SelectStatement,
-    description: 'Tail the results of a select statement.',
+    description: 'Query the results of a select statement.',
   },
 ];
 
 export const QueryEditor = ({ datasource, onChange, onRunQuery, query }: Props): JSX.Element => {
   defaults(query, defaultQuery);
-  const { target } = query;
+  const { operation, target } = query;
 
+  const onOperationChange = (event: SelectableValue<MaterializeOperation>) => {
+    const operation = event.value ?? MaterializeOperation.Tail;
+    if (operation === MaterializeOperation.Graph) {
+      // Graphs are always built around a relation.
+      onChange({ ...query, operation, target: MaterializeTarget.Relation } as MaterializeQuery);
+    } else {
+      onChange({ ...query, operation });
+    }
+    onRunQuery();
+  };
+  const onIntrospectionChange = (event: SelectableValue<IntrospectionQuery>) => {
+    onChange({ ...query, query: event.value });
+    onRunQuery();
+  };
+  const onStreamChange = (event: React.FormEvent<HTMLInputElement>) => {
+    onChange({ ...query, stream: event.currentTarget.checked });
+    onRunQuery();
+  };
+  const onStatsChange = (event: React.FormEvent<HTMLInputElement>) => {
+    onChange({ ...query, stats: event.currentTarget.checked });
+    onRunQuery();
+  };
+  const onPlanChange = (event: SelectableValue<ExplainPlan>) => {
+    onChange({ ...query, plan: event.value });
+    onRunQuery();
+  };
   const onTargetChange = (event: SelectableValue<MaterializeTarget>) => {
     onChange({ ...query, target: event.value ?? MaterializeTarget.Relation });
   };
@@ -36,20 +105,44 @@ export const QueryEditor = ({ datasource, onChange, onRunQuery, query }: Props):
     }
   };
 
+  const onFormatChange = (format: OutputFormat) => {
+    onChange({ ...query, format });
+  };
+
   const [relations, setRelations] = useState<SelectableValue[]>([]);
 
   useEffect(() => {
     if (target === MaterializeTarget.Relation) {
-      datasource.getResource('relations').then((options: string[]) => {
-        setRelations(options.map((value) => ({ label: value, value })));
+      datasource.getResource('relations').then((options: Relation[]) => {
+        setRelations(
+          options.map((relation) => ({
+            label: `${relation.schema}.${relation.name}`,
+            value: relation.fullyQualifiedName,
+            description: relation.kind,
+          }))
+        );
       });
     }
   }, [datasource, target]);
 
   return (
     <div className="gf-form">
-      <Select menuShouldPortal options={targetOptions} value={target} onChange={onTargetChange} />
-      {target === MaterializeTarget.Relation ? (
+      <Select menuShouldPortal options={operationOptions} value={operation} onChange={onOperationChange} />
+      {operation === MaterializeOperation.Introspection ? (
+        <>
+          <Select
+            menuShouldPortal
+            options={introspectionOptions}
+            value={query.query}
+            onChange={onIntrospectionChange}
+          />
+          <Checkbox label="Stream updates" value={query.stream ?? false} onChange={onStreamChange} />
+        </>
+      ) : null}
+      {operation !== MaterializeOperation.Graph && operation !== MaterializeOperation.Introspection ? (
+        <Select menuShouldPortal options={targetOptions} value={target} onChange={onTargetChange} />
+      ) : null}
+      {operation !== MaterializeOperation.Introspection && target === MaterializeTarget.Relation ? (
         <Select
           menuShouldPortal
           options={relations}
@@ -58,9 +151,20 @@ export const QueryEditor = ({ datasource, onChange, onRunQuery, query }: Props):
           onBlur={onRunQuery}
         />
       ) : null}
-      {target === MaterializeTarget.SelectStatement ? (
+      {operation !== MaterializeOperation.Introspection && target === MaterializeTarget.SelectStatement ? (
         <TextArea value={query.statement} onChange={onSelectStatementChange} onBlur={onRunQuery} />
       ) : null}
+      {operation === MaterializeOperation.Explain ? (
+        <Select menuShouldPortal options={planOptions} value={query.plan ?? 'optimized'} onChange={onPlanChange} />
+      ) : null}
+      {operation === MaterializeOperation.Graph ? (
+        <Checkbox label="Arrangement sizes" value={query.stats ?? false} onChange={onStatsChange} />
+      ) : null}
+      {operation === MaterializeOperation.Tail ||
+      operation === MaterializeOperation.Select ||
+      operation === MaterializeOperation.Introspection ? (
+        <FormatEditor format={query.format} onChange={onFormatChange} onBlur={onRunQuery} />
+      ) : null}
     </div>
   );
 };
@@ -1,5 +1,5 @@
 import { SelectableValue } from '@grafana/data';
-import { InlineField, Select } from '@grafana/ui';
+import { InlineField, Select, TextArea } from '@grafana/ui';
 import React, { useState } from 'react';
 import { VariableQuery, VariableQueryPathName } from './types';
 
@@ -10,6 +10,11 @@ interface VariableQueryProps {
 
 const pathOptions = [
   { label: 'Relations', value: VariableQueryPathName.Relations, description: 'Query available relations.' },
+  {
+    label: 'SQL',
+    value: VariableQueryPathName.Variable,
+    description: 'Run a SELECT statement. The first column is used as the text and the second as the value.',
+  },
 ];
 
 export const VariableQueryEditor: React.FC<VariableQueryProps> = ({ onChange, query }) => {
@@ -25,9 +30,22 @@ export const VariableQueryEditor: React.FC<VariableQueryProps> = ({ onChange, qu
       path: event.value,
     });
 
+  const handleStatementChange = (event: React.FormEvent<HTMLTextAreaElement>) =>
+    setState({
+      ...state,
+      statement: event.currentTarget.value,
+    });
+
   return (
-    <InlineField label="Query kind" labelWidth={20}>
-      <Select width={100} options={pathOptions} value={state.path} onChange={handleChange} onBlur={savePath} />
-    </InlineField>
+    <>
+      <InlineField label="Query kind" labelWidth={20}>
+        <Select width={100} options={pathOptions} value={state.path} onChange={handleChange} onBlur={savePath} />
+      </InlineField>
+      {state.path === VariableQueryPathName.Variable ? (
+        <InlineField label="Statement" labelWidth={20}>
+          <TextArea value={state.statement} onChange={handleStatementChange} onBlur={savePath} />
+        </InlineField>
+      ) : null}
+    </>
   );
 };
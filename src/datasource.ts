@@ -1,20 +1,78 @@
-import { DataSourceInstanceSettings, MetricFindValue } from '@grafana/data';
-import { DataSourceWithBackend, StreamingFrameOptions } from '@grafana/runtime';
-import { DataSourceOptions, MaterializeQuery, VariableQueryPathName, VariableQuery } from './types';
+import { DataSourceInstanceSettings, MetricFindValue, ScopedVars } from '@grafana/data';
+import { DataSourceWithBackend, getTemplateSrv, StreamingFrameOptions } from '@grafana/runtime';
+import {
+  AdHocFilter,
+  DataSourceOptions,
+  MaterializeQuery,
+  MaterializeTarget,
+  TemplateVariables,
+  VariableQueryPathName,
+  VariableQuery,
+} from './types';
+
+/// Collect the current values of all template variables, so they can be bound
+/// to placeholders by the backend instead of being interpolated into SQL.
+function templateVariables(scopedVars?: ScopedVars): TemplateVariables {
+  const templateSrv = getTemplateSrv();
+  const variables: TemplateVariables = {};
+  for (const { name } of templateSrv.getVariables()) {
+    templateSrv.replace(`$${name}`, scopedVars, (value: string | string[]) => {
+      variables[name] = value;
+      return '';
+    });
+  }
+  return variables;
+}
 
 export class DataSource extends DataSourceWithBackend<MaterializeQuery, DataSourceOptions> {
   constructor(instanceSettings: DataSourceInstanceSettings<DataSourceOptions>) {
     super(instanceSettings);
   }
 
+  /// Enable Grafana's standard annotation support, which runs queries using the
+  /// query editor. Use the annotations format to map columns to annotation fields.
+  annotations = {};
+
   streamOptionsProvider = (): Partial<StreamingFrameOptions> => ({ maxLength: 10000 });
 
-  async metricFindQuery(query: VariableQuery): Promise<MetricFindValue[]> {
+  applyTemplateVariables(query: MaterializeQuery, scopedVars: ScopedVars, filters?: AdHocFilter[]): MaterializeQuery {
+    const adhocFilters: AdHocFilter[] = filters ?? (getTemplateSrv() as any).getAdhocFilters?.(this.name) ?? [];
+    return { ...query, variables: templateVariables(scopedVars), adhocFilters };
+  }
+
+  /// The relation used for ad-hoc filter suggestions: the first relation queried by the dashboard.
+  private filterRelation(options?: any): string | undefined {
+    const query = (options?.queries ?? []).find(
+      (q: MaterializeQuery) => q.target === MaterializeTarget.Relation && q.name
+    );
+    return query?.name;
+  }
+
+  async getTagKeys(options?: any): Promise<MetricFindValue[]> {
+    const relation = this.filterRelation(options);
+    return relation ? this.getResource('tag-keys', { relation }) : [];
+  }
+
+  async getTagValues(options: any): Promise<MetricFindValue[]> {
+    const relation = this.filterRelation(options);
+    return relation ? this.getResource('tag-values', { relation, key: options.key }) : [];
+  }
+
+  async metricFindQuery(query: VariableQuery, options?: any): Promise<MetricFindValue[]> {
     if (query.path === VariableQueryPathName.Relations) {
       const url = 'relations';
-      const tasks = await this.getResource(url);
+      const tasks = await this.getResource(url, { format: 'names' });
       return tasks.map((text: string) => ({ text }));
     }
+    if (query.path === VariableQueryPathName.Variable && query.statement) {
+      const url = 'variable';
+      return this.getResource(url, {
+        statement: query.statement,
+        variables: JSON.stringify(templateVariables(options?.scopedVars)),
+        from: options?.range?.from.valueOf(),
+        to: options?.range?.to.valueOf(),
+      });
+    }
     return [];
   }
 }
@@ -3,14 +3,27 @@ import { DataQuery, DataSourceJsonData } from '@grafana/data';
 // Regular datasource queries.
 
 /// Types of operation available.
-///
-/// Currently the backend only supports running the TAIL statement.
 export enum MaterializeOperation {
   /// Tail a relation or the output of a select statement using the TAIL statement.
   /// See https://materialize.com/docs/sql/tail/ for details.
   Tail = 'tail',
+  /// Select the current contents of a relation or the output of a select statement once,
+  /// without streaming updates.
+  Select = 'select',
+  /// Explain the plan used to compute a relation or the output of a select statement.
+  Explain = 'explain',
+  /// Show the objects a relation depends on, and those depending on it, as a node graph.
+  Graph = 'graph',
+  /// Run a predefined introspection query, such as source status or freshness.
+  Introspection = 'introspection',
 }
 
+/// The predefined introspection queries.
+export type IntrospectionQuery = 'sourceStatus' | 'sinkStatus' | 'replicaUtilization' | 'freshness';
+
+/// The stage of planning shown by the explain operation.
+export type ExplainPlan = 'optimized' | 'physical';
+
 export enum MaterializeTarget {
   /// An existing relation (source, table or view).
   Relation = 'relation',
@@ -18,25 +31,76 @@ export enum MaterializeTarget {
   SelectStatement = 'select',
 }
 
+/// The values of dashboard template variables, keyed by name.
+///
+/// These are bound to `$name` placeholders in SQL by the backend rather than being
+/// interpolated as text. Multi-value variables are bound as arrays.
+export type TemplateVariables = Record<string, string | string[]>;
+
+/// An ad-hoc filter applied to a query's output by the backend.
+///
+/// The key must be a column of the query's target; the value is sent as a query parameter.
+export interface AdHocFilter {
+  key: string;
+  operator: '=' | '!=' | '<' | '>' | '=~' | '!~';
+  value: string;
+}
+
+/// Columns used to build dashboard annotations, given by name.
+export interface AnnotationFormat {
+  type: 'annotations';
+  time: string;
+  timeEnd?: string;
+  title?: string;
+  text?: string;
+  /// Columns whose values are added to each annotation as tags.
+  tags?: string[];
+}
+
+/// Columns used to build log lines, given by name.
+export interface LogsFormat {
+  type: 'logs';
+  time: string;
+  body: string;
+  level?: string;
+  /// Columns shown as extra fields alongside each line.
+  labels?: string[];
+}
+
+/// How the backend should shape a query's rows into frames.
+///
+/// Queries without a format return a generic table frame.
+export type OutputFormat = { type: 'table' } | AnnotationFormat | LogsFormat;
+
 interface PartialQuery extends DataQuery {
   /// The type of operation to request from the backend.
   operation: MaterializeOperation;
+  /// The values of template variables, set when the query is run.
+  variables?: TemplateVariables;
+  /// The dashboard's ad-hoc filters, set when the query is run.
+  adhocFilters?: AdHocFilter[];
+  /// How rows should be shaped into frames.
+  format?: OutputFormat;
+  /// The plan to show, if `operation` is `Explain`.
+  plan?: ExplainPlan;
+  /// Whether to include arrangement sizes, if `operation` is `Graph`.
+  stats?: boolean;
+  /// The query to run, if `operation` is `Introspection`.
+  query?: IntrospectionQuery;
+  /// Whether to stream updates, if `operation` is `Introspection`.
+  stream?: boolean;
 }
 
-/// A request to tail an existing relation.
+/// A request to tail or select from an existing relation.
 export interface TailRelation extends PartialQuery {
-  /// The operation to perform - here, TAIL.
-  operation: MaterializeOperation.Tail;
   /// The type of target to tail - here, a relation.
   target: MaterializeTarget.Relation;
   /// The name of the relation to tail.
   name?: string;
 }
 
-/// A request to tail the output of a SELECT statement.
+/// A request to tail or run a SELECT statement.
 export interface TailStatement extends PartialQuery {
-  /// The operation to perform - here, TAIL.
-  operation: MaterializeOperation.Tail;
   /// The type of target to tail - here, a select statement.
   target: MaterializeTarget.SelectStatement;
   /// The SELECT statement to tail.
@@ -56,10 +120,24 @@ export const defaultQuery: Partial<MaterializeQuery> = {
 export enum VariableQueryPathName {
   /// Query for available relations.
   Relations = 'relations',
+  /// Run a SELECT statement, using the first column as the text and the second as the value.
+  Variable = 'variable',
+}
+
+/// A relation returned by the `relations` resource.
+export interface Relation {
+  database: string;
+  schema: string;
+  name: string;
+  /// The quoted, fully qualified name of the relation, suitable for use as a TAIL target.
+  fullyQualifiedName: string;
+  kind: 'table' | 'view' | 'materializedView' | 'source' | 'sink' | 'index';
 }
 
 export interface VariableQuery {
   path?: VariableQueryPathName;
+  /// The SELECT statement to run, if `path` is `Variable`.
+  statement?: string;
 }
 
 /**
@@ -69,4 +147,6 @@ export interface DataSourceOptions extends DataSourceJsonData {
   host?: string;
   port?: number;
   username?: string;
+  database?: string;
+  cluster?: string;
 }